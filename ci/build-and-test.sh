@@ -17,6 +17,14 @@ begin_group "Test"
 cargo test --frozen
 end_group
 
+begin_group "Test (no default features)"
+cargo test --frozen --no-default-features
+end_group
+
+begin_group "Test (all features)"
+cargo test --frozen --all-features
+end_group
+
 begin_group "Doc"
 cargo doc --frozen
 end_group
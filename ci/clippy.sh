@@ -9,4 +9,5 @@ end_group
 
 begin_group "Run clippy"
 cargo clippy --all-targets --frozen -- -D warnings
+cargo clippy --all-targets --frozen --all-features -- -D warnings
 end_group
@@ -0,0 +1,1229 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::{vec, vec::Vec};
+
+use crate::bidi::VisualLine;
+#[cfg(feature = "instrumentation")]
+use crate::instrument::{Hooks, HooksRef, Phase, PhaseStats};
+use crate::snippet::SourceSpan;
+use crate::{
+    AnnotStyle, Error, LabelAnchor, LabelSanitization, LineBreakEnd, MainStyle, MultiLineStarts,
+    SourceSnippet, StylePrecedence, TabAnchor, UnitKind,
+};
+
+mod render;
+
+pub use render::RenderOptions;
+
+/// A collection of annotations for a source snippet.
+#[derive(Debug)]
+pub struct Annotations<'a, M> {
+    snippet: &'a SourceSnippet,
+    main_style: MainStyle<M>,
+    window: core::ops::Range<usize>,
+    annots: Vec<AnnotData<M>>,
+    guides: Vec<GuideData<M>>,
+    lines: BTreeMap<usize, LineData>,
+    num_ml_slots: usize,
+    group: usize,
+    // Combines base text metadata with annotation metadata
+    text_meta_combine: Option<fn(&M, &M) -> M>,
+    #[cfg(feature = "instrumentation")]
+    hooks: Option<HooksRef<'a>>,
+}
+
+/// Identifies an annotation within an [`Annotations`] collection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AnnotationId(usize);
+
+#[derive(Debug)]
+struct AnnotData<M> {
+    // Index of the annotation this one is a part of, which is its own
+    // index unless it is a secondary span of a multi-span annotation.
+    id: usize,
+    style: AnnotStyle<M>,
+    units: core::ops::Range<usize>,
+    group: usize,
+    span: SourceSpan,
+    label: Vec<(String, M)>,
+    unit_len: usize,
+    hidden: bool,
+    sl_overlaps: bool,
+    ml_slot: usize,
+    preferred_ml_slot: Option<usize>,
+    link: Option<LinkData>,
+    secondary: bool,
+}
+
+/// A column guide, see [`Annotations::add_column_guide`].
+#[derive(Debug)]
+struct GuideData<M> {
+    col: usize,
+    style: AnnotStyle<M>,
+    label: Vec<(String, M)>,
+    group: usize,
+}
+
+/// Data of a linked pair of spans on the same line.
+#[derive(Debug)]
+struct LinkData {
+    spans: (core::ops::Range<usize>, core::ops::Range<usize>),
+    // Gap between both spans, which is drawn as a line
+    gap_cols: core::ops::Range<usize>,
+    gap_utf8: core::ops::Range<usize>,
+}
+
+#[derive(Debug)]
+struct LineData {
+    // "sl" stands for single line
+    // "ml" stands for multi line
+    sl_annots: Vec<usize>,
+    ml_annots_starts: Vec<usize>,
+    ml_annots_ends: Vec<usize>,
+    sl_carets: Vec<usize>,
+    styles: Vec<(usize, bool)>,
+}
+
+impl<'a, M> Annotations<'a, M> {
+    pub fn new(snippet: &'a SourceSnippet, main_style: MainStyle<M>) -> Self {
+        Self::for_lines(snippet, 0..snippet.num_lines(), main_style)
+    }
+
+    /// Creates a collection of annotations restricted to a window of lines
+    /// of `snippet`, so only that window is rendered.
+    ///
+    /// `line_range` is a range of line indices within the snippet (i.e.,
+    /// not offset by the start line of the snippet).
+    ///
+    /// Annotations are clamped to the window and annotations completely
+    /// outside the window are ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `line_range` does not contain any line of the snippet.
+    pub fn for_lines(
+        snippet: &'a SourceSnippet,
+        line_range: core::ops::Range<usize>,
+        main_style: MainStyle<M>,
+    ) -> Self {
+        Self::try_for_lines(snippet, line_range, main_style)
+            .expect("the window must contain at least one line")
+    }
+
+    /// Like [`for_lines`](Self::for_lines), but returns an error instead of
+    /// panicking if `line_range` does not contain any line of the snippet.
+    pub fn try_for_lines(
+        snippet: &'a SourceSnippet,
+        line_range: core::ops::Range<usize>,
+        main_style: MainStyle<M>,
+    ) -> Result<Self, Error> {
+        let end_line = line_range.end.min(snippet.num_lines());
+        if line_range.start >= end_line {
+            return Err(Error::EmptyLineWindow);
+        }
+
+        Ok(Self {
+            snippet,
+            main_style,
+            window: snippet.line_start(line_range.start)..snippet.line_start(end_line),
+            annots: Vec::new(),
+            guides: Vec::new(),
+            lines: BTreeMap::new(),
+            num_ml_slots: 0,
+            group: 0,
+            text_meta_combine: None,
+            #[cfg(feature = "instrumentation")]
+            hooks: None,
+        })
+    }
+
+    /// Asserts, in debug builds, that the units of the snippet are of
+    /// `kind`.
+    ///
+    /// This is meant to be called where spans are converted from positions
+    /// of the caller (e.g., byte offsets) to catch spans of the wrong kind
+    /// of units, which would otherwise point to the wrong text.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds if the units of the snippet are not of `kind`
+    /// (see [`SourceSnippet::unit_kind`]).
+    #[inline]
+    #[track_caller]
+    pub fn debug_assert_unit_kind(&self, kind: UnitKind) {
+        debug_assert_eq!(
+            self.snippet.unit_kind(),
+            kind,
+            "the snippet has units of a different kind",
+        );
+    }
+
+    /// Adds an annotation.
+    ///
+    /// `span` is a range of source units. Positions beyond the end of the
+    /// snippet are clamped to the end of the snippet.
+    ///
+    /// If the annotations are restricted to a window of lines (see
+    /// [`for_lines`](Self::for_lines)), the span is clamped to the window and
+    /// the annotation is ignored if it is completely outside it.
+    ///
+    /// Single-line annotations with exactly the same span (e.g., several
+    /// lints on the same token) share a single run of carets, with their
+    /// labels on consecutive rows in the order they were added.
+    ///
+    /// Returns the id of the annotation, or `None` if it was ignored.
+    pub fn add_annotation(
+        &mut self,
+        span: core::ops::Range<usize>,
+        style: AnnotStyle<M>,
+        label: Vec<(String, M)>,
+    ) -> Option<AnnotationId> {
+        self.add_annotation_with_link(span, style, label, None)
+    }
+
+    /// Adds an annotation that points to the end of the snippet, such as
+    /// an "unexpected end of file" error.
+    ///
+    /// The annotation is drawn with
+    /// [`empty_span_carets`](MainStyle::empty_span_carets) carets past the
+    /// last character of the snippet, under
+    /// [`eof_placeholder`](MainStyle::eof_placeholder) if it is `Some`.
+    ///
+    /// Returns the id of the annotation, or `None` if it was ignored
+    /// because the end of the snippet is outside the window.
+    pub fn add_eof_annotation(
+        &mut self,
+        style: AnnotStyle<M>,
+        label: Vec<(String, M)>,
+    ) -> Option<AnnotationId> {
+        let end = self.snippet.num_units();
+        self.add_annotation(end..end, style, label)
+    }
+
+    /// Returns whether a visible annotation points to the end of the
+    /// snippet (or beyond it).
+    fn is_eof_annotated(&self) -> bool {
+        let end = self.snippet.num_units();
+        self.annots
+            .iter()
+            .any(|annot| !annot.hidden && annot.units.start >= end)
+    }
+
+    /// Adds an annotation that links two spans, drawn with carets under
+    /// both spans, a line that connects them and a single label.
+    ///
+    /// If the spans are not on the same line, the annotation is drawn like
+    /// a multi-line annotation from the start of the first span to the end
+    /// of the second one. Spans are handled as in
+    /// [`add_annotation`](Self::add_annotation), and the annotation is
+    /// ignored if any of them is ignored.
+    ///
+    /// This is useful for pairs like mismatched brackets.
+    pub fn add_linked_annotation(
+        &mut self,
+        span1: core::ops::Range<usize>,
+        span2: core::ops::Range<usize>,
+        style: AnnotStyle<M>,
+        label: Vec<(String, M)>,
+    ) -> Option<AnnotationId> {
+        let (first, second) = if span2.start < span1.start {
+            (span2, span1)
+        } else {
+            (span1, span2)
+        };
+        let (first_span, _) = self.convert_span(first.clone())?;
+        let (second_span, _) = self.convert_span(second.clone())?;
+
+        let gap_start_col = first_span.end_col.max(first_span.start_col + 1);
+        let link = if first_span.start_line == second_span.end_line
+            && gap_start_col < second_span.start_col
+        {
+            Some(LinkData {
+                spans: (first.clone(), second.clone()),
+                gap_cols: gap_start_col..second_span.start_col,
+                gap_utf8: first_span.end_utf8..second_span.start_utf8,
+            })
+        } else {
+            None
+        };
+        self.add_annotation_with_link(first.start..second.end.max(first.end), style, label, link)
+    }
+
+    fn add_annotation_with_link(
+        &mut self,
+        span: core::ops::Range<usize>,
+        style: AnnotStyle<M>,
+        label: Vec<(String, M)>,
+        link: Option<LinkData>,
+    ) -> Option<AnnotationId> {
+        self.in_layout_phase(
+            |id| usize::from(id.is_some()),
+            |this| this.add_annotation_with_link_impl(span, style, label, link),
+        )
+    }
+
+    /// Runs `add`, which adds an annotation or a column guide, reporting it
+    /// to the hooks (if any) as a [`Phase::Layout`] with the number of
+    /// items returned by `items`.
+    fn in_layout_phase<R>(
+        &mut self,
+        items: fn(&R) -> usize,
+        add: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        #[cfg(feature = "instrumentation")]
+        if let Some(hooks) = self.hooks {
+            hooks.0.phase_begin(Phase::Layout);
+            let result = add(self);
+            hooks.0.phase_end(
+                Phase::Layout,
+                &PhaseStats {
+                    items: items(&result),
+                },
+            );
+            return result;
+        }
+        #[cfg(not(feature = "instrumentation"))]
+        let _ = items;
+        add(self)
+    }
+
+    fn add_annotation_with_link_impl(
+        &mut self,
+        span: core::ops::Range<usize>,
+        style: AnnotStyle<M>,
+        label: Vec<(String, M)>,
+        link: Option<LinkData>,
+    ) -> Option<AnnotationId> {
+        let units = span.clone();
+        let (span, unit_len) = self.convert_span(span)?;
+
+        let mut annot = AnnotData {
+            id: self.annots.len(),
+            style,
+            units,
+            group: self.group,
+            span,
+            label,
+            unit_len,
+            hidden: false,
+            sl_overlaps: false,
+            ml_slot: usize::MAX,
+            preferred_ml_slot: None,
+            link,
+            secondary: false,
+        };
+        let annot_i = self.annots.len();
+        let precedence = self.main_style.style_precedence;
+
+        let line_data = self
+            .lines
+            .entry(annot.span.start_line)
+            .or_insert_with(|| Self::create_line_data(self.snippet, annot.span.start_line));
+        if annot.span.start_line == annot.span.end_line {
+            // Single line
+
+            // Render at least one caret for zero length spans
+            let min_carets = self.main_style.empty_span_carets.max(1);
+            if annot.span.end_col <= annot.span.start_col {
+                annot.span.end_col = annot.span.start_col + min_carets;
+            }
+
+            // Check if annotation overlaps with other single line annotations
+            // (annotations with the same span are stacked instead)
+            for &prev_annot_i in line_data.sl_annots.iter() {
+                let other_annot = &mut self.annots[prev_annot_i];
+                if annot.span.start_col.max(other_annot.span.start_col)
+                    < annot.span.end_col.min(other_annot.span.end_col)
+                    && !Self::is_same_span(&annot, other_annot)
+                {
+                    annot.sl_overlaps = true;
+                    other_annot.sl_overlaps = true;
+                }
+            }
+
+            // Apply caret styles
+            if annot.style.hide_carets {
+                // No carets
+            } else if line_data.sl_carets.len() <= annot.span.start_col {
+                line_data.sl_carets.resize(annot.span.start_col, usize::MAX);
+                line_data.sl_carets.resize(annot.span.end_col, annot_i);
+            } else if line_data.sl_carets.len() <= annot.span.end_col {
+                line_data.sl_carets[annot.span.start_col..]
+                    .iter_mut()
+                    .for_each(|sl_caret| {
+                        if takes_precedence(precedence, &self.annots, unit_len, *sl_caret) {
+                            *sl_caret = annot_i;
+                        }
+                    });
+                line_data.sl_carets.resize(annot.span.end_col, annot_i);
+            } else {
+                line_data.sl_carets[annot.span.start_col..annot.span.end_col]
+                    .iter_mut()
+                    .for_each(|sl_caret| {
+                        if takes_precedence(precedence, &self.annots, unit_len, *sl_caret) {
+                            *sl_caret = annot_i;
+                        }
+                    });
+            }
+
+            // Apply line text styles (except to the gap of linked spans)
+            let gap_utf8 = annot
+                .link
+                .as_ref()
+                .map_or(0..0, |link| link.gap_utf8.clone());
+            for (utf8, chr_style) in line_data.styles[..annot.span.end_utf8]
+                .iter_mut()
+                .enumerate()
+                .skip(annot.span.start_utf8)
+            {
+                if gap_utf8.contains(&utf8) {
+                    continue;
+                }
+                if takes_precedence(precedence, &self.annots, unit_len, chr_style.0) {
+                    chr_style.0 = annot_i;
+                }
+            }
+
+            Self::insert_annot_sorted(&self.annots, &annot, annot_i, &mut line_data.sl_annots);
+        } else {
+            // Multi line
+            for chr_style in line_data.styles[annot.span.start_utf8..].iter_mut() {
+                if takes_precedence(precedence, &self.annots, unit_len, chr_style.0) {
+                    chr_style.0 = annot_i;
+                }
+            }
+            Self::insert_annot_sorted(
+                &self.annots,
+                &annot,
+                annot_i,
+                &mut line_data.ml_annots_starts,
+            );
+
+            let end_line_data = self
+                .lines
+                .entry(annot.span.end_line)
+                .or_insert_with(|| Self::create_line_data(self.snippet, annot.span.end_line));
+
+            for chr_style in end_line_data.styles[..annot.span.end_utf8].iter_mut() {
+                if takes_precedence(precedence, &self.annots, unit_len, chr_style.0) {
+                    chr_style.0 = annot_i;
+                }
+            }
+            Self::insert_annot_sorted(
+                &self.annots,
+                &annot,
+                annot_i,
+                &mut end_line_data.ml_annots_ends,
+            );
+        }
+
+        let is_multi_line = annot.span.start_line != annot.span.end_line;
+        self.annots.push(annot);
+        if is_multi_line {
+            // Only the new annotation gets a slot, so the slots of existing
+            // annotations are stable between renders.
+            self.assign_ml_slot(annot_i);
+        }
+        Some(AnnotationId(annot_i))
+    }
+
+    /// Adds an annotation that covers the whole snippet (or the whole
+    /// window of lines, see [`for_lines`](Self::for_lines)).
+    ///
+    /// The returned id is handled as in
+    /// [`add_annotation`](Self::add_annotation).
+    pub fn add_full_snippet_annotation(
+        &mut self,
+        style: AnnotStyle<M>,
+        label: Vec<(String, M)>,
+    ) -> Option<AnnotationId> {
+        self.add_annotation(self.window.clone(), style, label)
+    }
+
+    /// Adds a hidden annotation, which applies the text metadata of `style`
+    /// to `span` without drawing carets, lines or labels.
+    ///
+    /// This is useful to highlight text (e.g., search matches). Lines covered
+    /// by hidden annotations are rendered like annotated lines.
+    ///
+    /// Spans and the returned id are handled as in
+    /// [`add_annotation`](Self::add_annotation).
+    pub fn add_hidden_annotation(
+        &mut self,
+        span: core::ops::Range<usize>,
+        style: AnnotStyle<M>,
+    ) -> Option<AnnotationId> {
+        self.in_layout_phase(
+            |id| usize::from(id.is_some()),
+            |this| this.add_hidden_annotation_impl(span, style),
+        )
+    }
+
+    fn add_hidden_annotation_impl(
+        &mut self,
+        span: core::ops::Range<usize>,
+        style: AnnotStyle<M>,
+    ) -> Option<AnnotationId> {
+        let units = span.clone();
+        let (span, unit_len) = self.convert_span(span)?;
+
+        let annot_i = self.annots.len();
+        let precedence = self.main_style.style_precedence;
+
+        for line_i in span.start_line..=span.end_line {
+            let line_data = self
+                .lines
+                .entry(line_i)
+                .or_insert_with(|| Self::create_line_data(self.snippet, line_i));
+            let start_utf8 = if line_i == span.start_line {
+                span.start_utf8
+            } else {
+                0
+            };
+            let end_utf8 = if line_i == span.end_line {
+                span.end_utf8
+            } else {
+                line_data.styles.len()
+            };
+            for chr_style in line_data.styles[start_utf8..end_utf8].iter_mut() {
+                if takes_precedence(precedence, &self.annots, unit_len, chr_style.0) {
+                    chr_style.0 = annot_i;
+                }
+            }
+        }
+
+        self.annots.push(AnnotData {
+            id: annot_i,
+            style,
+            units,
+            group: self.group,
+            span,
+            label: Vec::new(),
+            unit_len,
+            hidden: true,
+            sl_overlaps: false,
+            ml_slot: usize::MAX,
+            preferred_ml_slot: None,
+            link: None,
+            secondary: false,
+        });
+        Some(AnnotationId(annot_i))
+    }
+
+    /// Adds a column guide, which draws a vertical rule at display column
+    /// `col` of the source text (e.g., a maximum line length) through the
+    /// rendered rows, ending with the caret of `style` and `label` after the
+    /// last line.
+    ///
+    /// The rule is only drawn on the rows whose content ends before `col`,
+    /// so text that crosses the column interrupts it. Column guides do not
+    /// cause any line to be rendered by themselves.
+    ///
+    /// Column guides are not annotations: they are not counted by
+    /// [`len`](Self::len) and they do not appear in the elements of a
+    /// [`RenderLayout`](crate::RenderLayout).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # let main_style = sourceannot::MainStyle::new(()).with_console_chars(true);
+    /// # let annot_style = sourceannot::AnnotStyle {
+    /// #     caret: '^',
+    /// #     text_normal_meta: (),
+    /// #     text_alt_meta: (),
+    /// #     line_meta: (),
+    /// #     hide_carets: false,
+    /// # };
+    /// let source = "x = 1\nlong_name = 2\n";
+    /// let snippet = sourceannot::SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+    ///
+    /// let mut annots = sourceannot::Annotations::new(&snippet, main_style);
+    /// annots.add_annotation(0..18, annot_style, vec![("statements".into(), ())]);
+    /// annots.add_column_guide(8, annot_style, vec![("column 8".into(), ())]);
+    ///
+    /// let rendered: String = annots.render(1, 0, 0).into_iter().map(|(s, _)| s).collect();
+    /// assert_eq!(
+    ///     rendered,
+    ///     concat!(
+    ///         "1 | , x = 1   |\n",
+    ///         "2 | | long_name = 2\n",
+    ///         "  | `------------^ statements\n",
+    ///         "  |           ^ column 8\n",
+    ///     ),
+    /// );
+    /// ```
+    pub fn add_column_guide(&mut self, col: usize, style: AnnotStyle<M>, label: Vec<(String, M)>) {
+        self.in_layout_phase(
+            |()| 1,
+            |this| {
+                this.guides.push(GuideData {
+                    col,
+                    style,
+                    label,
+                    group: this.group,
+                });
+            },
+        );
+    }
+
+    /// Sets a function that combines the metadata of unannotated text
+    /// (first argument, [`MainStyle::text_normal_meta`] or
+    /// [`MainStyle::text_alt_meta`]) with the metadata of an annotation
+    /// (second argument) to style annotated text.
+    ///
+    /// It allows layering styles, e.g., keeping a base color while
+    /// underlining the annotated text. By default, the metadata of the
+    /// annotation replaces the base metadata.
+    pub fn set_text_meta_combine(&mut self, combine: fn(&M, &M) -> M) {
+        self.text_meta_combine = Some(combine);
+    }
+
+    /// Sets hooks that are notified when each annotation or column guide is
+    /// added (as [`Phase::Layout`]) and when the annotations are rendered (as
+    /// [`Phase::Render`]).
+    ///
+    /// Only available with the `instrumentation` feature.
+    #[cfg(feature = "instrumentation")]
+    pub fn set_hooks(&mut self, hooks: &'a dyn Hooks) {
+        self.hooks = Some(HooksRef(hooks));
+    }
+
+    /// Marks the annotation `id` as secondary, so its label is dropped from
+    /// crowded lines (see
+    /// [`RenderOptions::secondary_label_threshold`]).
+    pub fn set_secondary(&mut self, id: AnnotationId) {
+        for annot in self.annots.iter_mut() {
+            if annot.id == id.0 {
+                annot.secondary = true;
+            }
+        }
+    }
+
+    /// Sets the preferred multi-line slot (i.e., the column of the vertical
+    /// line in the gutter) of the annotation `id`.
+    ///
+    /// Annotations with a preferred slot are placed before any other one, so
+    /// they keep their slot when other annotations are added or removed
+    /// (e.g., across re-renders of an interactive view). If the slot is
+    /// taken by another annotation with a preferred slot whose lines
+    /// overlap, the first free slot is used instead.
+    ///
+    /// It has no effect on single-line annotations.
+    pub fn set_preferred_ml_slot(&mut self, id: AnnotationId, slot: usize) {
+        for annot in self.annots.iter_mut() {
+            if annot.id == id.0 {
+                annot.preferred_ml_slot = Some(slot);
+            }
+        }
+        self.assign_ml_slots();
+    }
+
+    /// Returns the multi-line slot of the annotation `id`, or `None` if it
+    /// is not a multi-line annotation.
+    ///
+    /// Adding annotations never changes the slots of the existing ones, so
+    /// connectors do not move between renders when annotations are added
+    /// incrementally. When the annotations are added again to a new
+    /// collection, slots can be kept by passing them to
+    /// [`set_preferred_ml_slot`](Self::set_preferred_ml_slot).
+    pub fn ml_slot(&self, id: AnnotationId) -> Option<usize> {
+        let annot = self.annots.get(id.0)?;
+        Self::is_ml(annot).then_some(annot.ml_slot)
+    }
+
+    /// Sets the group of the annotations added after this call.
+    ///
+    /// Annotations belong to group 0 by default. Each group can be rendered
+    /// as a separate snippet block (see [`group`](Self::group)), which is
+    /// useful when unrelated annotations are far apart.
+    #[inline]
+    pub fn set_group(&mut self, group: usize) {
+        self.group = group;
+    }
+
+    /// Returns the ids of the groups that have at least one annotation, in
+    /// ascending order.
+    pub fn group_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.annots.iter().map(|annot| annot.group).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Converts a span of source units, applying the window and the span
+    /// options of the main style.
+    ///
+    /// Returns the converted span and its length in source units, or `None`
+    /// if it is outside the window.
+    fn convert_span(&self, span: core::ops::Range<usize>) -> Option<(SourceSpan, usize)> {
+        let window = self.window.clone();
+        let is_last_window = window.end == self.snippet.num_units();
+        if (span.start >= window.end && !is_last_window)
+            || span.end.max(span.start) < window.start
+            || (span.start < window.start && span.end == window.start)
+        {
+            return None;
+        }
+        let span = if is_last_window {
+            span.start.max(window.start)..span.end.max(window.start)
+        } else {
+            span.start.clamp(window.start, window.end)..span.end.clamp(window.start, window.end)
+        };
+
+        let start = span.start;
+        let unit_len = span.end.saturating_sub(span.start);
+        let mut span = self.snippet.convert_span(span.start, span.end);
+        if self.main_style.line_break_end == LineBreakEnd::LastChar {
+            self.snippet.clamp_span_end(&mut span);
+        }
+        if self.main_style.tab_anchor == TabAnchor::LastColumn {
+            self.snippet.anchor_span_at_tab_end(start, &mut span);
+        }
+        Some((span, unit_len))
+    }
+
+    /// Assigns multi-line slots to all multi-line annotations, placing
+    /// annotations with a preferred slot first and then the rest in
+    /// insertion order.
+    fn assign_ml_slots(&mut self) {
+        let mut order: Vec<usize> = (0..self.annots.len())
+            .filter(|&annot_i| Self::is_ml(&self.annots[annot_i]))
+            .collect();
+        order.sort_by_key(|&annot_i| self.annots[annot_i].preferred_ml_slot.is_none());
+
+        for &annot_i in order.iter() {
+            self.annots[annot_i].ml_slot = usize::MAX;
+        }
+        self.num_ml_slots = 0;
+        for &annot_i in order.iter() {
+            self.assign_ml_slot(annot_i);
+        }
+    }
+
+    #[inline]
+    fn is_ml(annot: &AnnotData<M>) -> bool {
+        !annot.hidden && annot.span.start_line != annot.span.end_line
+    }
+
+    /// Assigns a multi-line slot to the multi-line annotation `annot_i`,
+    /// which does not overlap the lines of the annotations that already
+    /// have one.
+    fn assign_ml_slot(&mut self, annot_i: usize) {
+        let annot = &self.annots[annot_i];
+        let starts_at_col_0 = self.has_short_start(annot);
+
+        let mut used_slots = Vec::new();
+        for other_annot in self.annots.iter() {
+            if !Self::is_ml(other_annot) || other_annot.ml_slot == usize::MAX {
+                continue;
+            }
+            let other_starts_at_col_0 = self.has_short_start(other_annot);
+            let line_overlaps = (starts_at_col_0
+                && other_annot.span.end_line == annot.span.start_line)
+                || (other_starts_at_col_0 && other_annot.span.start_line == annot.span.end_line)
+                || annot.span.start_line.max(other_annot.span.start_line)
+                    < annot.span.end_line.min(other_annot.span.end_line);
+
+            if line_overlaps {
+                if other_annot.ml_slot >= used_slots.len() {
+                    used_slots.resize(other_annot.ml_slot, false);
+                    used_slots.push(true);
+                } else {
+                    used_slots[other_annot.ml_slot] = true;
+                }
+            }
+        }
+
+        let ml_slot = match annot.preferred_ml_slot {
+            Some(slot) if !used_slots.get(slot).copied().unwrap_or(false) => slot,
+            _ => used_slots
+                .iter()
+                .position(|used| !used)
+                .unwrap_or(used_slots.len()),
+        };
+        self.annots[annot_i].ml_slot = ml_slot;
+        self.num_ml_slots = self.num_ml_slots.max(ml_slot + 1);
+    }
+
+    /// Returns whether two single-line annotations have exactly the same
+    /// span, in which case the label of the later one is stacked under the
+    /// label of the earlier one, sharing its carets.
+    fn is_same_span(a: &AnnotData<M>, b: &AnnotData<M>) -> bool {
+        a.span.start_line == b.span.start_line
+            && a.span.start_col == b.span.start_col
+            && a.span.end_col == b.span.end_col
+            && a.link.is_none()
+            && b.link.is_none()
+            && !a.style.hide_carets
+            && !b.style.hide_carets
+    }
+
+    /// Returns the column under which the label of a single-line
+    /// annotation is placed.
+    ///
+    /// If the line is reordered (`visual` is not `None`), the column is
+    /// visual.
+    fn label_col(&self, annot: &AnnotData<M>, visual: Option<&VisualLine>) -> usize {
+        let first_col = annot.span.start_col;
+        let last_col = annot.span.end_col.max(annot.span.start_col + 1) - 1;
+        let (first_col, last_col) = match visual {
+            Some(visual) => {
+                let (a, b) = (visual.visual_col(first_col), visual.visual_col(last_col));
+                (a.min(b), a.max(b))
+            }
+            None => (first_col, last_col),
+        };
+        match self.main_style.label_anchor {
+            LabelAnchor::FirstCaret => first_col,
+            LabelAnchor::LastCaret => last_col,
+        }
+    }
+
+    /// Returns the range of lines of the window (see
+    /// [`for_lines`](Self::for_lines)).
+    fn window_lines(&self) -> core::ops::Range<usize> {
+        let start = self.snippet.get_line_col(self.window.start).0;
+        let end = if self.window.end == self.snippet.num_units() {
+            self.snippet.num_lines()
+        } else {
+            self.snippet.get_line_col(self.window.end).0
+        };
+        start..end
+    }
+
+    /// Returns the first and the last rendered source lines, including
+    /// context lines (see [`RenderOptions::context_lines`]).
+    fn rendered_lines(
+        &self,
+        options: &RenderOptions<'_, M>,
+    ) -> Option<core::ops::RangeInclusive<usize>> {
+        let (&first_line_i, _) = self.lines.first_key_value()?;
+        let (&last_line_i, _) = self.lines.last_key_value()?;
+        match options.context_lines {
+            Some(context_lines) => {
+                let window_lines = self.window_lines();
+                Some(
+                    first_line_i
+                        .saturating_sub(context_lines)
+                        .max(window_lines.start)
+                        ..=last_line_i
+                            .saturating_add(context_lines)
+                            .min(window_lines.end - 1),
+                )
+            }
+            None => Some(first_line_i..=last_line_i),
+        }
+    }
+
+    /// Returns the line `line_i` reordered for display, if enabled (see
+    /// [`RenderOptions::bidi_reordering`]) and needed.
+    fn visual_line(&self, line_i: usize, options: &RenderOptions<'_, M>) -> Option<VisualLine> {
+        if options.bidi_reordering {
+            let line = self.snippet.line(line_i);
+            VisualLine::new(&line.text, self.snippet.line_width(line_i))
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether a multi-line annotation starts on the line row itself
+    /// instead of on a separate row.
+    #[inline]
+    fn has_short_start(&self, annot: &AnnotData<M>) -> bool {
+        annot.span.start_col == 0 && self.main_style.multi_line_starts == MultiLineStarts::Compact
+    }
+
+    /// Inserts `annot_i` into `dest` ordered by start column, breaking ties
+    /// by insertion order so the layout never depends on anything else.
+    fn insert_annot_sorted(
+        annots: &[AnnotData<M>],
+        annot: &AnnotData<M>,
+        annot_i: usize,
+        dest: &mut Vec<usize>,
+    ) {
+        let insert_i = dest
+            .binary_search_by_key(&(annot.span.start_col, annot_i), |other_annot_i| {
+                let other_annot = &annots[*other_annot_i];
+                (other_annot.span.start_col, *other_annot_i)
+            })
+            .unwrap_err();
+        dest.insert(insert_i, annot_i);
+    }
+
+    fn create_line_data(snippet: &'a SourceSnippet, line_i: usize) -> LineData {
+        let snippet_line = snippet.line(line_i);
+        let mut styles = vec![(usize::MAX, false); snippet_line.text.len()];
+        for alt_range in snippet_line.alts.ranges() {
+            styles[alt_range].fill((usize::MAX, true));
+        }
+        LineData {
+            sl_annots: Vec::new(),
+            ml_annots_starts: Vec::new(),
+            ml_annots_ends: Vec::new(),
+            sl_carets: Vec::new(),
+            styles,
+        }
+    }
+
+    /// Returns the display width of a label once rendered.
+    fn label_width(&self, label: &[(String, M)]) -> usize {
+        label
+            .iter()
+            .map(|(text, _)| {
+                crate::width::str_width(&sanitize_label(text, self.main_style.label_sanitization))
+            })
+            .sum()
+    }
+
+    /// Returns the row (counting from the top) of the label of each
+    /// single-line annotation in `sl_annots`, which must be sorted by label
+    /// column.
+    ///
+    /// Without [`MainStyle::compact_labels`], the rightmost label is placed
+    /// in the first row and each label to its left one row below. With it,
+    /// each label is placed in the first row below the labels whose
+    /// vertical lines it would otherwise cover. Labels for which `is_tall`
+    /// returns `true` span several rows, so they are kept below the labels
+    /// to their right.
+    fn label_rows(
+        &self,
+        sl_annots: &[usize],
+        visual: Option<&VisualLine>,
+        is_tall: impl Fn(usize) -> bool,
+    ) -> Vec<usize> {
+        if !self.main_style.compact_labels {
+            return (0..sl_annots.len()).rev().collect();
+        }
+
+        let cols: Vec<usize> = sl_annots
+            .iter()
+            .map(|&annot_i| self.label_col(&self.annots[annot_i], visual))
+            .collect();
+        let mut rows = vec![0; sl_annots.len()];
+        for i in (0..sl_annots.len()).rev() {
+            // A label is shifted one column to the right when the vertical
+            // line of the previous label is in its column.
+            let shift = usize::from(i != 0 && cols[i - 1] == cols[i]);
+            let end_col = if is_tall(sl_annots[i]) {
+                usize::MAX
+            } else {
+                cols[i] + shift + self.label_width(&self.annots[sl_annots[i]].label)
+            };
+            rows[i] = (i + 1..sl_annots.len())
+                .filter(|&j| cols[j] <= end_col)
+                .map(|j| rows[j] + 1)
+                .max()
+                .unwrap_or(0);
+        }
+        rows
+    }
+
+    /// Returns the number of annotations, including hidden ones.
+    ///
+    /// Annotations that were ignored (e.g., outside the window) are not
+    /// counted, and multi-span annotations count once.
+    pub fn len(&self) -> usize {
+        self.annots
+            .iter()
+            .enumerate()
+            .filter(|&(annot_i, annot)| annot.id == annot_i)
+            .count()
+    }
+
+    /// Returns whether there are no annotations.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.annots.is_empty()
+    }
+
+    /// Returns the number of spans drawn as multi-line annotations.
+    ///
+    /// Each span of a multi-span annotation is counted separately.
+    pub fn multi_line_count(&self) -> usize {
+        self.annots
+            .iter()
+            .filter(|annot| !annot.hidden && annot.span.start_line != annot.span.end_line)
+            .count()
+    }
+
+    /// Returns the number of source lines covered by annotations (including
+    /// hidden ones), which are the lines that are always rendered.
+    ///
+    /// Only the first and the last lines of multi-line annotations are
+    /// counted, since the lines between them may be folded, except for
+    /// hidden annotations, which count every line of their span.
+    #[inline]
+    pub fn annotated_line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns the number of slots used to draw the vertical lines of
+    /// multi-line annotations.
+    #[inline]
+    pub fn num_multi_line_slots(&self) -> usize {
+        self.num_ml_slots
+    }
+
+    /// Returns the number of columns occupied by the vertical lines of
+    /// multi-line annotations (including the space that separates them from
+    /// the source text), between the margin and the source text.
+    #[inline]
+    pub fn gutter_width(&self) -> usize {
+        if self.num_ml_slots == 0 {
+            0
+        } else {
+            self.num_ml_slots + 1
+        }
+    }
+
+    /// Returns an estimation of the display width of the widest row
+    /// rendered with `options`, without rendering.
+    ///
+    /// The estimation is an upper bound that accounts for the margin, the
+    /// gutter, every source line between the first and the last annotated
+    /// lines and the labels. It is useful to choose between wrapping,
+    /// truncation or horizontal scrolling beforehand.
+    pub fn max_render_width(&self, options: &RenderOptions<'_, M>) -> usize {
+        let margin_width = self.margin_width(options);
+
+        let mut max_width = 0;
+        if let Some(rendered_lines) = self.rendered_lines(options) {
+            for line_i in rendered_lines {
+                max_width = max_width.max(self.snippet.line_width(line_i));
+            }
+        }
+        if let Some(placeholder) = self.main_style.eof_placeholder {
+            let mut eof_width = crate::width::str_width(placeholder);
+            if self.is_eof_annotated() {
+                eof_width += self.snippet.line_width(self.snippet.num_lines() - 1);
+            }
+            max_width = max_width.max(eof_width);
+        }
+
+        for guide in self.guides.iter() {
+            // The caret at the end of the rule, a space and the label
+            max_width = max_width.max(guide.col + 2 + self.label_width(&guide.label));
+        }
+
+        for annot in self.annots.iter() {
+            if annot.hidden {
+                continue;
+            }
+            // Carets (or the end of a multi-line annotation), a space and
+            // the label
+            let carets_end = annot
+                .span
+                .end_col
+                .max(annot.span.start_col + self.main_style.empty_span_carets)
+                .max(1);
+            max_width = max_width.max(carets_end + 1 + self.label_width(&annot.label));
+        }
+
+        if let Some(delimiter) = self.main_style.label_delimiter {
+            let delimiter_width = crate::width::str_width(delimiter);
+            for line_data in self.lines.values() {
+                let labels_width: usize = line_data
+                    .sl_annots
+                    .iter()
+                    .map(|&annot_i| self.label_width(&self.annots[annot_i].label) + delimiter_width)
+                    .sum();
+                max_width = max_width.max(line_data.sl_carets.len() + 1 + labels_width);
+            }
+        }
+
+        if options.max_labels_per_line.is_some() || options.secondary_label_threshold.is_some() {
+            for line_data in self.lines.values() {
+                let note = unlabeled_note(
+                    self.main_style.more_annotations_marker,
+                    line_data.sl_annots.len(),
+                );
+                max_width = max_width.max(crate::width::str_width(&note));
+            }
+        }
+
+        margin_width + self.gutter_width() + max_width
+    }
+
+    pub fn max_line_no_width(&self) -> usize {
+        self.max_line_no_width_with_options(&RenderOptions::default())
+    }
+
+    /// Like [`max_line_no_width`](Self::max_line_no_width), but for a
+    /// rendering with `options` (e.g., with another
+    /// [`start_line`](RenderOptions::start_line)).
+    ///
+    /// [`RenderOptions::max_line_no_width`] is ignored.
+    pub fn max_line_no_width_with_options(&self, options: &RenderOptions<'_, M>) -> usize {
+        let start_line = options.start_line.unwrap_or(self.snippet.start_line());
+        if let Some(rendered_lines) = self.rendered_lines(options) {
+            let max_line_no = rendered_lines.end().saturating_add(start_line);
+            (max_line_no.max(1).ilog10() + 1) as usize
+        } else {
+            0
+        }
+    }
+
+    /// Returns the width of the line numbers rendered with `options`.
+    fn line_no_width(&self, options: &RenderOptions<'_, M>) -> usize {
+        options
+            .max_line_no_width
+            .unwrap_or_else(|| self.max_line_no_width_with_options(options))
+    }
+
+    /// Returns the width of the margin rendered with `options`, including
+    /// the space after it.
+    fn margin_width(&self, options: &RenderOptions<'_, M>) -> usize {
+        if let Some((indent, _)) = options.indent {
+            crate::width::str_width(indent)
+        } else if self.main_style.margin.is_some() {
+            self.line_no_width(options) + 3
+        } else {
+            0
+        }
+    }
+}
+
+impl<'a, M: Clone> Annotations<'a, M> {
+    /// Returns a collection with only the annotations of `group` (see
+    /// [`set_group`](Self::set_group)), laid out on their own.
+    ///
+    /// The returned collection covers the same lines as `self`, so rendering
+    /// it only shows the lines annotated by that group, with its own context
+    /// lines and folding.
+    pub fn group(&self, group: usize) -> Annotations<'a, M> {
+        let mut result = Self {
+            snippet: self.snippet,
+            main_style: self.main_style.clone(),
+            window: self.window.clone(),
+            annots: Vec::new(),
+            guides: Vec::new(),
+            lines: BTreeMap::new(),
+            num_ml_slots: 0,
+            group,
+            text_meta_combine: self.text_meta_combine,
+            #[cfg(feature = "instrumentation")]
+            hooks: self.hooks,
+        };
+        let mut new_indices = vec![usize::MAX; self.annots.len()];
+        for (annot_i, annot) in self.annots.iter().enumerate() {
+            if annot.group != group {
+                continue;
+            }
+            let id = if annot.hidden {
+                result.add_hidden_annotation(annot.units.clone(), annot.style.clone())
+            } else if let Some(ref link) = annot.link {
+                result.add_linked_annotation(
+                    link.spans.0.clone(),
+                    link.spans.1.clone(),
+                    annot.style.clone(),
+                    annot.label.clone(),
+                )
+            } else {
+                result.add_annotation(
+                    annot.units.clone(),
+                    annot.style.clone(),
+                    annot.label.clone(),
+                )
+            };
+            if let Some(AnnotationId(new_i)) = id {
+                new_indices[annot_i] = new_i;
+                result.annots[new_i].id = new_indices[annot.id];
+                result.annots[new_i].secondary = annot.secondary;
+                result.annots[new_i].preferred_ml_slot = annot.preferred_ml_slot;
+            }
+        }
+        for guide in self.guides.iter() {
+            if guide.group == group {
+                result.add_column_guide(guide.col, guide.style.clone(), guide.label.clone());
+            }
+        }
+        result.assign_ml_slots();
+        result
+    }
+
+    /// Adds an annotation made of several spans that share `style` and
+    /// `label`.
+    ///
+    /// Carets are drawn under each span, but the label is only attached to
+    /// the last span (in source order). Spans are handled as in
+    /// [`add_annotation`](Self::add_annotation), ignored spans do not
+    /// receive the label.
+    ///
+    /// Returns the id of the annotation, or `None` if all spans were
+    /// ignored.
+    pub fn add_multi_span_annotation<I>(
+        &mut self,
+        spans: I,
+        style: AnnotStyle<M>,
+        label: Vec<(String, M)>,
+    ) -> Option<AnnotationId>
+    where
+        I: IntoIterator<Item = core::ops::Range<usize>>,
+    {
+        let mut spans: Vec<_> = spans
+            .into_iter()
+            .filter(|span| self.convert_span(span.clone()).is_some())
+            .collect();
+        spans.sort_by_key(|span| span.start);
+
+        // The span with the label is added first, so it is the primary one.
+        let labeled_span = spans.pop()?;
+        let id = self.add_annotation(labeled_span, style.clone(), label)?;
+        for span in spans {
+            if let Some(AnnotationId(annot_i)) =
+                self.add_annotation(span, style.clone(), Vec::new())
+            {
+                self.annots[annot_i].id = id.0;
+            }
+        }
+        Some(id)
+    }
+}
+
+/// Returns whether a new annotation, whose span is `unit_len` units long,
+/// takes precedence over the annotation `current_i` (or `usize::MAX` if
+/// there is none) when applying styles.
+fn takes_precedence<M>(
+    precedence: StylePrecedence,
+    annots: &[AnnotData<M>],
+    unit_len: usize,
+    current_i: usize,
+) -> bool {
+    if current_i == usize::MAX {
+        return true;
+    }
+    match precedence {
+        StylePrecedence::FirstAdded => false,
+        StylePrecedence::LastAdded => true,
+        StylePrecedence::Innermost => unit_len < annots[current_i].unit_len,
+    }
+}
+
+fn sanitize_label(text: &str, sanitization: LabelSanitization) -> String {
+    match sanitization {
+        LabelSanitization::None => text.into(),
+        LabelSanitization::Strip => text.chars().filter(|chr| !chr.is_control()).collect(),
+        LabelSanitization::Escape => {
+            let mut escaped = String::with_capacity(text.len());
+            for chr in text.chars() {
+                if chr.is_control() {
+                    escaped.extend(chr.escape_default());
+                } else {
+                    escaped.push(chr);
+                }
+            }
+            escaped
+        }
+    }
+}
+
+/// Returns the note that counts the `num_unlabeled` labels dropped from a
+/// line, starting with `marker`.
+fn unlabeled_note(marker: &str, num_unlabeled: usize) -> String {
+    if num_unlabeled == 1 {
+        format!("{marker} and 1 more annotation")
+    } else {
+        format!("{marker} and {num_unlabeled} more annotations")
+    }
+}
@@ -0,0 +1,1766 @@
+use alloc::string::String;
+use alloc::string::ToString as _;
+use alloc::{vec, vec::Vec};
+
+use super::{
+    sanitize_label, unlabeled_note, AnnotData, AnnotationId, Annotations, GuideData, LineData,
+};
+use crate::bidi::VisualLine;
+#[cfg(feature = "instrumentation")]
+use crate::instrument::{Phase, PhaseStats};
+use crate::layout::{ChunkKind, LayoutElementKind, LayoutRow, RenderLayout, RowKind};
+use crate::{EndLabelOverflow, LabelAnchor, MainStyle, Output, WideCharCarets};
+
+/// Options to render a collection of [`Annotations`].
+///
+/// Every option has a default (see [`RenderOptions::default`]), so only the
+/// ones that differ need to be set:
+///
+/// ```
+/// let options = sourceannot::RenderOptions {
+///     max_fill_after_first: 1,
+///     max_fill_before_last: 1,
+///     indent: Some(("> ", ())),
+///     ..sourceannot::RenderOptions::default()
+/// };
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RenderOptions<'r, M> {
+    /// Width reserved for line numbers in the margin.
+    ///
+    /// It should be at least
+    /// [`Annotations::max_line_no_width_with_options`], but it can be
+    /// greater to align the margin of multiple snippets. If `None`, that
+    /// width is used.
+    pub max_line_no_width: Option<usize>,
+
+    /// Maximum number of unannotated lines rendered after an annotated
+    /// line when the lines up to the next annotated line are folded.
+    pub max_fill_after_first: usize,
+
+    /// Maximum number of unannotated lines rendered before an annotated
+    /// line when the lines since the previous annotated line are folded.
+    pub max_fill_before_last: usize,
+
+    /// Number of context lines shown before and after each annotated line.
+    ///
+    /// If `Some(n)`, up to `n` lines are shown before the first annotated
+    /// line, after the last one and on each side of the gaps between
+    /// annotated lines, and each gap with more than `2 * n` lines is folded
+    /// into a single row. [`max_fill_after_first`](Self::max_fill_after_first)
+    /// and [`max_fill_before_last`](Self::max_fill_before_last) are then
+    /// ignored. If `None`, only the lines between annotated lines are
+    /// filled, according to those options.
+    pub context_lines: Option<usize>,
+
+    /// Whether to render the line of a gap that would fold a single line
+    /// instead of the `·` row of the margin.
+    ///
+    /// Folding a single line saves no space and hides the line, so with
+    /// `true`, gaps are only folded when they hide at least two lines.
+    pub unfold_single_line_gaps: bool,
+
+    /// Number of the first line of the snippet in the margin.
+    ///
+    /// If `None`, the start line the snippet was built with is used.
+    pub start_line: Option<usize>,
+
+    /// Text (and its metadata) that prefixes every row instead of the
+    /// margin, regardless of [`MainStyle::margin`].
+    ///
+    /// This is useful to embed snippets in other structures, such as
+    /// bulleted lists or quotes. The vertical lines of multi-line
+    /// annotations are still drawn after the indent.
+    pub indent: Option<(&'r str, M)>,
+
+    /// Number of blank rows (with only the margin) before the first line.
+    pub padding_before: usize,
+
+    /// Number of blank rows (with only the margin) after the last row.
+    pub padding_after: usize,
+
+    /// Maximum width of the rendered output, used by the options that avoid
+    /// overflowing it (see [`MainStyle::end_label_overflow`] and
+    /// [`MainStyle::label_wrap_marker`]).
+    ///
+    /// If `None`, the width is not limited.
+    pub render_width: Option<usize>,
+
+    /// Maximum number of single-line annotation labels rendered under each
+    /// line.
+    ///
+    /// Labels of the annotations beyond the limit (in column order) are
+    /// replaced by a single "… and N more annotations" row (see
+    /// [`MainStyle::more_annotations_marker`]), but their carets
+    /// are still drawn. If `None`, the number of labels is not limited.
+    pub max_labels_per_line: Option<usize>,
+
+    /// Number of single-line annotation labels under a line above which
+    /// the labels of secondary annotations (see
+    /// [`Annotations::set_secondary`]) are dropped.
+    ///
+    /// Dropped labels are counted in the same note as
+    /// [`max_labels_per_line`](Self::max_labels_per_line). If `None`,
+    /// secondary annotations are rendered like any other.
+    pub secondary_label_threshold: Option<usize>,
+
+    /// Whether to reorder runs of right-to-left characters (e.g., Arabic
+    /// or Hebrew) of source lines for display (experimental).
+    ///
+    /// Reordered lines are rendered in visual order, wrapped in a
+    /// left-to-right override (U+202D ... U+202C) so they are not reordered
+    /// again, and the carets and labels of single-line annotations are
+    /// placed under the visual columns of the annotated text. The
+    /// reordering is a simplification of the Unicode Bidirectional
+    /// Algorithm, and multi-line annotations still point to logical
+    /// columns.
+    pub bidi_reordering: bool,
+}
+
+impl<M> Default for RenderOptions<'_, M> {
+    /// Returns options that render the margin with the snippet line
+    /// numbers, fold every unannotated line between annotated lines and do
+    /// not limit the width of the output.
+    fn default() -> Self {
+        Self {
+            max_line_no_width: None,
+            max_fill_after_first: 0,
+            max_fill_before_last: 0,
+            context_lines: None,
+            unfold_single_line_gaps: false,
+            start_line: None,
+            indent: None,
+            padding_before: 0,
+            padding_after: 0,
+            render_width: None,
+            max_labels_per_line: None,
+            secondary_label_threshold: None,
+            bidi_reordering: false,
+        }
+    }
+}
+
+/// Rendered parts, along with the layout of the annotations drawn in them.
+struct RenderOutput<M> {
+    parts: Vec<(String, M)>,
+    // Semantic role of each part
+    kinds: Vec<ChunkKind>,
+    // `id` of each annotation
+    ids: Vec<usize>,
+    layout: RenderLayout,
+    // Display column of the end of the last row
+    col: usize,
+    // Whether rendering was abandoned before the end
+    cancelled: bool,
+    // Column guides drawn at the end of each row
+    guides: Option<RowGuides<M>>,
+}
+
+/// Vertical rules of column guides, drawn at the end of each row that ends
+/// before them.
+struct RowGuides<M> {
+    // Display column (including the margin) and metadata of each guide,
+    // sorted by column
+    guides: Vec<(usize, M)>,
+    vertical_char: char,
+    spaces_meta: M,
+}
+
+impl<M: Clone> RenderOutput<M> {
+    fn new(ids: Vec<usize>) -> Self {
+        Self {
+            parts: Vec::new(),
+            kinds: Vec::new(),
+            ids,
+            layout: RenderLayout {
+                rows: vec![LayoutRow::default()],
+            },
+            col: 0,
+            cancelled: false,
+            guides: None,
+        }
+    }
+
+    /// Pushes a part that does not belong to any annotation.
+    #[inline]
+    fn push(&mut self, part: (String, M), kind: ChunkKind) {
+        self.push_impl(part, usize::MAX, LayoutElementKind::Line, kind);
+    }
+
+    /// Pushes a part that belongs to the annotation `annot_i` (or to none,
+    /// if it is `usize::MAX`, in which case it is whitespace).
+    fn push_annot(&mut self, part: (String, M), annot_i: usize, kind: LayoutElementKind) {
+        let chunk_kind = match kind {
+            _ if annot_i == usize::MAX => ChunkKind::Whitespace,
+            LayoutElementKind::Caret => ChunkKind::Caret,
+            LayoutElementKind::Line => ChunkKind::Connector,
+            LayoutElementKind::Label => ChunkKind::Label,
+        };
+        self.push_impl(part, annot_i, kind, chunk_kind);
+    }
+
+    fn push_impl(
+        &mut self,
+        part: (String, M),
+        annot_i: usize,
+        kind: LayoutElementKind,
+        chunk_kind: ChunkKind,
+    ) {
+        if self.guides.is_none() || !part.0.contains('\n') {
+            self.push_raw(part, annot_i, kind, chunk_kind);
+            return;
+        }
+        // Guides are drawn before the line break of each row
+        let (text, meta) = part;
+        for (i, segment) in text.split('\n').enumerate() {
+            if i != 0 {
+                self.put_guides();
+                self.push_raw(('\n'.into(), meta.clone()), annot_i, kind, chunk_kind);
+            }
+            if !segment.is_empty() {
+                self.push_raw((segment.into(), meta.clone()), annot_i, kind, chunk_kind);
+            }
+        }
+    }
+
+    /// Draws the column guides that are after the end of the current row.
+    fn put_guides(&mut self) {
+        let Some(guides) = self.guides.take() else {
+            return;
+        };
+        for (col, meta) in guides.guides.iter() {
+            if self.col > *col {
+                continue;
+            }
+            if self.col < *col {
+                self.push_raw(
+                    (" ".repeat(col - self.col), guides.spaces_meta.clone()),
+                    usize::MAX,
+                    LayoutElementKind::Line,
+                    ChunkKind::Whitespace,
+                );
+            }
+            self.push_raw(
+                (guides.vertical_char.into(), meta.clone()),
+                usize::MAX,
+                LayoutElementKind::Line,
+                ChunkKind::Connector,
+            );
+        }
+        self.guides = Some(guides);
+    }
+
+    fn push_raw(
+        &mut self,
+        part: (String, M),
+        annot_i: usize,
+        kind: LayoutElementKind,
+        chunk_kind: ChunkKind,
+    ) {
+        for (i, segment) in part.0.split('\n').enumerate() {
+            if i != 0 {
+                self.layout.rows.push(LayoutRow::default());
+                self.col = 0;
+            }
+            let width: usize = segment
+                .chars()
+                .map(|chr| crate::width::char_width(chr).unwrap_or(0))
+                .sum();
+            if annot_i != usize::MAX && width != 0 {
+                self.layout.push_element(
+                    AnnotationId(self.ids[annot_i]),
+                    kind,
+                    self.col..(self.col + width),
+                );
+            }
+            self.col += width;
+        }
+        self.parts.push(part);
+        self.kinds.push(chunk_kind);
+    }
+
+    /// Marks the current row as showing the source line `line_i`.
+    #[inline]
+    fn set_source_line(&mut self, line_i: usize, kind: RowKind) {
+        let row = self.layout.rows.last_mut().unwrap();
+        row.source_line = Some(line_i);
+        row.kind = kind;
+    }
+
+    /// Sets the kind of the current row.
+    #[inline]
+    fn set_row_kind(&mut self, kind: RowKind) {
+        self.layout.rows.last_mut().unwrap().kind = kind;
+    }
+
+    fn finish(mut self) -> (Vec<(String, M)>, RenderLayout) {
+        // The last row is the empty one that follows the last line break
+        self.layout.rows.pop();
+        (self.parts, self.layout)
+    }
+
+    /// Writes the rendered parts to `out`, delimiting each row.
+    fn finish_to<O: Output<M> + ?Sized>(mut self, out: &mut O) {
+        let kinds = core::mem::take(&mut self.kinds);
+        let (parts, layout) = self.finish();
+        let mut row_i = 0;
+        let mut in_row = false;
+        for ((text, meta), &kind) in parts.iter().zip(kinds.iter()) {
+            for segment in text.split_inclusive('\n') {
+                if !in_row {
+                    out.begin_row(layout.rows[row_i].kind);
+                    in_row = true;
+                }
+                out.put(segment, meta, kind);
+                if segment.ends_with('\n') {
+                    out.end_row();
+                    row_i += 1;
+                    in_row = false;
+                }
+            }
+        }
+    }
+
+    fn finish_with_kinds(self) -> Vec<(String, M, ChunkKind)> {
+        self.parts
+            .into_iter()
+            .zip(self.kinds)
+            .map(|((text, meta), kind)| (text, meta, kind))
+            .collect()
+    }
+}
+
+impl<'a, M: Clone> Annotations<'a, M> {
+    /// Renders each group of annotations as a separate snippet block, in
+    /// ascending order of group id.
+    ///
+    /// See [`group`](Self::group) and
+    /// [`render_with_options`](Self::render_with_options).
+    pub fn render_groups(&self, options: &RenderOptions<'_, M>) -> Vec<Vec<(String, M)>> {
+        self.group_ids()
+            .into_iter()
+            .map(|group| self.group(group).render_with_options(options))
+            .collect()
+    }
+
+    /// Renders the snippet with the annotations.
+    ///
+    /// `max_line_no_width` should be at least
+    /// [`self.max_line_no_width()`](Self::max_line_no_width), but
+    /// it can be greater to align the margin of multiple snippets.
+    ///
+    /// This is a shorthand for
+    /// [`render_with_options`](Self::render_with_options) with the default
+    /// value of the rest of [`RenderOptions`].
+    pub fn render(
+        &self,
+        max_line_no_width: usize,
+        max_fill_after_first: usize,
+        max_fill_before_last: usize,
+    ) -> Vec<(String, M)> {
+        self.render_with_options(&RenderOptions {
+            max_line_no_width: Some(max_line_no_width),
+            max_fill_after_first,
+            max_fill_before_last,
+            ..RenderOptions::default()
+        })
+    }
+
+    /// Renders the snippet with the annotations according to `options`.
+    pub fn render_with_options(&self, options: &RenderOptions<'_, M>) -> Vec<(String, M)> {
+        self.render_output(options, None).finish().0
+    }
+
+    /// Renders the snippet like
+    /// [`render_with_options`](Self::render_with_options), also returning
+    /// the layout of the output.
+    ///
+    /// The layout can be queried with [`RenderLayout::hit_test`] to find the
+    /// annotation drawn at a position of the output (e.g., the one under the
+    /// mouse cursor), as many times as needed without rendering again.
+    pub fn render_with_layout(
+        &self,
+        options: &RenderOptions<'_, M>,
+    ) -> (Vec<(String, M)>, RenderLayout) {
+        self.render_output(options, None).finish()
+    }
+
+    /// Returns the annotation drawn at display column `col` of row `row`
+    /// (both zero-based) of the output of
+    /// [`render_with_options`](Self::render_with_options) with `options`, if
+    /// any.
+    ///
+    /// This renders the snippet on each call, so use
+    /// [`render_with_layout`](Self::render_with_layout) and
+    /// [`RenderLayout::hit_test`] to query several positions.
+    pub fn hit_test(
+        &self,
+        options: &RenderOptions<'_, M>,
+        row: usize,
+        col: usize,
+    ) -> Option<AnnotationId> {
+        let (_, layout) = self.render_with_layout(options);
+        layout.hit_test(row, col)
+    }
+
+    /// Renders the snippet like
+    /// [`render_with_options`](Self::render_with_options), also returning
+    /// the semantic role of each chunk.
+    pub fn render_with_kinds(&self, options: &RenderOptions<'_, M>) -> Vec<(String, M, ChunkKind)> {
+        self.render_output(options, None).finish_with_kinds()
+    }
+
+    /// Renders the snippet like
+    /// [`render_with_options`](Self::render_with_options), writing the
+    /// output to `out` row by row.
+    pub fn render_to<O: Output<M> + ?Sized>(&self, out: &mut O, options: &RenderOptions<'_, M>) {
+        self.render_output(options, None).finish_to(out);
+    }
+
+    /// Renders the snippet like
+    /// [`render_with_options`](Self::render_with_options), calling
+    /// `should_cancel` before rendering each source line and abandoning
+    /// rendering if it returns `true`.
+    ///
+    /// Returns `None` if rendering was cancelled. This allows interactive
+    /// applications to stop rendering enormous snippets that are no longer
+    /// needed (e.g., when the user scrolls away).
+    pub fn render_cancellable<F>(
+        &self,
+        should_cancel: F,
+        options: &RenderOptions<'_, M>,
+    ) -> Option<Vec<(String, M)>>
+    where
+        F: Fn() -> bool,
+    {
+        let parts = self.render_output(options, Some(&should_cancel));
+        if parts.cancelled {
+            None
+        } else {
+            Some(parts.finish().0)
+        }
+    }
+
+    fn render_output(
+        &self,
+        options: &RenderOptions<'_, M>,
+        should_cancel: Option<&dyn Fn() -> bool>,
+    ) -> RenderOutput<M> {
+        #[cfg(feature = "instrumentation")]
+        if let Some(hooks) = self.hooks {
+            hooks.0.phase_begin(Phase::Render);
+            let parts = Renderer::new(self, options, should_cancel).render();
+            hooks.0.phase_end(
+                Phase::Render,
+                &PhaseStats {
+                    items: parts.parts.len(),
+                },
+            );
+            return parts;
+        }
+        Renderer::new(self, options, should_cancel).render()
+    }
+}
+
+/// The labels of the single-line annotations of a line, in the order in
+/// which they are drawn.
+struct LineLabels {
+    // Labeled annotations with carets, sorted by label column
+    all_labeled: Vec<usize>,
+    // Same as `all_labeled`, but annotations with the same span as a
+    // previous one are removed, since they do not get their own vertical
+    // line
+    labeled: Vec<usize>,
+    // Annotations whose labels are stacked under each of `labeled`
+    stacked: Vec<Vec<usize>>,
+    // Labeled annotations without carets, whose labels are placed on their
+    // own rows after the rest
+    hidden_labeled: Vec<usize>,
+    // Annotations whose labels were dropped
+    num_unlabeled: usize,
+    // Last annotation with carets (or the one of `labeled` with its span)
+    last_with_carets: Option<usize>,
+    // Whether the last label needs a vertical line instead of being placed
+    // on the caret row
+    last_has_vertical: bool,
+    // Delimiter of the labels merged onto the caret row, if they are
+    merged_labels: Option<&'static str>,
+}
+
+impl LineLabels {
+    /// Returns the annotations whose labels are stacked under the label of
+    /// `annot_i`.
+    fn stacked_under(&self, annot_i: usize) -> &[usize] {
+        self.labeled
+            .iter()
+            .position(|&other_i| other_i == annot_i)
+            .map_or(&[][..], |k| self.stacked[k].as_slice())
+    }
+
+    /// Returns the annotations whose labels are placed under the caret row,
+    /// each with its own vertical line.
+    fn with_verticals(&self) -> &[usize] {
+        if self.merged_labels.is_some() {
+            &[]
+        } else if self.last_has_vertical || self.labeled.is_empty() {
+            &self.labeled
+        } else {
+            &self.labeled[..(self.labeled.len() - 1)]
+        }
+    }
+}
+
+/// Rows of a block of labels, each with the annotation it belongs to and
+/// whether it is the last row of its label.
+type LabelBlock<M> = Vec<(usize, Vec<(String, M)>, bool)>;
+
+/// A rendering in progress, which draws the output row by row.
+struct Renderer<'r, 'a, M> {
+    annots: &'r Annotations<'a, M>,
+    options: &'r RenderOptions<'r, M>,
+    // Number of the first line of the snippet
+    start_line: usize,
+    max_line_no_width: usize,
+    max_fill_after_first: usize,
+    max_fill_before_last: usize,
+    // Checked before each source line to abandon rendering
+    should_cancel: Option<&'r dyn Fn() -> bool>,
+    // Width of the margin, including the space after it
+    margin_width: usize,
+    // Annotation whose vertical line is drawn in each multi-line slot
+    ml_slots: Vec<Option<usize>>,
+    // Whether the annotation of each slot starts on the current line
+    is_slot_start: Vec<bool>,
+    parts: RenderOutput<M>,
+}
+
+impl<'r, 'a, M: Clone> Renderer<'r, 'a, M> {
+    fn new(
+        annots: &'r Annotations<'a, M>,
+        options: &'r RenderOptions<'r, M>,
+        should_cancel: Option<&'r dyn Fn() -> bool>,
+    ) -> Self {
+        let (max_fill_after_first, max_fill_before_last) = match options.context_lines {
+            Some(context_lines) => (context_lines, context_lines),
+            None => (options.max_fill_after_first, options.max_fill_before_last),
+        };
+        Self {
+            annots,
+            options,
+            start_line: options
+                .start_line
+                .unwrap_or_else(|| annots.snippet.start_line()),
+            max_line_no_width: annots.line_no_width(options),
+            max_fill_after_first,
+            max_fill_before_last,
+            should_cancel,
+            margin_width: annots.margin_width(options),
+            ml_slots: vec![None; annots.num_ml_slots],
+            is_slot_start: vec![false; annots.num_ml_slots],
+            parts: RenderOutput::new(annots.annots.iter().map(|annot| annot.id).collect()),
+        }
+    }
+
+    #[inline]
+    fn style(&self) -> &'r MainStyle<M> {
+        let annots: &'r Annotations<'a, M> = self.annots;
+        &annots.main_style
+    }
+
+    #[inline]
+    fn annot(&self, annot_i: usize) -> &'r AnnotData<M> {
+        let annots: &'r Annotations<'a, M> = self.annots;
+        &annots.annots[annot_i]
+    }
+
+    /// Returns whether rendering has to be abandoned, marking the output as
+    /// cancelled if so.
+    fn check_cancelled(&mut self) -> bool {
+        if self
+            .should_cancel
+            .is_some_and(|should_cancel| should_cancel())
+        {
+            self.parts.cancelled = true;
+        }
+        self.parts.cancelled
+    }
+
+    fn render(mut self) -> RenderOutput<M> {
+        let annots = self.annots;
+        let (Some((&first_line_i, _)), Some((&last_line_i, _))) = (
+            annots.lines.first_key_value(),
+            annots.lines.last_key_value(),
+        ) else {
+            return self.parts;
+        };
+
+        self.put_padding(self.options.padding_before);
+
+        // Column guides, sorted by column (in order of addition if equal)
+        let mut guides: Vec<&GuideData<M>> = annots.guides.iter().collect();
+        guides.sort_by_key(|guide| guide.col);
+        if !guides.is_empty() {
+            self.parts.guides = Some(RowGuides {
+                guides: guides
+                    .iter()
+                    .map(|guide| {
+                        (
+                            self.margin_width + annots.gutter_width() + guide.col,
+                            guide.style.line_meta.clone(),
+                        )
+                    })
+                    .collect(),
+                vertical_char: self.style().vertical_char,
+                spaces_meta: self.style().spaces_meta.clone(),
+            });
+        }
+
+        let rendered_lines = annots.rendered_lines(self.options).unwrap();
+
+        // Leading context lines
+        for line_i in *rendered_lines.start()..first_line_i {
+            if self.check_cancelled() {
+                return self.parts;
+            }
+            self.put_fill_line(line_i);
+        }
+
+        let mut prev_line_i = None;
+        for (&line_i, line_data) in annots.lines.iter() {
+            if self.check_cancelled() {
+                return self.parts;
+            }
+            if let Some(prev_line_i) = prev_line_i {
+                if !self.put_gap(prev_line_i, line_i) {
+                    return self.parts;
+                }
+            }
+            self.put_annotated_line(line_i, line_data);
+            prev_line_i = Some(line_i);
+        }
+
+        // Trailing context lines
+        for line_i in (last_line_i + 1)..=*rendered_lines.end() {
+            if self.check_cancelled() {
+                return self.parts;
+            }
+            self.put_fill_line(line_i);
+        }
+
+        self.parts.guides = None;
+        self.put_guide_ends(&guides);
+
+        self.put_padding(self.options.padding_after);
+
+        self.parts
+    }
+
+    /// Renders `n` blank rows with only the margin.
+    fn put_padding(&mut self, n: usize) {
+        for _ in 0..n {
+            self.parts.set_row_kind(RowKind::Padding);
+            self.put_margin(None, false);
+            self.put_line_break();
+        }
+    }
+
+    #[inline]
+    fn put_line_break(&mut self) {
+        self.put_whitespace('\n'.into());
+    }
+
+    #[inline]
+    fn put_whitespace(&mut self, text: String) {
+        self.parts.push(
+            (text, self.style().spaces_meta.clone()),
+            ChunkKind::Whitespace,
+        );
+    }
+
+    /// Renders the left margin of a row:
+    /// with line number:    `123 │ `
+    /// without line number: `    │ `
+    /// with dot:            `    · `
+    /// embedded:            `<indent>`
+    fn put_margin(&mut self, line_i: Option<usize>, is_dot: bool) {
+        let style = self.style();
+        if is_dot {
+            self.parts.set_row_kind(RowKind::Fold);
+        }
+        if let Some((indent, ref indent_meta)) = self.options.indent {
+            if !indent.is_empty() {
+                self.parts
+                    .push((indent.into(), indent_meta.clone()), ChunkKind::Margin);
+            }
+        } else if let Some(ref margin_style) = style.margin {
+            if let Some(line_i) = line_i {
+                // Line numbers saturate at `usize::MAX`.
+                let line_no = line_i.saturating_add(self.start_line);
+                let line_no_str = line_no.to_string();
+                let line_no_width = line_no_str.len();
+                self.parts.push(
+                    (line_no_str, margin_style.meta.clone()),
+                    ChunkKind::LineNumber,
+                );
+                self.parts.push(
+                    (
+                        " ".repeat(self.max_line_no_width.saturating_sub(line_no_width) + 1),
+                        style.spaces_meta.clone(),
+                    ),
+                    ChunkKind::Margin,
+                );
+            } else {
+                self.parts.push(
+                    (
+                        " ".repeat(self.max_line_no_width + 1),
+                        style.spaces_meta.clone(),
+                    ),
+                    ChunkKind::Margin,
+                );
+            }
+
+            let chr = if is_dot {
+                margin_style.dot_char
+            } else {
+                margin_style.line_char
+            };
+            self.parts
+                .push((chr.into(), margin_style.meta.clone()), ChunkKind::Margin);
+            self.parts
+                .push((' '.into(), style.spaces_meta.clone()), ChunkKind::Margin);
+        }
+    }
+
+    /// Renders the text of an annotated line
+    fn put_line_text(
+        &mut self,
+        line_i: usize,
+        styles: &[(usize, bool)],
+        visual: Option<&VisualLine>,
+    ) {
+        let annots = self.annots;
+        let style = self.style();
+        self.parts.set_source_line(line_i, RowKind::Source);
+        let line = annots.snippet.line(line_i);
+        assert_eq!(styles.len(), line.text.len());
+        // Placeholder drawn after the text of the last line
+        let mut eof_placeholder = None;
+        if let Some(placeholder) = style.eof_placeholder {
+            if line_i == annots.snippet.num_lines() - 1 {
+                if line.text.is_empty() {
+                    self.parts.push(
+                        (placeholder.into(), style.text_alt_meta.clone()),
+                        ChunkKind::SourceText,
+                    );
+                } else if annots.is_eof_annotated() {
+                    eof_placeholder = Some(placeholder);
+                }
+            }
+        }
+        let style_meta = |(annot_i, is_alt): (usize, bool)| {
+            let base_meta = if is_alt {
+                &style.text_alt_meta
+            } else {
+                &style.text_normal_meta
+            };
+            if annot_i == usize::MAX {
+                return base_meta.clone();
+            }
+            let annot_style = &annots.annots[annot_i].style;
+            let annot_meta = if is_alt {
+                &annot_style.text_alt_meta
+            } else {
+                &annot_style.text_normal_meta
+            };
+            match annots.text_meta_combine {
+                Some(combine) => combine(base_meta, annot_meta),
+                None => annot_meta.clone(),
+            }
+        };
+        if let Some(visual) = visual {
+            // Left-to-right override, so the reordered text is not
+            // reordered again by the terminal
+            self.parts.push(
+                ("\u{202D}".into(), style.text_normal_meta.clone()),
+                ChunkKind::SourceText,
+            );
+            let mut i = 0;
+            while i < visual.chars.len() {
+                let text_style = styles[visual.chars[i].start];
+                let len = visual.chars[i..]
+                    .iter()
+                    .position(|range| styles[range.start] != text_style)
+                    .unwrap_or(visual.chars.len() - i);
+                let text: String = visual.chars[i..(i + len)]
+                    .iter()
+                    .map(|range| &line.text[range.clone()])
+                    .collect();
+                self.parts
+                    .push((text, style_meta(text_style)), ChunkKind::SourceText);
+                i += len;
+            }
+            self.parts.push(
+                ("\u{202C}".into(), style.text_normal_meta.clone()),
+                ChunkKind::SourceText,
+            );
+        } else {
+            let mut chr_i = 0;
+            while chr_i < line.text.len() {
+                let text_style = styles[chr_i];
+                let len = styles[chr_i..]
+                    .iter()
+                    .position(|&other_style| other_style != text_style)
+                    .unwrap_or(styles.len() - chr_i);
+                self.parts.push(
+                    (
+                        String::from(&line.text[chr_i..(chr_i + len)]),
+                        style_meta(text_style),
+                    ),
+                    ChunkKind::SourceText,
+                );
+                chr_i += len;
+            }
+        }
+        if let Some(placeholder) = eof_placeholder {
+            self.parts.push(
+                (placeholder.into(), style.text_alt_meta.clone()),
+                ChunkKind::SourceText,
+            );
+        }
+        self.put_line_break();
+    }
+
+    /// Renders an unannotated line, with its margin and the vertical lines
+    /// of the multi-line annotations that cross it.
+    fn put_fill_line(&mut self, line_i: usize) {
+        let annots = self.annots;
+        self.put_margin(Some(line_i), false);
+        self.put_slots_simple();
+        self.parts.set_source_line(line_i, RowKind::Fill);
+        let line = annots.snippet.line(line_i);
+        let text = match annots.visual_line(line_i, self.options) {
+            Some(visual) => core::iter::once("\u{202D}")
+                .chain(visual.chars.iter().map(|range| &line.text[range.clone()]))
+                .chain(core::iter::once("\u{202C}"))
+                .collect(),
+            None => String::from(&*line.text),
+        };
+        self.parts.push(
+            (text, self.style().text_normal_meta.clone()),
+            ChunkKind::Fill,
+        );
+        self.put_line_break();
+    }
+
+    /// Renders the lines between the annotated lines `prev_line_i` and
+    /// `line_i`, folding them if there are too many.
+    ///
+    /// Returns `false` if rendering was cancelled.
+    fn put_gap(&mut self, prev_line_i: usize, line_i: usize) -> bool {
+        let gap_len: usize = line_i - prev_line_i - 1;
+        let num_folded =
+            gap_len.saturating_sub(self.max_fill_after_first + self.max_fill_before_last);
+        let min_folded = if self.options.unfold_single_line_gaps {
+            2
+        } else {
+            1
+        };
+        if num_folded >= min_folded {
+            for i in 0..self.max_fill_after_first {
+                self.put_fill_line(prev_line_i + 1 + i);
+            }
+            self.put_margin(None, true);
+            self.put_slots_simple();
+            self.put_line_break();
+            for i in (0..self.max_fill_before_last).rev() {
+                self.put_fill_line(line_i - 1 - i);
+            }
+        } else {
+            for line_i in (prev_line_i + 1)..line_i {
+                if self.check_cancelled() {
+                    return false;
+                }
+                self.put_fill_line(line_i);
+            }
+        }
+        true
+    }
+
+    /// Renders an annotated line, followed by the carets and labels of its
+    /// annotations.
+    fn put_annotated_line(&mut self, line_i: usize, line_data: &LineData) {
+        let annots = self.annots;
+
+        // Handle multi line annotations that start at the beginning of the line
+        for &annot_i in line_data.ml_annots_starts.iter() {
+            let annot = self.annot(annot_i);
+            if !annots.has_short_start(annot) {
+                continue;
+            }
+
+            assert!(self.ml_slots[annot.ml_slot].is_none());
+            assert!(!self.is_slot_start[annot.ml_slot]);
+            self.ml_slots[annot.ml_slot] = Some(annot_i);
+            self.is_slot_start[annot.ml_slot] = true;
+        }
+
+        self.put_margin(Some(line_i), false);
+        self.put_slots_with_short_start();
+        let visual = annots.visual_line(line_i, self.options);
+        self.put_line_text(line_i, &line_data.styles, visual.as_ref());
+
+        self.is_slot_start.fill(false);
+
+        let labels = self.line_labels(line_data, visual.as_ref());
+        if labels.last_with_carets.is_some() {
+            self.put_caret_row(line_i, line_data, visual.as_ref(), &labels);
+        }
+        self.put_label_rows(visual.as_ref(), &labels);
+        self.put_hidden_labels(visual.as_ref(), &labels.hidden_labeled);
+        if labels.num_unlabeled != 0 {
+            self.put_unlabeled_note(labels.num_unlabeled);
+        }
+
+        // Handle multi line annotations that end at this line
+        for &annot_i in line_data.ml_annots_ends.iter() {
+            self.put_ml_end(annot_i);
+        }
+
+        // Handle multi line annotations that start at this line
+        // (but not at the beginning of the line)
+        for &annot_i in line_data.ml_annots_starts.iter() {
+            if !annots.has_short_start(self.annot(annot_i)) {
+                self.put_ml_start(annot_i);
+            }
+        }
+    }
+
+    /// Chooses which labels of the single-line annotations of a line are
+    /// drawn and how.
+    fn line_labels(&self, line_data: &LineData, visual: Option<&VisualLine>) -> LineLabels {
+        let annots = self.annots;
+        let style = self.style();
+
+        // Secondary spans of multi-span annotations only have carets.
+        let mut sl_annots: Vec<usize> = line_data
+            .sl_annots
+            .iter()
+            .copied()
+            .filter(|&annot_i| self.annot(annot_i).id == annot_i)
+            .collect();
+
+        // Labels of secondary annotations are dropped from crowded lines.
+        let num_annots = sl_annots.len();
+        if self
+            .options
+            .secondary_label_threshold
+            .is_some_and(|threshold| num_annots > threshold)
+        {
+            sl_annots.retain(|&annot_i| !self.annot(annot_i).secondary);
+        }
+        if style.label_anchor == LabelAnchor::LastCaret || visual.is_some() {
+            sl_annots.sort_by_key(|&annot_i| annots.label_col(self.annot(annot_i), visual));
+        }
+
+        // Only the first `max_labels_per_line` single line annotations
+        // get a label, the rest are summarized in a note.
+        let sl_labeled = match self.options.max_labels_per_line {
+            Some(max) => &sl_annots[..max.min(sl_annots.len())],
+            None => sl_annots.as_slice(),
+        };
+        let num_unlabeled = num_annots - sl_labeled.len();
+
+        // Labels of annotations without carets are placed on their own
+        // rows, after the rest.
+        let is_hidden = |&annot_i: &usize| self.annot(annot_i).style.hide_carets;
+        let (hidden_labeled, all_labeled): (Vec<usize>, Vec<usize>) =
+            sl_labeled.iter().copied().partition(is_hidden);
+
+        // Annotations with the same span as a previous one do not get
+        // their own vertical line: their labels are stacked under the
+        // label of the first one with a non-empty label.
+        let mut labeled: Vec<usize> = Vec::new();
+        let mut stacked: Vec<Vec<usize>> = Vec::new();
+        for &annot_i in all_labeled.iter() {
+            let annot = self.annot(annot_i);
+            let has_label = annot.label.iter().any(|(s, _)| !s.is_empty());
+            match labeled
+                .iter()
+                .position(|&other_i| Annotations::is_same_span(self.annot(other_i), annot))
+            {
+                Some(_) if !has_label => {}
+                Some(k)
+                    if self
+                        .annot(labeled[k])
+                        .label
+                        .iter()
+                        .all(|(s, _)| s.is_empty()) =>
+                {
+                    labeled[k] = annot_i;
+                }
+                Some(k) => stacked[k].push(annot_i),
+                None => {
+                    labeled.push(annot_i);
+                    stacked.push(Vec::new());
+                }
+            }
+        }
+
+        let last_with_carets = line_data
+            .sl_annots
+            .iter()
+            .rev()
+            .find(|annot_i| !is_hidden(annot_i))
+            .map(|&annot_i| {
+                labeled
+                    .iter()
+                    .copied()
+                    .find(|&other_i| {
+                        Annotations::is_same_span(self.annot(other_i), self.annot(annot_i))
+                    })
+                    .unwrap_or(annot_i)
+            });
+
+        // Labels are never placed after the carets of unlabeled
+        // annotations, to avoid attributing them to the wrong ones.
+        let last_has_vertical = last_with_carets.as_ref() != labeled.last()
+            || labeled
+                .last()
+                .is_some_and(|&annot_i| self.annot(annot_i).sl_overlaps);
+
+        // Labels of non-overlapping annotations can be merged onto the
+        // caret row.
+        let merged_labels = match style.label_delimiter {
+            Some(delimiter)
+                if !last_has_vertical
+                    && all_labeled.len() > 1
+                    && all_labeled
+                        .iter()
+                        .all(|&annot_i| !self.annot(annot_i).sl_overlaps) =>
+            {
+                Some(delimiter)
+            }
+            _ => None,
+        };
+
+        LineLabels {
+            all_labeled,
+            labeled,
+            stacked,
+            hidden_labeled,
+            num_unlabeled,
+            last_with_carets,
+            last_has_vertical,
+            merged_labels,
+        }
+    }
+
+    /// Returns the rows of the label of `annot_i` (wrapped if needed)
+    /// followed by the rows of the labels stacked under it, or `None` if it
+    /// is a single row.
+    fn label_block(
+        &self,
+        labels: &LineLabels,
+        annot_i: usize,
+        start_col: usize,
+    ) -> Option<LabelBlock<M>> {
+        let stacked = labels.stacked_under(annot_i);
+        let wrapped = self.wrap_label(annot_i, start_col);
+        if stacked.is_empty() && wrapped.is_none() {
+            return None;
+        }
+        let mut rows = Vec::new();
+        let members = core::iter::once((annot_i, wrapped)).chain(
+            stacked
+                .iter()
+                .map(|&other_i| (other_i, self.wrap_label(other_i, start_col))),
+        );
+        for (member_i, member_rows) in members {
+            let member_rows = member_rows.unwrap_or_else(|| {
+                let label = self.annot(member_i).label.iter().map(|(text, meta)| {
+                    (
+                        sanitize_label(text, self.style().label_sanitization),
+                        meta.clone(),
+                    )
+                });
+                vec![label.collect()]
+            });
+            let num_rows = member_rows.len();
+            rows.extend(
+                member_rows
+                    .into_iter()
+                    .enumerate()
+                    .map(|(row_i, row)| (member_i, row, row_i == num_rows - 1)),
+            );
+        }
+        Some(rows)
+    }
+
+    /// Splits the label of `annot_i`, which starts at display column
+    /// `start_col` of the source text, into rows if it has to be wrapped
+    /// (see [`MainStyle::label_wrap_marker`]).
+    fn wrap_label(&self, annot_i: usize, start_col: usize) -> Option<Vec<Vec<(String, M)>>> {
+        let annots = self.annots;
+        let style = self.style();
+        let marker = style.label_wrap_marker?;
+        let render_width = self.options.render_width?;
+        let label = &self.annot(annot_i).label;
+        let budget =
+            render_width.saturating_sub(self.margin_width + annots.gutter_width() + start_col);
+        if annots.label_width(label) <= budget {
+            return None;
+        }
+        let width = budget
+            .saturating_sub(crate::width::str_width(marker))
+            .max(1);
+
+        // Characters of each row, with the index of their fragment
+        let mut rows: Vec<Vec<(char, usize)>> = vec![Vec::new()];
+        let mut row_width = 0;
+        for (frag_i, (text, _)) in label.iter().enumerate() {
+            for chr in sanitize_label(text, style.label_sanitization).chars() {
+                let chr_width = crate::width::char_width(chr).unwrap_or(0);
+                let row = rows.last_mut().unwrap();
+                if row_width + chr_width > width && !row.is_empty() {
+                    // Break at the last space of the row, if any
+                    let tail = match row.iter().rposition(|&(c, _)| c == ' ') {
+                        Some(space_i) if space_i != 0 => {
+                            let tail = row.split_off(space_i + 1);
+                            row.pop();
+                            tail
+                        }
+                        _ => Vec::new(),
+                    };
+                    row_width = tail
+                        .iter()
+                        .map(|&(c, _)| crate::width::char_width(c).unwrap_or(0))
+                        .sum();
+                    rows.push(tail);
+                }
+                let row = rows.last_mut().unwrap();
+                if row.is_empty() && chr == ' ' {
+                    continue;
+                }
+                row.push((chr, frag_i));
+                row_width += chr_width;
+            }
+        }
+
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                let mut frags: Vec<(String, usize)> = Vec::new();
+                for (chr, frag_i) in row {
+                    match frags.last_mut() {
+                        Some((text, last_frag_i)) if *last_frag_i == frag_i => text.push(chr),
+                        _ => frags.push((chr.into(), frag_i)),
+                    }
+                }
+                frags
+                    .into_iter()
+                    .map(|(text, frag_i)| (text, label[frag_i].1.clone()))
+                    .collect()
+            })
+            .collect();
+        Some(rows)
+    }
+
+    /// Renders the row with the carets of the single-line annotations of
+    /// `line_i`, with the label placed on it (if any), followed by the
+    /// continuation rows of that label.
+    fn put_caret_row(
+        &mut self,
+        line_i: usize,
+        line_data: &LineData,
+        visual: Option<&VisualLine>,
+        labels: &LineLabels,
+    ) {
+        let annots = self.annots;
+        let style = self.style();
+        self.put_margin(None, false);
+        self.put_slots_simple();
+
+        // With `WideCharCarets::PerChar`, only one cell of each wide
+        // character gets a caret.
+        let wide_gaps = if style.wide_char_carets == WideCharCarets::PerChar {
+            annots.snippet.wide_char_gaps(line_i)
+        } else {
+            Vec::new()
+        };
+        // Columns of the caret row are visual, so they are mapped
+        // back to logical columns.
+        let logical_col = |col: usize| visual.map_or(col, |v| v.logical_col(col));
+        let caret_annot = |col: usize| {
+            let col = logical_col(col);
+            if wide_gaps.get(col).copied().unwrap_or(false) {
+                usize::MAX
+            } else {
+                line_data.sl_carets.get(col).copied().unwrap_or(usize::MAX)
+            }
+        };
+        let mut carets_end = if visual.is_some() {
+            line_data
+                .sl_carets
+                .len()
+                .max(annots.snippet.line_width(line_i))
+        } else {
+            line_data.sl_carets.len()
+        };
+        while carets_end > 0 && caret_annot(carets_end - 1) == usize::MAX {
+            carets_end -= 1;
+        }
+
+        // The gap between linked spans is drawn as a line
+        let in_link_gap = |col: usize, annot_i: usize| {
+            annot_i != usize::MAX
+                && annots.annots[annot_i]
+                    .link
+                    .as_ref()
+                    .is_some_and(|link| link.gap_cols.contains(&logical_col(col)))
+        };
+
+        // With `left_labels`, the label of the last annotation is
+        // placed before its carets if it fits between them and the
+        // previous carets, with a space on both sides.
+        let mut left_label = None;
+        if let (true, false, None, Some(&last_annot_i)) = (
+            style.left_labels,
+            labels.last_has_vertical,
+            labels.merged_labels,
+            labels.labeled.last(),
+        ) {
+            let label_width = if labels.stacked_under(last_annot_i).is_empty() {
+                annots.label_width(&self.annot(last_annot_i).label)
+            } else {
+                // Stacked labels need the column of the carets
+                0
+            };
+            let first_caret = (0..carets_end).find(|&col| caret_annot(col) == last_annot_i);
+            if let (Some(first_caret), true) = (first_caret, label_width != 0) {
+                let free_start = (0..first_caret)
+                    .rev()
+                    .find(|&col| caret_annot(col) != usize::MAX)
+                    .map_or(0, |col| col + 2);
+                if let Some(label_col) = first_caret.checked_sub(label_width + 1) {
+                    if label_col >= free_start {
+                        left_label = Some((last_annot_i, label_col, first_caret));
+                    }
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < carets_end {
+            if let Some((annot_i, label_col, first_caret)) = left_label {
+                if i == label_col {
+                    self.put_label(annot_i);
+                    self.put_whitespace(' '.into());
+                    i = first_caret;
+                    continue;
+                }
+            }
+            let annot_i = caret_annot(i);
+            let is_gap = in_link_gap(i, annot_i);
+            let mut len = (i..carets_end)
+                .position(|col| caret_annot(col) != annot_i || in_link_gap(col, annot_i) != is_gap)
+                .unwrap_or(carets_end - i);
+            if let Some((_, label_col, _)) = left_label {
+                if i < label_col {
+                    len = len.min(label_col - i);
+                }
+            }
+            let chr = if annot_i == usize::MAX {
+                ' '
+            } else if is_gap {
+                style.horizontal_char
+            } else {
+                self.annot(annot_i).style.caret
+            };
+            let meta = if annot_i == usize::MAX {
+                style.spaces_meta.clone()
+            } else {
+                self.annot(annot_i).style.line_meta.clone()
+            };
+            let kind = if is_gap {
+                LayoutElementKind::Line
+            } else {
+                LayoutElementKind::Caret
+            };
+            self.parts.push_annot(
+                (core::iter::repeat(chr).take(len).collect(), meta),
+                annot_i,
+                kind,
+            );
+            i += len;
+        }
+        let mut wrapped = None;
+        if let Some(delimiter) = labels.merged_labels {
+            self.put_whitespace(' '.into());
+            for (k, &annot_i) in labels.all_labeled.iter().enumerate() {
+                if k != 0 {
+                    self.parts.push(
+                        (delimiter.into(), style.spaces_meta.clone()),
+                        ChunkKind::Label,
+                    );
+                }
+                self.put_label(annot_i);
+            }
+        } else if let (false, None, Some(&last_annot_i)) =
+            (labels.last_has_vertical, left_label, labels.labeled.last())
+        {
+            let last_annot = self.annot(last_annot_i);
+            if last_annot.label.iter().any(|(s, _)| !s.is_empty()) {
+                self.put_whitespace(' '.into());
+                let start_col = carets_end + 1;
+                match self.label_block(labels, last_annot_i, start_col) {
+                    Some(rows) => {
+                        let (row_annot_i, row, is_last) = &rows[0];
+                        self.put_label_row(*row_annot_i, row, *is_last);
+                        wrapped = Some((start_col, rows));
+                    }
+                    None => self.put_label(last_annot_i),
+                }
+            }
+        }
+
+        self.put_line_break();
+
+        // Continuation rows of the label, crossed by the vertical
+        // lines of the rest of labels
+        if let Some((start_col, rows)) = wrapped {
+            let with_verticals = &labels.labeled[..(labels.labeled.len() - 1)];
+            self.put_label_continuation(&rows, start_col, with_verticals, visual);
+        }
+    }
+
+    /// Renders the rows of a label block after the first one, starting at
+    /// `start_col` and crossed by the vertical lines of `pending`.
+    fn put_label_continuation(
+        &mut self,
+        rows: &LabelBlock<M>,
+        start_col: usize,
+        pending: &[usize],
+        visual: Option<&VisualLine>,
+    ) {
+        for (annot_i, row, is_last) in rows.iter().skip(1) {
+            self.put_margin(None, false);
+            self.put_slots_simple();
+            let col_cursor = self.put_sl_verticals(pending, visual);
+            self.put_whitespace(" ".repeat(start_col.saturating_sub(col_cursor)));
+            self.put_label_row(*annot_i, row, *is_last);
+            self.put_line_break();
+        }
+    }
+
+    /// Renders the labels of the single-line annotations that are placed
+    /// under the caret row, with the row of their vertical lines.
+    fn put_label_rows(&mut self, visual: Option<&VisualLine>, labels: &LineLabels) {
+        let annots = self.annots;
+        let with_verticals = labels.with_verticals();
+
+        if !with_verticals.is_empty() {
+            self.put_margin(None, false);
+            self.put_slots_simple();
+            self.put_sl_verticals(with_verticals, visual);
+            self.put_line_break();
+        }
+
+        let label_rows = annots.label_rows(with_verticals, visual, |annot_i| {
+            !labels.stacked_under(annot_i).is_empty()
+        });
+        // Annotations whose labels are below `row`
+        let pending_after = |row: usize| -> Vec<usize> {
+            with_verticals
+                .iter()
+                .zip(label_rows.iter())
+                .filter(|&(_, &label_row)| label_row > row)
+                .map(|(&annot_i, _)| annot_i)
+                .collect()
+        };
+        let num_label_rows = label_rows.iter().max().map_or(0, |&row| row + 1);
+        for row in 0..num_label_rows {
+            if row != 0 {
+                for _ in 0..self.style().label_row_spacing {
+                    self.put_margin(None, false);
+                    self.put_slots_simple();
+                    self.put_sl_verticals(&pending_after(row - 1), visual);
+                    self.put_line_break();
+                }
+            }
+            self.put_margin(None, false);
+            self.put_slots_simple();
+            let mut col_cursor = 0;
+            let mut wrapped = None;
+            for (k, (&annot_i, &label_row)) in
+                with_verticals.iter().zip(label_rows.iter()).enumerate()
+            {
+                if label_row < row {
+                    continue;
+                }
+                let start_col = annots.label_col(self.annot(annot_i), visual);
+                if label_row > row && start_col < col_cursor {
+                    continue;
+                }
+                if col_cursor < start_col {
+                    self.put_whitespace(" ".repeat(start_col - col_cursor));
+                    col_cursor = start_col;
+                }
+                if label_row > row {
+                    // The label is further down, only its vertical line
+                    // goes through this row.
+                    self.parts.push_annot(
+                        (
+                            self.style().vertical_char.into(),
+                            self.annot(annot_i).style.line_meta.clone(),
+                        ),
+                        annot_i,
+                        LayoutElementKind::Line,
+                    );
+                    col_cursor += 1;
+                } else {
+                    // Only the last element of a row can be wrapped
+                    let is_last = !label_rows[(k + 1)..].iter().any(|&other| other >= row);
+                    match is_last
+                        .then(|| self.label_block(labels, annot_i, start_col))
+                        .flatten()
+                    {
+                        Some(rows) => {
+                            let (row_annot_i, row, is_last) = &rows[0];
+                            self.put_label_row(*row_annot_i, row, *is_last);
+                            wrapped = Some((start_col, rows));
+                        }
+                        None => self.put_label(annot_i),
+                    }
+                    col_cursor += annots.label_width(&self.annot(annot_i).label);
+                }
+            }
+            self.put_line_break();
+
+            // Continuation rows of a wrapped label, crossed by the
+            // vertical lines of the labels further down
+            if let Some((start_col, rows)) = wrapped {
+                self.put_label_continuation(&rows, start_col, &pending_after(row), visual);
+            }
+        }
+    }
+
+    /// Renders the labels of annotations without carets, each on its own
+    /// row.
+    fn put_hidden_labels(&mut self, visual: Option<&VisualLine>, hidden_labeled: &[usize]) {
+        for &annot_i in hidden_labeled.iter() {
+            let annot = self.annot(annot_i);
+            if annot.label.iter().all(|(s, _)| s.is_empty()) {
+                continue;
+            }
+            self.put_margin(None, false);
+            self.put_slots_simple();
+            let start_col = self.annots.label_col(annot, visual);
+            if start_col != 0 {
+                self.put_whitespace(" ".repeat(start_col));
+            }
+            self.put_label(annot_i);
+            self.put_line_break();
+        }
+    }
+
+    /// Renders the note that counts the labels that were dropped from a
+    /// line.
+    fn put_unlabeled_note(&mut self, num_unlabeled: usize) {
+        self.put_margin(None, false);
+        self.put_slots_simple();
+        let note = unlabeled_note(self.style().more_annotations_marker, num_unlabeled);
+        self.parts.push(
+            (note, self.style().text_normal_meta.clone()),
+            ChunkKind::Note,
+        );
+        self.put_line_break();
+    }
+
+    /// Renders the end of the multi-line annotation `annot_i`, releasing its
+    /// slot.
+    fn put_ml_end(&mut self, annot_i: usize) {
+        let style = self.style();
+        let annot = self.annot(annot_i);
+
+        assert!(self.ml_slots[annot.ml_slot].is_some());
+        self.ml_slots[annot.ml_slot] = None;
+
+        self.put_margin(None, false);
+        self.put_slots_with_end(annot_i);
+
+        if annot.span.end_col != 0 {
+            self.parts.push_annot(
+                (
+                    core::iter::repeat(style.horizontal_char)
+                        .take(annot.span.end_col - 1)
+                        .collect(),
+                    annot.style.line_meta.clone(),
+                ),
+                annot_i,
+                LayoutElementKind::Line,
+            );
+        }
+        self.parts.push_annot(
+            (annot.style.caret.into(), annot.style.line_meta.clone()),
+            annot_i,
+            LayoutElementKind::Caret,
+        );
+
+        let label_width = self.annots.label_width(&annot.label);
+        let row_width = self.margin_width
+            + self.ml_slots.len()
+            + 1
+            + annot.span.end_col.max(1)
+            + 1
+            + label_width;
+        let move_label = label_width != 0
+            && style.end_label_overflow == EndLabelOverflow::NextRow
+            && self
+                .options
+                .render_width
+                .is_some_and(|render_width| row_width > render_width);
+
+        if !move_label {
+            self.put_whitespace(' '.into());
+            self.put_label(annot_i);
+            self.put_line_break();
+            return;
+        }
+
+        // Move the label to the next row, under the corner if
+        // there are no vertical lines at its right.
+        self.put_line_break();
+        self.put_margin(None, false);
+        if self.ml_slots[..annot.ml_slot].iter().all(Option::is_none) {
+            for slot_i in ((annot.ml_slot + 1)..self.ml_slots.len()).rev() {
+                self.put_slot(self.ml_slots[slot_i]);
+            }
+        } else {
+            self.put_slots_simple();
+        }
+        self.put_label(annot_i);
+        self.put_line_break();
+    }
+
+    /// Renders the start of the multi-line annotation `annot_i`, taking its
+    /// slot.
+    fn put_ml_start(&mut self, annot_i: usize) {
+        let annot = self.annot(annot_i);
+
+        self.put_margin(None, false);
+        self.put_slots_with_start(annot_i);
+
+        assert!(self.ml_slots[annot.ml_slot].is_none());
+        self.ml_slots[annot.ml_slot] = Some(annot_i);
+
+        self.parts.push_annot(
+            (
+                core::iter::repeat(self.style().horizontal_char)
+                    .take(annot.span.start_col)
+                    .collect(),
+                annot.style.line_meta.clone(),
+            ),
+            annot_i,
+            LayoutElementKind::Line,
+        );
+        self.parts.push_annot(
+            (annot.style.caret.into(), annot.style.line_meta.clone()),
+            annot_i,
+            LayoutElementKind::Caret,
+        );
+        self.put_line_break();
+    }
+
+    /// Renders the ends of column guides, from right to left, crossed by the
+    /// rules of the guides at their left.
+    fn put_guide_ends(&mut self, guides: &[&GuideData<M>]) {
+        let style = self.style();
+        for k in (0..guides.len()).rev() {
+            let guide = guides[k];
+            self.put_margin(None, false);
+            self.put_slots_simple();
+            let mut col_cursor = 0;
+            for other in guides[..k].iter() {
+                if other.col < col_cursor || other.col >= guide.col {
+                    continue;
+                }
+                self.put_whitespace(" ".repeat(other.col - col_cursor));
+                self.parts.push(
+                    (style.vertical_char.into(), other.style.line_meta.clone()),
+                    ChunkKind::Connector,
+                );
+                col_cursor = other.col + 1;
+            }
+            if guide.col > col_cursor {
+                self.put_whitespace(" ".repeat(guide.col - col_cursor));
+            }
+            self.parts.push(
+                (guide.style.caret.into(), guide.style.line_meta.clone()),
+                ChunkKind::Caret,
+            );
+            if guide.label.iter().any(|(s, _)| !s.is_empty()) {
+                self.put_whitespace(' '.into());
+                for (text, meta) in guide.label.iter() {
+                    self.parts.push(
+                        (sanitize_label(text, style.label_sanitization), meta.clone()),
+                        ChunkKind::Label,
+                    );
+                }
+            }
+            self.put_line_break();
+        }
+    }
+
+    /// Renders the label of an annotation
+    fn put_label(&mut self, annot_i: usize) {
+        let style = self.style();
+        for (text, meta) in self.annot(annot_i).label.iter() {
+            self.parts.push_annot(
+                (sanitize_label(text, style.label_sanitization), meta.clone()),
+                annot_i,
+                LayoutElementKind::Label,
+            );
+        }
+    }
+
+    /// Renders a row of a wrapped label, followed by the wrap marker unless
+    /// it is the last row
+    fn put_label_row(&mut self, annot_i: usize, row: &[(String, M)], is_last: bool) {
+        for (text, meta) in row.iter() {
+            self.parts.push_annot(
+                (text.clone(), meta.clone()),
+                annot_i,
+                LayoutElementKind::Label,
+            );
+        }
+        if let (false, Some(marker)) = (is_last, self.style().label_wrap_marker) {
+            if !marker.is_empty() {
+                self.parts.push_annot(
+                    (marker.into(), self.annot(annot_i).style.line_meta.clone()),
+                    annot_i,
+                    LayoutElementKind::Line,
+                );
+            }
+        }
+    }
+
+    /// Renders the vertical line of a multi-line slot, or a space if it is
+    /// free.
+    fn put_slot(&mut self, slot: Option<usize>) {
+        match slot {
+            Some(slot_annot_i) => self.parts.push_annot(
+                (
+                    self.style().vertical_char.into(),
+                    self.annot(slot_annot_i).style.line_meta.clone(),
+                ),
+                slot_annot_i,
+                LayoutElementKind::Line,
+            ),
+            None => self.put_whitespace(' '.into()),
+        }
+    }
+
+    /// Renders the slots of a row
+    /// example: ` ││ `
+    fn put_slots_simple(&mut self) {
+        for slot_i in (0..self.ml_slots.len()).rev() {
+            self.put_slot(self.ml_slots[slot_i]);
+        }
+        if !self.ml_slots.is_empty() {
+            self.put_whitespace(' '.into());
+        }
+    }
+
+    /// example: ` ╭│ `
+    fn put_slots_with_short_start(&mut self) {
+        for slot_i in (0..self.ml_slots.len()).rev() {
+            match self.ml_slots[slot_i] {
+                Some(slot_annot_i) if self.is_slot_start[slot_i] => {
+                    self.parts.push_annot(
+                        (
+                            self.style().top_vertical_char.into(),
+                            self.annot(slot_annot_i).style.line_meta.clone(),
+                        ),
+                        slot_annot_i,
+                        LayoutElementKind::Line,
+                    );
+                }
+                slot => self.put_slot(slot),
+            }
+        }
+        if !self.ml_slots.is_empty() {
+            self.put_whitespace(' '.into());
+        }
+    }
+
+    /// example: ` ╭|─`
+    fn put_slots_with_start(&mut self, start_annot_i: usize) {
+        let top_corner_char = self.style().top_corner_char;
+        self.put_slots_with_corner(start_annot_i, top_corner_char);
+    }
+
+    /// example: ` ╰│─`
+    fn put_slots_with_end(&mut self, end_annot_i: usize) {
+        let bottom_corner_char = self.style().bottom_corner_char;
+        self.put_slots_with_corner(end_annot_i, bottom_corner_char);
+    }
+
+    /// Renders the slots of a row with a corner at the slot of `annot_i`,
+    /// followed by a horizontal line to the source text.
+    fn put_slots_with_corner(&mut self, annot_i: usize, corner_char: char) {
+        let style = self.style();
+        let corner_slot = self.annot(annot_i).ml_slot;
+        let corner_meta = &self.annot(annot_i).style.line_meta;
+        for slot_i in (0..self.ml_slots.len()).rev() {
+            let slot = self.ml_slots[slot_i];
+            if slot.is_some() {
+                self.put_slot(slot);
+            } else if slot_i == corner_slot {
+                self.parts.push_annot(
+                    (corner_char.into(), corner_meta.clone()),
+                    annot_i,
+                    LayoutElementKind::Line,
+                );
+            } else if slot_i < corner_slot {
+                self.parts.push_annot(
+                    (style.horizontal_char.into(), corner_meta.clone()),
+                    annot_i,
+                    LayoutElementKind::Line,
+                );
+            } else {
+                self.put_whitespace(' '.into());
+            }
+        }
+        self.parts.push_annot(
+            (style.horizontal_char.into(), corner_meta.clone()),
+            annot_i,
+            LayoutElementKind::Line,
+        );
+    }
+
+    /// Renders the vertical lines of the labels of `sl_annots`, returning
+    /// the column after the last one.
+    /// example: ` │ │`
+    fn put_sl_verticals(&mut self, sl_annots: &[usize], visual: Option<&VisualLine>) -> usize {
+        let mut col_cursor = 0;
+        for &prev_annot_i in sl_annots.iter() {
+            let start_col = self.annots.label_col(self.annot(prev_annot_i), visual);
+            if start_col < col_cursor {
+                continue;
+            }
+            if start_col - col_cursor >= 1 {
+                self.put_whitespace(" ".repeat(start_col - col_cursor));
+            }
+            self.parts.push_annot(
+                (
+                    self.style().vertical_char.into(),
+                    self.annot(prev_annot_i).style.line_meta.clone(),
+                ),
+                prev_annot_i,
+                LayoutElementKind::Line,
+            );
+            col_cursor = start_col + 1;
+        }
+        col_cursor
+    }
+}
@@ -0,0 +1,165 @@
+use alloc::{vec, vec::Vec};
+
+/// A line of text reordered for display, where runs of right-to-left
+/// characters are reversed.
+///
+/// This is a simplification of the Unicode Bidirectional Algorithm that
+/// assumes a left-to-right paragraph: a right-to-left run spans from a
+/// strong right-to-left character to the last one before a strong
+/// left-to-right character, and numbers inside it keep their order.
+pub(crate) struct VisualLine {
+    /// UTF-8 ranges of the characters, in visual order.
+    pub(crate) chars: Vec<core::ops::Range<usize>>,
+    /// Visual column of each logical column.
+    visual_cols: Vec<usize>,
+    /// Logical column of each visual column.
+    logical_cols: Vec<usize>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum CharClass {
+    Rtl,
+    Ltr,
+    Number,
+    Neutral,
+}
+
+impl VisualLine {
+    /// Reorders `text`, whose display width is `width`.
+    ///
+    /// Returns `None` if the text does not need to be reordered or its
+    /// characters do not add up to `width` (e.g., for custom snippets).
+    pub(crate) fn new(text: &str, width: usize) -> Option<Self> {
+        let chars: Vec<_> = text
+            .char_indices()
+            .map(|(i, chr)| {
+                let chr_width = crate::width::char_width(chr).unwrap_or(0);
+                (i..(i + chr.len_utf8()), chr_width, char_class(chr))
+            })
+            .collect();
+        if chars.iter().all(|&(_, _, class)| class != CharClass::Rtl)
+            || chars.iter().map(|&(_, w, _)| w).sum::<usize>() != width
+        {
+            return None;
+        }
+
+        let mut order: Vec<usize> = (0..chars.len()).collect();
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i].2 != CharClass::Rtl {
+                i += 1;
+                continue;
+            }
+            let mut run_end = i + 1;
+            let mut j = i + 1;
+            while j < chars.len() && chars[j].2 != CharClass::Ltr {
+                if chars[j].2 == CharClass::Rtl {
+                    run_end = j + 1;
+                }
+                j += 1;
+            }
+
+            let run = &mut order[i..run_end];
+            run.reverse();
+            // Numbers keep their order
+            let mut k = 0;
+            while k < run.len() {
+                let num_len = run[k..]
+                    .iter()
+                    .take_while(|&&char_i| chars[char_i].2 == CharClass::Number)
+                    .count();
+                run[k..(k + num_len)].reverse();
+                k += num_len.max(1);
+            }
+            i = run_end;
+        }
+
+        let mut logical_starts = Vec::with_capacity(chars.len());
+        let mut col = 0;
+        for &(_, chr_width, _) in chars.iter() {
+            logical_starts.push(col);
+            col += chr_width;
+        }
+
+        let mut visual_cols = vec![0; width];
+        let mut logical_cols = vec![0; width];
+        let mut visual_col = 0;
+        for &char_i in order.iter() {
+            let chr_width = chars[char_i].1;
+            for k in 0..chr_width {
+                visual_cols[logical_starts[char_i] + k] = visual_col + k;
+                logical_cols[visual_col + k] = logical_starts[char_i] + k;
+            }
+            visual_col += chr_width;
+        }
+
+        Some(Self {
+            chars: order
+                .iter()
+                .map(|&char_i| chars[char_i].0.clone())
+                .collect(),
+            visual_cols,
+            logical_cols,
+        })
+    }
+
+    /// Returns the visual column of the logical column `col`.
+    #[inline]
+    pub(crate) fn visual_col(&self, col: usize) -> usize {
+        self.visual_cols.get(col).copied().unwrap_or(col)
+    }
+
+    /// Returns the logical column of the visual column `col`.
+    #[inline]
+    pub(crate) fn logical_col(&self, col: usize) -> usize {
+        self.logical_cols.get(col).copied().unwrap_or(col)
+    }
+}
+
+fn char_class(chr: char) -> CharClass {
+    match u32::from(chr) {
+        0x0590..=0x08FF
+        | 0xFB1D..=0xFDFF
+        | 0xFE70..=0xFEFC
+        | 0x10800..=0x10FFF
+        | 0x1E800..=0x1EFFF => CharClass::Rtl,
+        _ if chr.is_ascii_digit() => CharClass::Number,
+        _ if chr.is_alphabetic() => CharClass::Ltr,
+        _ => CharClass::Neutral,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::VisualLine;
+
+    fn reorder(text: &str) -> String {
+        let line = VisualLine::new(text, text.chars().count()).unwrap();
+        line.chars
+            .iter()
+            .map(|range| &text[range.clone()])
+            .collect()
+    }
+
+    #[test]
+    fn test_reorder() {
+        assert!(VisualLine::new("abc 123", 7).is_none());
+        // A byte order mark is not right-to-left
+        assert!(VisualLine::new("\u{FEFF}abc", 3).is_none());
+        assert_eq!(reorder("a אבג d"), "a גבא d");
+        assert_eq!(reorder("x = \"אב 12 ג\";"), "x = \"ג 12 בא\";");
+        assert_eq!(reorder("א b ג"), "א b ג");
+    }
+
+    #[test]
+    fn test_cols() {
+        let line = VisualLine::new("a אבג", 5).unwrap();
+        assert_eq!(line.visual_col(0), 0);
+        assert_eq!(line.visual_col(2), 4);
+        assert_eq!(line.visual_col(4), 2);
+        assert_eq!(line.logical_col(4), 2);
+        assert_eq!(line.visual_col(7), 7);
+    }
+}
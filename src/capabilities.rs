@@ -0,0 +1,221 @@
+use crate::{AnnotStyle, Error, MainStyle, SeverityStyles};
+
+/// Capabilities of the output where snippets are shown (e.g., a terminal),
+/// used to check styles and to replace the characters that the output
+/// cannot show.
+///
+/// Metadata (e.g., colors) is never checked nor changed, since its meaning
+/// is up to the application, which can ignore it when `NO_COLOR` is set.
+///
+/// # Example
+///
+/// ```
+/// use sourceannot::{AnnotStyle, Capabilities};
+///
+/// let term = "dumb"; // e.g., from the `TERM` environment variable
+/// let caps = if term == "dumb" {
+///     Capabilities::ASCII
+/// } else {
+///     Capabilities::FULL
+/// };
+///
+/// let style = AnnotStyle {
+///     caret: '▲',
+///     text_normal_meta: (),
+///     text_alt_meta: (),
+///     line_meta: (),
+///     hide_carets: false,
+/// };
+/// assert!(style.validate(caps).is_err());
+/// assert_eq!(style.downgrade(caps).caret, '^');
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether the output can only show ASCII characters.
+    pub ascii_only: bool,
+
+    /// Whether the output can show wide characters (i.e., characters that
+    /// take two columns).
+    ///
+    /// Wide characters are only detected with the `unicode-width` feature.
+    pub wide_chars: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of an output that can show any character.
+    pub const FULL: Self = Self {
+        ascii_only: false,
+        wide_chars: true,
+    };
+
+    /// Capabilities of an output that can only show ASCII characters (e.g.,
+    /// with `TERM=dumb`).
+    pub const ASCII: Self = Self {
+        ascii_only: true,
+        wide_chars: false,
+    };
+
+    /// Returns whether `chr` can be used in styles shown on the output.
+    ///
+    /// Control and zero-width characters are never supported, since they
+    /// would break the alignment of the rendered snippet.
+    pub fn supports_char(&self, chr: char) -> bool {
+        if self.ascii_only && !chr.is_ascii() {
+            return false;
+        }
+        match crate::width::char_width(chr) {
+            Some(1) => true,
+            Some(2) => self.wide_chars,
+            _ => false,
+        }
+    }
+
+    /// Returns the first character of `s` that is not
+    /// [supported](Self::supports_char), if any.
+    fn first_unsupported(&self, s: &str) -> Option<char> {
+        s.chars().find(|&chr| !self.supports_char(chr))
+    }
+}
+
+impl<M> MainStyle<M> {
+    /// Checks that all the characters of the style can be shown on an
+    /// output with `caps`.
+    ///
+    /// Returns [`Error::UnsupportedStyleChar`] with the first character
+    /// that cannot be shown.
+    pub fn validate(&self, caps: Capabilities) -> Result<(), Error> {
+        let mut chars = [
+            self.horizontal_char,
+            self.vertical_char,
+            self.top_vertical_char,
+            self.top_corner_char,
+            self.bottom_corner_char,
+        ]
+        .into_iter()
+        .chain(
+            self.margin
+                .iter()
+                .flat_map(|margin| [margin.line_char, margin.dot_char]),
+        );
+        if let Some(chr) = chars.find(|&chr| !caps.supports_char(chr)) {
+            return Err(Error::UnsupportedStyleChar(chr));
+        }
+        for s in [
+            self.eof_placeholder,
+            self.label_wrap_marker,
+            self.label_delimiter,
+            Some(self.more_annotations_marker),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Some(chr) = caps.first_unsupported(s) {
+                return Err(Error::UnsupportedStyleChar(chr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the characters of the style that cannot be shown on an
+    /// output with `caps`, so the style passes
+    /// [`validate`](Self::validate).
+    ///
+    /// Line and margin characters are replaced with the ASCII fallbacks of
+    /// [`with_console_chars`](Self::with_console_chars). Strings are
+    /// replaced as a whole: the EOF placeholder with `<EOF>`, the wrap
+    /// marker with `\`, the label delimiter with `; ` and the more
+    /// annotations marker with `...`.
+    pub fn downgrade(mut self, caps: Capabilities) -> Self {
+        let fix_str = |s: Option<&'static str>, fallback: &'static str| {
+            s.map(|s| match caps.first_unsupported(s) {
+                Some(_) => fallback,
+                None => s,
+            })
+        };
+
+        self.replace_console_chars(|chr, fallback| {
+            if caps.supports_char(chr) {
+                chr
+            } else {
+                fallback
+            }
+        });
+        self.eof_placeholder = fix_str(self.eof_placeholder, "<EOF>");
+        self.label_wrap_marker = fix_str(self.label_wrap_marker, "\\");
+        self.label_delimiter = fix_str(self.label_delimiter, "; ");
+        self.more_annotations_marker = fix_str(Some(self.more_annotations_marker), "...").unwrap();
+        self
+    }
+}
+
+impl<M> AnnotStyle<M> {
+    /// Checks that the caret of the style can be shown on an output with
+    /// `caps`.
+    ///
+    /// Returns [`Error::UnsupportedStyleChar`] if it cannot be shown.
+    pub fn validate(&self, caps: Capabilities) -> Result<(), Error> {
+        if caps.supports_char(self.caret) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedStyleChar(self.caret))
+        }
+    }
+
+    /// Replaces the caret of the style with `^` if it cannot be shown on an
+    /// output with `caps`.
+    pub fn downgrade(mut self, caps: Capabilities) -> Self {
+        if !caps.supports_char(self.caret) {
+            self.caret = '^';
+        }
+        self
+    }
+}
+
+impl<M> SeverityStyles<M> {
+    /// Checks the style of each severity, see [`AnnotStyle::validate`].
+    pub fn validate(&self, caps: Capabilities) -> Result<(), Error> {
+        self.error.validate(caps)?;
+        self.warning.validate(caps)?;
+        self.note.validate(caps)
+    }
+
+    /// Downgrades the style of each severity, see
+    /// [`AnnotStyle::downgrade`].
+    pub fn downgrade(self, caps: Capabilities) -> Self {
+        Self {
+            error: self.error.downgrade(caps),
+            warning: self.warning.downgrade(caps),
+            note: self.note.downgrade(caps),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Capabilities;
+
+    #[test]
+    fn test_supports_char() {
+        for caps in [Capabilities::FULL, Capabilities::ASCII] {
+            assert!(caps.supports_char('^'));
+            assert!(!caps.supports_char('\t'));
+            assert!(!caps.supports_char('\u{1B}'));
+        }
+        assert!(Capabilities::FULL.supports_char('│'));
+        assert!(!Capabilities::ASCII.supports_char('│'));
+    }
+
+    #[cfg(feature = "unicode-width")]
+    #[test]
+    fn test_supports_wide_char() {
+        let caps = Capabilities {
+            ascii_only: false,
+            wide_chars: false,
+        };
+        assert!(caps.supports_char('│'));
+        assert!(!caps.supports_char('\u{FF3E}'));
+        assert!(Capabilities::FULL.supports_char('\u{FF3E}'));
+        // Combining characters do not take a column
+        assert!(!Capabilities::FULL.supports_char('\u{301}'));
+    }
+}
@@ -0,0 +1,50 @@
+/// Errors reported by the fallible functions of this crate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// An element of a snippet spans zero source units.
+    ZeroLengthUnit,
+
+    /// The text of an element of a snippet contains a control character.
+    ControlCharInText(char),
+
+    /// The number of source units of a snippet does not match the expected
+    /// one.
+    UnitCountMismatch { expected: usize, actual: usize },
+
+    /// A window of lines does not contain any line of the snippet.
+    EmptyLineWindow,
+
+    /// A snippet snapshot is malformed.
+    InvalidSnapshot,
+
+    /// A snippet snapshot was created with an unsupported version of the
+    /// format.
+    UnsupportedSnapshotVersion(u8),
+
+    /// A style contains a character that cannot be shown with the given
+    /// [`Capabilities`](crate::Capabilities).
+    UnsupportedStyleChar(char),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::ZeroLengthUnit => f.write_str("snippet element spans zero units"),
+            Self::ControlCharInText(chr) => {
+                write!(f, "snippet element text contains control character {chr:?}")
+            }
+            Self::UnitCountMismatch { expected, actual } => {
+                write!(f, "snippet spans {actual} units, expected {expected}")
+            }
+            Self::EmptyLineWindow => f.write_str("window of lines does not contain any line"),
+            Self::InvalidSnapshot => f.write_str("malformed snippet snapshot"),
+            Self::UnsupportedSnapshotVersion(version) => {
+                write!(f, "unsupported snippet snapshot version {version}")
+            }
+            Self::UnsupportedStyleChar(chr) => {
+                write!(f, "style contains unsupported character {chr:?}")
+            }
+        }
+    }
+}
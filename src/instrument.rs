@@ -0,0 +1,133 @@
+//! Hooks that report the phases of snippet building, annotation and
+//! rendering.
+//!
+//! This module is only available with the `instrumentation` feature.
+//!
+//! The crate does not measure time or count allocations, since it is
+//! `#![no_std]`. Hooks only mark where each phase begins and ends and
+//! report the amount of work done in [`PhaseStats`]. Integrators that need
+//! timings or allocation counts can take them in their [`Hooks`] (e.g.,
+//! from a clock or a counting global allocator).
+//!
+//! # Example
+//!
+//! ```
+//! use std::cell::RefCell;
+//!
+//! use sourceannot::instrument::{Hooks, Phase, PhaseStats};
+//!
+//! #[derive(Default)]
+//! struct Recorder {
+//!     events: RefCell<Vec<(Phase, usize)>>,
+//! }
+//!
+//! impl Hooks for Recorder {
+//!     fn phase_end(&self, phase: Phase, stats: &PhaseStats) {
+//!         self.events.borrow_mut().push((phase, stats.items));
+//!     }
+//! }
+//!
+//! let recorder = Recorder::default();
+//! let snippet = sourceannot::instrument::build(&recorder, || {
+//!     sourceannot::SourceSnippet::build_from_utf8(1, b"let x = 1;\n", 4)
+//! });
+//! # let main_style = sourceannot::MainStyle::new(());
+//! # let annot_style = sourceannot::AnnotStyle {
+//! #     caret: '^',
+//! #     text_normal_meta: (),
+//! #     text_alt_meta: (),
+//! #     line_meta: (),
+//! #     hide_carets: false,
+//! # };
+//!
+//! let mut annots = sourceannot::Annotations::new(&snippet, main_style);
+//! annots.set_hooks(&recorder);
+//! annots.add_annotation(4..5, annot_style, vec![("a variable".into(), ())]);
+//! annots.add_hidden_annotation(0..3, annot_style);
+//! let rendered = annots.render(1, 0, 0);
+//!
+//! assert_eq!(
+//!     *recorder.events.borrow(),
+//!     [
+//!         (Phase::Build, 11),
+//!         (Phase::Layout, 1),
+//!         (Phase::Layout, 1),
+//!         (Phase::Render, rendered.len()),
+//!     ],
+//! );
+//! ```
+
+use crate::SourceSnippet;
+
+/// A phase of the work of the crate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Building a snippet. Snippets are built by functions that do not
+    /// take hooks, so this phase is only reported by [`build`].
+    Build,
+    /// Adding an annotation (including hidden annotations) or a column guide
+    /// to an [`Annotations`](crate::Annotations) collection, which lays it
+    /// out (e.g., assigns it a multi-line slot). It is reported once for
+    /// each call that adds one.
+    Layout,
+    /// Rendering an [`Annotations`](crate::Annotations) collection.
+    Render,
+}
+
+/// Statistics of a phase, reported when it ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PhaseStats {
+    /// Number of items processed or produced by the phase: source units for
+    /// [`Phase::Build`], annotations or column guides added (0 or 1) for
+    /// [`Phase::Layout`]
+    /// and rendered chunks for [`Phase::Render`].
+    pub items: usize,
+}
+
+/// Receives the beginning and the end of each phase.
+///
+/// Both methods have no-op default implementations. They take `&self`, so
+/// implementations that record data need interior mutability.
+pub trait Hooks {
+    /// Called when `phase` begins.
+    #[inline]
+    fn phase_begin(&self, phase: Phase) {
+        let _ = phase;
+    }
+
+    /// Called when `phase` ends.
+    #[inline]
+    fn phase_end(&self, phase: Phase, stats: &PhaseStats) {
+        let _ = (phase, stats);
+    }
+}
+
+/// Runs `build`, which builds a snippet (e.g., with
+/// [`SourceSnippet::build_from_utf8`]), between the beginning and the end of
+/// a [`Phase::Build`] reported to `hooks`.
+pub fn build<F>(hooks: &dyn Hooks, build: F) -> SourceSnippet
+where
+    F: FnOnce() -> SourceSnippet,
+{
+    hooks.phase_begin(Phase::Build);
+    let snippet = build();
+    hooks.phase_end(
+        Phase::Build,
+        &PhaseStats {
+            items: snippet.num_units(),
+        },
+    );
+    snippet
+}
+
+/// A reference to hooks, so they can be stored in structs that derive
+/// `Debug`.
+#[derive(Copy, Clone)]
+pub(crate) struct HooksRef<'a>(pub(crate) &'a dyn Hooks);
+
+impl core::fmt::Debug for HooksRef<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("HooksRef(..)")
+    }
+}
@@ -0,0 +1,103 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Truncates a label (a sequence of text fragments with their metadata) so
+/// its display width is at most `max_width`, ending it with `ellipsis` if
+/// anything was removed.
+///
+/// Characters are never split, and wide characters that do not fit are
+/// removed as a whole, so the result can be narrower than `max_width`. The
+/// ellipsis, which counts towards `max_width`, takes the metadata of the
+/// fragment in which the label was cut, and it is omitted if it does not
+/// fit by itself. Control characters count as zero-width.
+///
+/// # Example
+///
+/// ```
+/// let label = vec![("expected ".into(), 1), ("`usize`".into(), 2)];
+///
+/// assert_eq!(sourceannot::truncate_label(&label, 20, "…"), label);
+/// assert_eq!(
+///     sourceannot::truncate_label(&label, 14, "…"),
+///     [("expected ".into(), 1), ("`usi…".into(), 2)],
+/// );
+/// ```
+pub fn truncate_label<M: Clone>(
+    label: &[(String, M)],
+    max_width: usize,
+    ellipsis: &str,
+) -> Vec<(String, M)> {
+    let char_width = |chr: char| crate::width::char_width(chr).unwrap_or(0);
+    let total_width: usize = label
+        .iter()
+        .flat_map(|(text, _)| text.chars())
+        .map(char_width)
+        .sum();
+    if total_width <= max_width {
+        return label.to_vec();
+    }
+
+    let ellipsis_width: usize = ellipsis.chars().map(char_width).sum();
+    let (ellipsis, budget) = match max_width.checked_sub(ellipsis_width) {
+        Some(budget) => (ellipsis, budget),
+        None => ("", max_width),
+    };
+
+    let mut result = Vec::new();
+    let mut width = 0;
+    for (text, meta) in label.iter() {
+        let mut fragment = String::new();
+        for chr in text.chars() {
+            let chr_width = char_width(chr);
+            if width + chr_width > budget {
+                fragment.push_str(ellipsis);
+                if !fragment.is_empty() {
+                    result.push((fragment, meta.clone()));
+                }
+                return result;
+            }
+            fragment.push(chr);
+            width += chr_width;
+        }
+        if !fragment.is_empty() {
+            result.push((fragment, meta.clone()));
+        }
+    }
+    // Not reached, since the label is wider than `budget`
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use super::truncate_label;
+
+    fn label(fragments: &[(&str, u8)]) -> Vec<(String, u8)> {
+        fragments
+            .iter()
+            .map(|&(text, meta)| (text.into(), meta))
+            .collect()
+    }
+
+    #[test]
+    fn test_truncate_label() {
+        let l = label(&[("abc", 1), ("def", 2)]);
+        assert_eq!(truncate_label(&l, 6, "..."), l);
+        assert_eq!(truncate_label(&l, 5, "..."), label(&[("ab...", 1)]));
+        assert_eq!(truncate_label(&l, 5, "…"), label(&[("abc", 1), ("d…", 2)]),);
+        assert_eq!(truncate_label(&l, 4, "…"), label(&[("abc", 1), ("…", 2)]));
+        assert_eq!(truncate_label(&l, 2, "..."), label(&[("ab", 1)]));
+        assert_eq!(truncate_label(&l, 0, "…"), label(&[]));
+    }
+
+    #[cfg(feature = "unicode-width")]
+    #[test]
+    fn test_truncate_label_wide() {
+        let l = label(&[("日本語", 1)]);
+        assert_eq!(truncate_label(&l, 5, "…"), label(&[("日本…", 1)]));
+        assert_eq!(truncate_label(&l, 4, "…"), label(&[("日…", 1)]));
+        assert_eq!(truncate_label(&l, 3, ""), label(&[("日", 1)]));
+    }
+}
@@ -0,0 +1,139 @@
+use alloc::vec::Vec;
+
+use crate::AnnotationId;
+
+/// The layout of a rendered snippet, as returned by
+/// [`Annotations::render_with_layout`](crate::Annotations::render_with_layout).
+///
+/// It describes which rows of the output show source lines and where the
+/// elements of each annotation were drawn, so the rendered text can be
+/// post-processed or overlaid reliably.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderLayout {
+    /// The rows of the output, in order.
+    pub rows: Vec<LayoutRow>,
+}
+
+/// A row of a rendered snippet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LayoutRow {
+    /// Index (within the snippet) of the source line shown in the row, if
+    /// any.
+    pub source_line: Option<usize>,
+
+    /// Elements of annotations drawn in the row, from left to right.
+    pub elements: Vec<LayoutElement>,
+
+    /// What the row shows.
+    pub kind: RowKind,
+}
+
+/// Kind of a rendered row, see [`LayoutRow`] and
+/// [`Output::begin_row`](crate::Output::begin_row).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RowKind {
+    /// An annotated source line.
+    Source,
+    /// A source line without annotations, shown between or around
+    /// annotated lines.
+    Fill,
+    /// The row that replaces folded source lines.
+    Fold,
+    /// A row with carets, lines or labels of annotations.
+    #[default]
+    Annotation,
+    /// A blank row before or after the snippet.
+    Padding,
+}
+
+/// A contiguous element of an annotation within a rendered row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayoutElement {
+    /// The annotation the element belongs to.
+    pub annotation: AnnotationId,
+
+    /// What the element is.
+    pub kind: LayoutElementKind,
+
+    /// Range of display columns occupied by the element, including the
+    /// margin.
+    pub cols: core::ops::Range<usize>,
+}
+
+/// Kind of a [`LayoutElement`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LayoutElementKind {
+    /// Carets that point to the annotated text.
+    Caret,
+    /// Lines that connect carets with labels or draw multi-line annotations.
+    Line,
+    /// The label of the annotation.
+    Label,
+}
+
+/// Semantic role of a chunk of rendered text, as returned by
+/// [`Annotations::render_with_kinds`](crate::Annotations::render_with_kinds).
+///
+/// It allows styling the output structurally (e.g., with HTML classes)
+/// without encoding the role of each chunk in its metadata.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChunkKind {
+    /// The left margin, except line numbers.
+    Margin,
+    /// A line number in the left margin.
+    LineNumber,
+    /// The text of an annotated source line.
+    SourceText,
+    /// The text of a source line without annotations, shown between or
+    /// around annotated lines.
+    Fill,
+    /// Carets that point to the annotated text.
+    Caret,
+    /// Lines that connect carets with labels or draw multi-line annotations.
+    Connector,
+    /// The label of an annotation.
+    Label,
+    /// A note about annotations whose labels were not rendered.
+    Note,
+    /// Spaces between other chunks and line breaks.
+    Whitespace,
+}
+
+impl RenderLayout {
+    /// Returns the annotation drawn at display column `col` of row `row`,
+    /// both zero-based, if any.
+    ///
+    /// Carets, the lines that connect them to labels and labels belong to
+    /// their annotation, while the margin and the source text do not belong
+    /// to any.
+    pub fn hit_test(&self, row: usize, col: usize) -> Option<AnnotationId> {
+        self.rows
+            .get(row)?
+            .elements
+            .iter()
+            .find(|element| element.cols.contains(&col))
+            .map(|element| element.annotation)
+    }
+
+    /// Adds an element to the last row, merging it with the previous
+    /// element if they are adjacent and equivalent.
+    pub(crate) fn push_element(
+        &mut self,
+        annotation: AnnotationId,
+        kind: LayoutElementKind,
+        cols: core::ops::Range<usize>,
+    ) {
+        let row = self.rows.last_mut().unwrap();
+        if let Some(last) = row.elements.last_mut() {
+            if last.annotation == annotation && last.kind == kind && last.cols.end == cols.start {
+                last.cols.end = cols.end;
+                return;
+            }
+        }
+        row.elements.push(LayoutElement {
+            annotation,
+            kind,
+            cols,
+        });
+    }
+}
@@ -13,6 +13,27 @@
 //!
 //! This crate is `#![no_std]`, but it depends on `alloc`.
 //!
+//! # Features
+//!
+//! * `unicode-width` (enabled by default): uses the [`unicode-width`] crate to
+//!   compute the display width of characters. When disabled, every
+//!   non-control character is assumed to have a width of 1, which is only
+//!   accurate for sources without wide or zero-width characters (e.g., ASCII).
+//! * `instrumentation`: enables the `instrument` module, with hooks that
+//!   report the phases of snippet building, annotation and rendering.
+//! * `testing`: enables the `testing` module, with helpers to test
+//!   rendered snippets.
+//!
+//! [`unicode-width`]: https://crates.io/crates/unicode-width
+//!
+//! # Determinism
+//!
+//! Rendering is deterministic: the same snippet, styles and sequence of
+//! annotations always produce the same output, regardless of the platform
+//! or the Rust version. No hash-based collections are involved, and
+//! annotations that would otherwise be ambiguous (e.g., labels that start at
+//! the same column) are ordered by the order in which they were added.
+//!
 //! # Example
 //!
 //! ```
@@ -38,21 +59,15 @@
 //! // If do not you need this per-chunk metadata, you can use `()` instead.
 //!
 //! // Define the styles
-//! // Use Unicode box drawing characters
+//! // Start from the default style, with Unicode box drawing characters,
+//! // and draw the margin in blue
 //! let main_style = sourceannot::MainStyle {
 //!     margin: Some(sourceannot::MarginStyle {
 //!         line_char: '│',
 //!         dot_char: '·',
 //!         meta: Color::Blue,
 //!     }),
-//!     horizontal_char: '─',
-//!     vertical_char: '│',
-//!     top_vertical_char: '╭',
-//!     top_corner_char: '╭',
-//!     bottom_corner_char: '╰',
-//!     spaces_meta: Color::Default,
-//!     text_normal_meta: Color::Default,
-//!     text_alt_meta: Color::Default,
+//!     ..sourceannot::MainStyle::new(Color::Default)
 //! };
 //!
 //! // You can use a different style for each annotation, but in
@@ -62,6 +77,7 @@
 //!     text_normal_meta: Color::Red,
 //!     text_alt_meta: Color::Red,
 //!     line_meta: Color::Red,
+//!     hide_carets: false,
 //! };
 //!
 //! // Create the annotations
@@ -116,13 +132,48 @@
 extern crate alloc;
 
 mod annots;
+mod bidi;
+mod capabilities;
+mod error;
+#[cfg(feature = "instrumentation")]
+pub mod instrument;
+mod label;
+mod layout;
+mod output;
 mod range_set;
 mod snippet;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod width;
 
-pub use annots::Annotations;
-pub use snippet::SourceSnippet;
+pub use annots::{AnnotationId, Annotations, RenderOptions};
+pub use capabilities::Capabilities;
+pub use error::Error;
+pub use label::truncate_label;
+pub use layout::{ChunkKind, LayoutElement, LayoutElementKind, LayoutRow, RenderLayout, RowKind};
+pub use output::Output;
+pub use range_set::RangeSet;
+pub use snippet::{
+    Decoder, IndentSettings, Latin1Options, NulHandling, ReplacementMap, SourceSnippet,
+    SourceSnippetBuilder, SpaceLikeChars, SpanNormalization, UnitKind, Utf8Options,
+};
 
 /// The general style of an annotated snippet.
+///
+/// [`MainStyle::new`] (or [`MainStyle::default`]) returns a style with
+/// Unicode box drawing characters and the default options, so only the
+/// fields that differ need to be set:
+///
+/// ```
+/// let main_style = sourceannot::MainStyle {
+///     label_delimiter: Some("; "),
+///     ..sourceannot::MainStyle::new(())
+/// };
+/// ```
+///
+/// Options that do not change how the snippet is drawn, such as the
+/// context lines or the render width, are set when rendering (see
+/// [`RenderOptions`]).
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MainStyle<M> {
     /// The style of the margin.
@@ -154,6 +205,310 @@ pub struct MainStyle<M> {
 
     /// Metadata that accompanies unannotated alternative text.
     pub text_alt_meta: M,
+
+    /// Where the end of an annotation is placed when its span ends on
+    /// a line break.
+    pub line_break_end: LineBreakEnd,
+
+    /// Where the start of an annotation is placed when its span starts at
+    /// a tab.
+    pub tab_anchor: TabAnchor,
+
+    /// How carets are drawn under wide characters.
+    pub wide_char_carets: WideCharCarets,
+
+    /// Text rendered, with [`text_alt_meta`](Self::text_alt_meta), in place
+    /// of the last line of the snippet when it is annotated and empty.
+    ///
+    /// This happens when the snippet is empty or ends with a line break, and
+    /// an annotation points to its end (or beyond it). A placeholder such as
+    /// `<empty file>` or `<EOF>` makes such annotations easier to read.
+    ///
+    /// If the last line is not empty, the placeholder is rendered after its
+    /// text when an annotation points to the end of the snippet (see
+    /// [`Annotations::add_eof_annotation`]).
+    ///
+    /// If `None`, the empty line is rendered as is.
+    pub eof_placeholder: Option<&'static str>,
+
+    /// How control characters (including line breaks and ANSI escape
+    /// sequences) in labels are handled.
+    pub label_sanitization: LabelSanitization,
+
+    /// Number of carets drawn for single-line annotations with zero-length
+    /// spans (i.e., insertion points).
+    ///
+    /// Values lower than 1 are treated as 1.
+    pub empty_span_carets: usize,
+
+    /// Where the label of a multi-line annotation is placed when rendering it
+    /// after the end caret would exceed
+    /// [`RenderOptions::render_width`].
+    pub end_label_overflow: EndLabelOverflow,
+
+    /// Marker placed at the end of each row of a wrapped label.
+    ///
+    /// If `Some`, labels of single-line annotations that would exceed
+    /// [`RenderOptions::render_width`] are wrapped (preferably at spaces)
+    /// and each continuation row is indented under the start of the label.
+    /// If `None` (or `render_width` is `None`), labels are not wrapped.
+    pub label_wrap_marker: Option<&'static str>,
+
+    /// How the start of multi-line annotations is drawn.
+    pub multi_line_starts: MultiLineStarts,
+
+    /// Which annotation provides the metadata of text (and carets) covered
+    /// by more than one annotation.
+    pub style_precedence: StylePrecedence,
+
+    /// Column under which the vertical line and the label of single-line
+    /// annotations are placed.
+    pub label_anchor: LabelAnchor,
+
+    /// Whether the label of the last single-line annotation of a line is
+    /// placed to the left of its carets (`label ^^^^`) instead of after
+    /// them, when there is room for it (and a space) before the carets.
+    ///
+    /// This keeps short labels near the code for spans at the end of long
+    /// lines. Labels that do not fit are placed after the carets.
+    pub left_labels: bool,
+
+    /// Number of blank rows (with the vertical lines of pending labels)
+    /// between stacked labels of single-line annotations.
+    pub label_row_spacing: usize,
+
+    /// Whether to place the labels of single-line annotations on shared
+    /// rows when they fit before the vertical lines of the labels to their
+    /// right.
+    ///
+    /// When `false`, each label that needs a vertical line gets a row of
+    /// its own.
+    pub compact_labels: bool,
+
+    /// Delimiter used to merge the labels of the single-line annotations of
+    /// a line onto the row of their carets.
+    ///
+    /// If `Some` (e.g., `"; "`), the labels of a line with several
+    /// non-overlapping single-line annotations are rendered after the
+    /// carets, from left to right and separated by the delimiter, instead
+    /// of being stacked under their carets. If `None`, labels are never
+    /// merged.
+    pub label_delimiter: Option<&'static str>,
+
+    /// Marker placed before the note that counts the labels dropped from a
+    /// line (e.g., `… and 2 more annotations`), see
+    /// [`RenderOptions::max_labels_per_line`] and
+    /// [`RenderOptions::secondary_label_threshold`].
+    pub more_annotations_marker: &'static str,
+}
+
+impl<M> MainStyle<M> {
+    /// Returns a style with Unicode box drawing characters, where every
+    /// chunk is accompanied by `meta`.
+    ///
+    /// Optional features, such as
+    /// [`eof_placeholder`](Self::eof_placeholder) or
+    /// [`label_delimiter`](Self::label_delimiter), are disabled.
+    pub const fn new(meta: M) -> Self
+    where
+        M: Copy,
+    {
+        Self::with_metas(meta, meta, meta, meta)
+    }
+
+    /// Like [`new`](Self::new), with different metadata for each kind of
+    /// chunk.
+    const fn with_metas(
+        margin_meta: M,
+        spaces_meta: M,
+        text_normal_meta: M,
+        text_alt_meta: M,
+    ) -> Self {
+        Self {
+            margin: Some(MarginStyle {
+                line_char: '│',
+                dot_char: '·',
+                meta: margin_meta,
+            }),
+            horizontal_char: '─',
+            vertical_char: '│',
+            top_vertical_char: '╭',
+            top_corner_char: '╭',
+            bottom_corner_char: '╰',
+            spaces_meta,
+            text_normal_meta,
+            text_alt_meta,
+            line_break_end: LineBreakEnd::PastLastChar,
+            tab_anchor: TabAnchor::FirstColumn,
+            wide_char_carets: WideCharCarets::PerCell,
+            eof_placeholder: None,
+            label_sanitization: LabelSanitization::None,
+            empty_span_carets: 1,
+            end_label_overflow: EndLabelOverflow::SameRow,
+            label_wrap_marker: None,
+            multi_line_starts: MultiLineStarts::Compact,
+            style_precedence: StylePrecedence::FirstAdded,
+            label_anchor: LabelAnchor::FirstCaret,
+            left_labels: false,
+            label_row_spacing: 0,
+            compact_labels: false,
+            label_delimiter: None,
+            more_annotations_marker: "…",
+        }
+    }
+
+    /// Replaces the characters used to draw lines and the margin, and the
+    /// [`more_annotations_marker`](Self::more_annotations_marker), with ASCII
+    /// fallbacks if `legacy_console` is `true`, and returns `self` unchanged
+    /// otherwise.
+    ///
+    /// Legacy consoles (e.g., the Windows console with a raster font or a
+    /// non-Unicode code page) render box-drawing characters poorly, so the
+    /// flag is meant to be detected at runtime. Sources shown on such
+    /// consoles should also avoid [`NulHandling::Glyph`], which uses a
+    /// control picture.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # let main_style = sourceannot::MainStyle::new(());
+    /// let legacy_console = true; // e.g., detected at runtime
+    /// let main_style = main_style.with_console_chars(legacy_console);
+    ///
+    /// assert_eq!(main_style.vertical_char, '|');
+    /// assert_eq!(main_style.bottom_corner_char, '`');
+    /// ```
+    pub fn with_console_chars(mut self, legacy_console: bool) -> Self {
+        if legacy_console {
+            self.replace_console_chars(|_, fallback| fallback);
+            self.more_annotations_marker = "...";
+        }
+        self
+    }
+
+    /// Replaces each character used to draw lines and the margin with the
+    /// result of `replace`, which receives the character and its ASCII
+    /// fallback for legacy consoles.
+    pub(crate) fn replace_console_chars(&mut self, replace: impl Fn(char, char) -> char) {
+        if let Some(ref mut margin) = self.margin {
+            margin.line_char = replace(margin.line_char, '|');
+            margin.dot_char = replace(margin.dot_char, ':');
+        }
+        self.horizontal_char = replace(self.horizontal_char, '-');
+        self.vertical_char = replace(self.vertical_char, '|');
+        self.top_vertical_char = replace(self.top_vertical_char, ',');
+        self.top_corner_char = replace(self.top_corner_char, ',');
+        self.bottom_corner_char = replace(self.bottom_corner_char, '`');
+    }
+}
+
+impl<M: Default> Default for MainStyle<M> {
+    /// Like [`MainStyle::new`], but every chunk is accompanied by
+    /// `M::default()`.
+    fn default() -> Self {
+        Self::with_metas(M::default(), M::default(), M::default(), M::default())
+    }
+}
+
+/// Placement of the end of an annotation whose span ends on a line break.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LineBreakEnd {
+    /// The line break is treated as an extra column after the last
+    /// character of the line.
+    PastLastChar,
+
+    /// The end is clamped to the last character of the line. If the span
+    /// only covers the line break, it points to the last character.
+    LastChar,
+}
+
+/// Placement of the start of an annotation whose span starts at a tab.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TabAnchor {
+    /// The start is placed at the first display column of the expanded tab.
+    FirstColumn,
+
+    /// The start is placed at the last display column of the expanded tab,
+    /// which is where most editors show the cursor when it is on a tab.
+    LastColumn,
+}
+
+/// How carets are drawn under characters that occupy more than one
+/// display column.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WideCharCarets {
+    /// One caret is drawn for each display column (e.g., `^^` for a
+    /// double-width character).
+    PerCell,
+
+    /// A single caret is drawn for each character, in its central column
+    /// (the first one for double-width characters).
+    PerChar,
+}
+
+/// How control characters in labels are handled when rendering.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LabelSanitization {
+    /// Labels are rendered as they are.
+    None,
+
+    /// Control characters are removed.
+    Strip,
+
+    /// Control characters are escaped like [`char::escape_default`]
+    /// (e.g., a line break is rendered as `\n`).
+    Escape,
+}
+
+/// Placement of multi-line annotation labels that would exceed the render
+/// width.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EndLabelOverflow {
+    /// The label is placed after the end caret, overflowing the render
+    /// width.
+    SameRow,
+
+    /// The label is moved to its own row, aligned under the bottom corner.
+    NextRow,
+}
+
+/// How the start of multi-line annotations is drawn.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MultiLineStarts {
+    /// Annotations that start at the first column are attached to the line
+    /// itself (with [`top_vertical_char`](MainStyle::top_vertical_char)),
+    /// while the rest get a separate row below the line.
+    Compact,
+
+    /// All annotations get a separate row below the line, regardless of the
+    /// column where they start.
+    AlwaysBelow,
+}
+
+/// Precedence of annotations that cover the same text.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StylePrecedence {
+    /// The annotation that was added first wins.
+    FirstAdded,
+
+    /// The annotation that was added last wins.
+    LastAdded,
+
+    /// The annotation with the shortest span (in source units) wins. Ties
+    /// are resolved in favor of the annotation that was added first.
+    Innermost,
+}
+
+/// Column under which the vertical line and the label of a single-line
+/// annotation are placed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LabelAnchor {
+    /// Under the first caret.
+    FirstCaret,
+
+    /// Under the last caret, which reads better for long spans at the
+    /// start of long lines.
+    LastCaret,
 }
 
 /// The style of the margin of an annotated snippet.
@@ -184,4 +539,103 @@ pub struct AnnotStyle<M> {
 
     /// Metadata that accompanies annotation drawings.
     pub line_meta: M,
+
+    /// Whether to hide the carets of single-line annotations.
+    ///
+    /// The annotated text is still rendered with the metadata of the
+    /// annotation, and the label is placed on its own row under the start
+    /// of the annotated text. This is useful for informational highlights,
+    /// where carets add noise.
+    pub hide_carets: bool,
+}
+
+/// Severity of an annotation, used to pick its style from
+/// [`SeverityStyles`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// An error, drawn with `^` carets by default.
+    Error,
+    /// A warning, drawn with `~` carets by default.
+    Warning,
+    /// A note, drawn with `-` carets by default.
+    Note,
+}
+
+impl Severity {
+    /// Returns the default caret character of the severity.
+    #[inline]
+    pub fn default_caret(self) -> char {
+        match self {
+            Self::Error => '^',
+            Self::Warning => '~',
+            Self::Note => '-',
+        }
+    }
+}
+
+/// A mapping from [`Severity`] to [`AnnotStyle`], so annotations can be
+/// styled by severity.
+///
+/// # Example
+///
+/// ```
+/// use sourceannot::{Severity, SeverityStyles};
+///
+/// let styles = SeverityStyles::new(|severity| match severity {
+///     Severity::Error => ("red", "red-alt", "red-line"),
+///     Severity::Warning => ("yellow", "yellow-alt", "yellow-line"),
+///     Severity::Note => ("blue", "blue-alt", "blue-line"),
+/// });
+///
+/// let style = styles.get(Severity::Warning);
+/// assert_eq!(style.caret, '~');
+/// assert_eq!(style.line_meta, "yellow-line");
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SeverityStyles<M> {
+    /// Style of [`Severity::Error`] annotations.
+    pub error: AnnotStyle<M>,
+
+    /// Style of [`Severity::Warning`] annotations.
+    pub warning: AnnotStyle<M>,
+
+    /// Style of [`Severity::Note`] annotations.
+    pub note: AnnotStyle<M>,
+}
+
+impl<M> SeverityStyles<M> {
+    /// Creates the styles using the [default caret](Severity::default_caret)
+    /// of each severity.
+    ///
+    /// `metas` returns the `(text_normal_meta, text_alt_meta, line_meta)`
+    /// of each severity.
+    pub fn new(mut metas: impl FnMut(Severity) -> (M, M, M)) -> Self {
+        let mut style = |severity: Severity| {
+            let (text_normal_meta, text_alt_meta, line_meta) = metas(severity);
+            AnnotStyle {
+                caret: severity.default_caret(),
+                text_normal_meta,
+                text_alt_meta,
+                line_meta,
+                hide_carets: false,
+            }
+        };
+        Self {
+            error: style(Severity::Error),
+            warning: style(Severity::Warning),
+            note: style(Severity::Note),
+        }
+    }
+
+    /// Returns the style of `severity`.
+    pub fn get(&self, severity: Severity) -> AnnotStyle<M>
+    where
+        M: Clone,
+    {
+        match severity {
+            Severity::Error => self.error.clone(),
+            Severity::Warning => self.warning.clone(),
+            Severity::Note => self.note.clone(),
+        }
+    }
 }
@@ -0,0 +1,34 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{ChunkKind, RowKind};
+
+/// A sink for rendered snippets, see
+/// [`Annotations::render_to`](crate::Annotations::render_to).
+///
+/// Only [`put`](Self::put) is required. The row methods have no-op default
+/// implementations and can be overridden by sinks that wrap each row (e.g.,
+/// HTML tables or TUI grids).
+pub trait Output<M> {
+    /// Writes a chunk of rendered text.
+    ///
+    /// A chunk that ends a row ends with a line break.
+    fn put(&mut self, text: &str, meta: &M, kind: ChunkKind);
+
+    /// Called before the first chunk of each row.
+    #[inline]
+    fn begin_row(&mut self, kind: RowKind) {
+        let _ = kind;
+    }
+
+    /// Called after the chunk that ends each row.
+    #[inline]
+    fn end_row(&mut self) {}
+}
+
+impl<M: Clone> Output<M> for Vec<(String, M)> {
+    #[inline]
+    fn put(&mut self, text: &str, meta: &M, _kind: ChunkKind) {
+        self.push((String::from(text), meta.clone()));
+    }
+}
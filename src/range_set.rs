@@ -1,8 +1,29 @@
 use alloc::{vec, vec::Vec};
 use core::ops::RangeInclusive;
 
+/// A set of values, stored as sorted ranges that neither overlap nor touch.
+///
+/// It is used by snippets to keep track of alternative text, and it is
+/// exported so custom snippet builders can do the same bookkeeping.
+///
+/// # Example
+///
+/// ```
+/// use sourceannot::RangeSet;
+///
+/// let mut set = RangeSet::new();
+/// set.insert(1..=3);
+/// set.insert(4..=6);
+/// set.insert(10..=12);
+/// assert!(set.contains(&5));
+/// assert!(!set.contains(&8));
+/// assert_eq!(set.ranges().collect::<Vec<_>>(), [1..=6, 10..=12]);
+///
+/// set.remove(3..=4);
+/// assert_eq!(set.ranges().collect::<Vec<_>>(), [1..=2, 5..=6, 10..=12]);
+/// ```
 #[derive(Clone, PartialEq, Eq)]
-pub(crate) struct RangeSet<T: Copy + Ord>
+pub struct RangeSet<T: Copy + Ord>
 where
     RangeInclusive<T>: Iterator,
 {
@@ -85,11 +106,24 @@ impl<T: Copy + Ord> RangeSet<T>
 where
     RangeInclusive<T>: Iterator,
 {
+    /// Creates an empty set.
     #[inline]
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self { ranges: Vec::new() }
     }
 
+    /// Returns whether the set is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns whether `value` is in the set.
+    #[inline]
+    pub fn contains(&self, value: &T) -> bool {
+        self.search(value).is_ok()
+    }
+
     #[inline]
     fn search(&self, value: &T) -> Result<usize, usize> {
         self.ranges.binary_search_by(|range| {
@@ -103,12 +137,51 @@ where
         })
     }
 
+    /// Returns an iterator over the ranges of the set, in ascending order.
+    ///
+    /// The ranges neither overlap nor touch each other.
     #[inline]
-    pub(crate) fn ranges(&self) -> impl Iterator<Item = RangeInclusive<T>> + '_ {
+    pub fn ranges(&self) -> impl Iterator<Item = RangeInclusive<T>> + '_ {
         self.ranges.iter().cloned()
     }
 
-    pub(crate) fn insert(&mut self, new_range: RangeInclusive<T>) {
+    /// Returns an iterator over the values of the set, in ascending order.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_
+    where
+        RangeInclusive<T>: Iterator<Item = T>,
+    {
+        self.ranges.iter().cloned().flatten()
+    }
+
+    /// Returns the values that are in both `self` and `other`.
+    pub fn intersection(&self, other: &Self) -> Self {
+        let mut ranges = Vec::new();
+        let mut i1 = 0;
+        let mut i2 = 0;
+        while i1 < self.ranges.len() && i2 < other.ranges.len() {
+            let range1 = &self.ranges[i1];
+            let range2 = &other.ranges[i2];
+            let start = *range1.start().max(range2.start());
+            let end = *range1.end().min(range2.end());
+            if start <= end {
+                ranges.push(start..=end);
+            }
+            if range1.end() < range2.end() {
+                i1 += 1;
+            } else {
+                i2 += 1;
+            }
+        }
+        Self { ranges }
+    }
+
+    /// Adds the values of `new_range` to the set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the start of `new_range` is greater than its end.
+    pub fn insert(&mut self, new_range: RangeInclusive<T>) {
         assert!(new_range.start() <= new_range.end());
         match (self.search(new_range.start()), self.search(new_range.end())) {
             (Ok(i1), Ok(i2)) => {
@@ -201,8 +274,86 @@ where
     }
 }
 
+impl<T: Copy + Ord> RangeSet<T>
+where
+    RangeInclusive<T>: DoubleEndedIterator<Item = T>,
+{
+    /// Removes the values of `range` from the set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the start of `range` is greater than its end.
+    pub fn remove(&mut self, range: RangeInclusive<T>) {
+        assert!(range.start() <= range.end());
+        let i1 = self.ranges.partition_point(|r| r.end() < range.start());
+        let i2 = self.ranges.partition_point(|r| r.start() <= range.end());
+        if i1 >= i2 {
+            return;
+        }
+
+        let mut remaining = Vec::new();
+        let first = &self.ranges[i1];
+        if first.start() < range.start() {
+            // `nth(1)` of the reversed range is the predecessor of its end
+            let end = (*first.start()..=*range.start()).rev().nth(1).unwrap();
+            remaining.push(*first.start()..=end);
+        }
+        let last = &self.ranges[i2 - 1];
+        if last.end() > range.end() {
+            // `nth(1)` of the range is the successor of its start
+            let start = (*range.end()..=*last.end()).nth(1).unwrap();
+            remaining.push(start..=*last.end());
+        }
+        self.ranges.splice(i1..i2, remaining);
+    }
+
+    /// Returns the values of `self` that are not in `other`.
+    pub fn difference(&self, other: &Self) -> Self {
+        let mut result = self.clone();
+        for range in other.ranges() {
+            result.remove(range);
+        }
+        result
+    }
+
+    /// Returns the values within `bounds` that are not in the set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the start of `bounds` is greater than its end.
+    pub fn complement(&self, bounds: RangeInclusive<T>) -> Self {
+        assert!(bounds.start() <= bounds.end());
+        let mut ranges = Vec::new();
+        // First value of `bounds` not yet known to be in the set or in the
+        // complement, `None` once the end of `bounds` is reached.
+        let mut next = Some(*bounds.start());
+        for range in self.ranges.iter() {
+            let Some(start) = next else {
+                break;
+            };
+            if *range.end() < start {
+                continue;
+            }
+            if range.start() > bounds.end() {
+                break;
+            }
+            if *range.start() > start {
+                let end = (start..=*range.start()).rev().nth(1).unwrap();
+                ranges.push(start..=end);
+            }
+            next = (*range.end()..=*bounds.end()).nth(1);
+        }
+        if let Some(start) = next {
+            ranges.push(start..=*bounds.end());
+        }
+        Self { ranges }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use alloc::vec::Vec;
+
     use super::RangeSet;
 
     #[test]
@@ -352,4 +503,81 @@ mod tests {
         set.insert(15..=45);
         assert_eq!(set.ranges, [0..=10, 15..=50]);
     }
+
+    #[test]
+    fn test_contains() {
+        let set: RangeSet<u32> = [1..=3, 7..=9].into_iter().collect();
+        assert!(!set.contains(&0));
+        assert!(set.contains(&1));
+        assert!(set.contains(&3));
+        assert!(!set.contains(&5));
+        assert!(set.contains(&8));
+        assert!(!set.contains(&10));
+        assert!(!RangeSet::new().contains(&0));
+    }
+
+    #[test]
+    fn test_iter() {
+        let set: RangeSet<u32> = [1..=3, 7..=8].into_iter().collect();
+        assert_eq!(set.iter().collect::<Vec<_>>(), [1, 2, 3, 7, 8]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut set: RangeSet<u32> = [0..=10, 20..=30, 40..=50].into_iter().collect();
+        set.remove(12..=18);
+        assert_eq!(set.ranges, [0..=10, 20..=30, 40..=50]);
+        set.remove(25..=25);
+        assert_eq!(set.ranges, [0..=10, 20..=24, 26..=30, 40..=50]);
+        set.remove(5..=22);
+        assert_eq!(set.ranges, [0..=4, 23..=24, 26..=30, 40..=50]);
+        set.remove(23..=45);
+        assert_eq!(set.ranges, [0..=4, 46..=50]);
+        set.remove(0..=0);
+        assert_eq!(set.ranges, [1..=4, 46..=50]);
+        set.remove(0..=100);
+        assert!(set.is_empty());
+
+        let mut set: RangeSet<u8> = [0..=255].into_iter().collect();
+        set.remove(0..=254);
+        assert_eq!(set.ranges, [255..=255]);
+    }
+
+    #[test]
+    fn test_intersection() {
+        let set1: RangeSet<u32> = [0..=10, 20..=30, 40..=50].into_iter().collect();
+        let set2: RangeSet<u32> = [5..=25, 30..=35, 45..=45].into_iter().collect();
+        assert_eq!(
+            set1.intersection(&set2).ranges,
+            [5..=10, 20..=25, 30..=30, 45..=45]
+        );
+        assert_eq!(set2.intersection(&set1), set1.intersection(&set2));
+        assert!(set1.intersection(&RangeSet::new()).is_empty());
+    }
+
+    #[test]
+    fn test_difference() {
+        let set1: RangeSet<u32> = [0..=10, 20..=30, 40..=50].into_iter().collect();
+        let set2: RangeSet<u32> = [5..=25, 30..=35, 45..=45].into_iter().collect();
+        assert_eq!(
+            set1.difference(&set2).ranges,
+            [0..=4, 26..=29, 40..=44, 46..=50],
+        );
+        assert_eq!(set2.difference(&set1).ranges, [11..=19, 31..=35]);
+        assert!(set1.difference(&set1).is_empty());
+    }
+
+    #[test]
+    fn test_complement() {
+        let set: RangeSet<u32> = [5..=10, 20..=30].into_iter().collect();
+        assert_eq!(set.complement(0..=40).ranges, [0..=4, 11..=19, 31..=40]);
+        assert_eq!(set.complement(5..=30).ranges, [11..=19]);
+        assert_eq!(set.complement(8..=25).ranges, [11..=19]);
+        assert_eq!(set.complement(12..=15).ranges, [12..=15]);
+        assert!(set.complement(20..=30).is_empty());
+        assert_eq!(RangeSet::new().complement(1..=2).ranges, [1..=2]);
+
+        let set: RangeSet<u8> = [0..=10, 250..=255].into_iter().collect();
+        assert_eq!(set.complement(0..=255).ranges, [11..=249]);
+    }
 }
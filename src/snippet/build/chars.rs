@@ -0,0 +1,172 @@
+use alloc::format;
+use alloc::string::String;
+
+use super::{check_callback_output, NulHandling, SourceSnippetBuilder, Utf8Options};
+use crate::snippet::UnitKind;
+use crate::{Error, SourceSnippet};
+
+impl SourceSnippet {
+    /// Creates a snippet from a string whose units are characters (i.e.,
+    /// Unicode scalar values), which is useful when the positions are char
+    /// indices (e.g., `source.chars().enumerate()`).
+    ///
+    /// "\n" and "\r\n" are treated as line breaks.
+    ///
+    /// Control characters (except tabs and line breaks) are represented as
+    /// `<XXXX>` as alternative text.
+    ///
+    /// The snippet renders exactly like the one created with
+    /// [`build_from_utf8`](Self::build_from_utf8) from the same source, with
+    /// spans expressed in characters instead of bytes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let source = "λx. x\n";
+    /// let snippet = sourceannot::SourceSnippet::build_from_chars(1, source, 4);
+    ///
+    /// // `x` is the second character
+    /// assert_eq!(snippet.get_line_col(1), (0, 1));
+    /// ```
+    pub fn build_from_chars(start_line: usize, source: &str, tab_width: usize) -> Self {
+        Self::build_from_chars_ex(start_line, source, Utf8Options::default(), |chr| {
+            if chr == '\t' {
+                (false, " ".repeat(tab_width))
+            } else {
+                (true, format!("<{:04X}>", u32::from(chr)))
+            }
+        })
+    }
+
+    /// Creates a snippet from a string whose units are characters.
+    ///
+    /// "\n" and "\r\n" are treated as line breaks.
+    ///
+    /// `options` are applied like in
+    /// [`build_from_utf8_ex_with_options`](Self::build_from_utf8_ex_with_options).
+    ///
+    /// `on_control` is used to handle control characters (that are not line
+    /// breaks). `on_control` also returns a boolean to indicate if the text
+    /// should be rendered as alternative.
+    ///
+    /// The snippet renders exactly like the one created with
+    /// [`build_from_utf8_ex_with_options`](Self::build_from_utf8_ex_with_options)
+    /// from the same source, options and callbacks, with spans expressed in
+    /// characters instead of bytes.
+    pub fn build_from_chars_ex<FnCtrl>(
+        start_line: usize,
+        source: &str,
+        options: Utf8Options,
+        mut on_control: FnCtrl,
+    ) -> Self
+    where
+        FnCtrl: FnMut(char) -> (bool, String),
+    {
+        let mut snippet = SourceSnippetBuilder::new(start_line, UnitKind::Char);
+
+        let mut chars = source.chars();
+        while let Some(chr) = chars.next() {
+            if chr == '\r' && chars.as_str().starts_with('\n') {
+                snippet.push_line_break(2);
+                chars.next().unwrap();
+            } else if chr == '\n' {
+                snippet.push_line_break(1);
+            } else if chr == '\0' && options.nul != NulHandling::Control {
+                snippet.push_nul(options.nul, 1);
+            } else if options.space_like.contains(chr) {
+                let (alt, text) = on_control(chr);
+                snippet.push_text(&text, 1, alt);
+            } else if let Some(chr_width) = crate::width::char_width(chr) {
+                snippet.push_char(chr, chr_width, 1, false);
+            } else {
+                snippet.push_decoded_control(chr, 1, options, &mut on_control);
+            }
+        }
+
+        snippet.finish()
+    }
+
+    /// Like [`build_from_chars_ex`](Self::build_from_chars_ex), but returns an
+    /// error if `on_control` returns an invalid text (i.e., one with control
+    /// characters), which would otherwise corrupt the layout of the snippet.
+    pub fn try_build_from_chars_ex<FnCtrl>(
+        start_line: usize,
+        source: &str,
+        options: Utf8Options,
+        mut on_control: FnCtrl,
+    ) -> Result<Self, Error>
+    where
+        FnCtrl: FnMut(char) -> (bool, String),
+    {
+        let mut error = None;
+        let snippet = Self::build_from_chars_ex(start_line, source, options, |chr| {
+            check_callback_output(&mut error, on_control(chr))
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(snippet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use crate::snippet::{NulHandling, SpaceLikeChars, Utf8Options};
+    use crate::{SourceSnippet, UnitKind};
+
+    #[test]
+    fn test_same_as_utf8() {
+        let source = "aé\tλ\r\nb\u{7}c\n";
+        let from_chars = SourceSnippet::build_from_chars(0, source, 4);
+        let from_utf8 = SourceSnippet::build_from_utf8(0, source.as_bytes(), 4);
+
+        assert_eq!(from_chars.unit_kind(), UnitKind::Char);
+        assert_eq!(from_utf8.unit_kind(), UnitKind::Byte);
+        assert_eq!(from_chars.lines, from_utf8.lines);
+        assert_eq!(from_chars.num_units(), source.chars().count());
+        for (chr_i, (byte_i, _)) in source.char_indices().enumerate() {
+            assert_eq!(
+                from_chars.get_line_col(chr_i),
+                from_utf8.get_line_col(byte_i),
+            );
+        }
+    }
+
+    #[test]
+    fn test_options_same_as_utf8() {
+        let source = "a\0\u{A0}\tb\r\n";
+        let options = Utf8Options {
+            nul: NulHandling::Glyph,
+            space_like: SpaceLikeChars::ALL,
+            keep_tabs: true,
+        };
+        let on_control = |chr: char| {
+            if chr == '\t' {
+                (false, " ".repeat(4))
+            } else {
+                (true, format!("<{:04X}>", u32::from(chr)))
+            }
+        };
+        let from_chars = SourceSnippet::build_from_chars_ex(0, source, options, on_control);
+        let from_utf8 = SourceSnippet::build_from_utf8_ex_with_options(
+            0,
+            source.as_bytes(),
+            options,
+            on_control,
+            |_| unreachable!(),
+            false,
+        );
+
+        assert_eq!(from_chars.lines, from_utf8.lines);
+        assert_eq!(&*from_chars.lines[0].text, "a\u{2400}<00A0>\tb");
+        assert_eq!(from_chars.num_units(), source.chars().count());
+        for (chr_i, (byte_i, _)) in source.char_indices().enumerate() {
+            assert_eq!(
+                from_chars.get_line_col(chr_i),
+                from_utf8.get_line_col(byte_i),
+            );
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use alloc::format;
+use alloc::string::String;
+
+use super::{check_callback_output, Latin1Options, NulHandling, SourceSnippetBuilder};
+use crate::snippet::UnitKind;
+use crate::{Error, SourceSnippet};
+
+impl SourceSnippet {
+    /// Creates a snippet from a source encoded with a single-byte code page
+    /// (such as CP437 or CP850), described by `table`, which maps each byte
+    /// to a character.
+    ///
+    /// "\n" and "\r\n" are treated as line breaks, regardless of `table`.
+    ///
+    /// Bytes mapped to control characters (except tabs and line breaks) are
+    /// represented as `<XX>` as alternative text.
+    pub fn build_from_code_page(
+        start_line: usize,
+        source: &[u8],
+        table: &[char; 256],
+        tab_width: usize,
+    ) -> Self {
+        Self::build_from_code_page_ex(start_line, source, table, Latin1Options::default(), |chr| {
+            if chr == b'\t' {
+                (false, " ".repeat(tab_width))
+            } else {
+                (true, format!("<{chr:02X}>"))
+            }
+        })
+    }
+
+    /// Creates a snippet from a source encoded with a single-byte code page
+    /// (such as CP437 or CP850), described by `table`, which maps each byte
+    /// to a character.
+    ///
+    /// "\n" and "\r\n" are treated as line breaks, regardless of `table`.
+    ///
+    /// `options` are applied like in
+    /// [`build_from_latin1_ex_with_options`](Self::build_from_latin1_ex_with_options).
+    ///
+    /// `on_control` is used to handle bytes mapped to control characters
+    /// (that are not line breaks). `on_control` also returns a boolean to
+    /// indicate if the text should be rendered as alternative.
+    pub fn build_from_code_page_ex<FnCtrl>(
+        start_line: usize,
+        source: &[u8],
+        table: &[char; 256],
+        options: Latin1Options,
+        mut on_control: FnCtrl,
+    ) -> Self
+    where
+        FnCtrl: FnMut(u8) -> (bool, String),
+    {
+        let nul = options.nul;
+        let mut snippet = SourceSnippetBuilder::new(start_line, UnitKind::Byte);
+
+        let mut bytes = source.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte == b'\r' && bytes.as_slice().starts_with(b"\n") {
+                snippet.push_line_break(2);
+                bytes.next().unwrap();
+            } else if byte == b'\n' {
+                snippet.push_line_break(1);
+            } else if byte == 0 && nul != NulHandling::Control {
+                snippet.push_nul(nul, 1);
+            } else {
+                let chr = table[usize::from(byte)];
+                if let Some(chr_width) = crate::width::char_width(chr) {
+                    snippet.push_char(chr, chr_width, 1, false);
+                } else {
+                    let (alt, text) = on_control(byte);
+                    if byte == b'\t' {
+                        snippet.push_tab(&text, 1, alt);
+                    } else {
+                        snippet.push_text(&text, 1, alt);
+                    }
+                }
+            }
+        }
+
+        snippet.finish()
+    }
+
+    /// Like [`build_from_code_page_ex`](Self::build_from_code_page_ex), but
+    /// returns an error if `on_control` returns an invalid text (i.e., one
+    /// with control characters), which would otherwise corrupt the layout of
+    /// the snippet.
+    pub fn try_build_from_code_page_ex<FnCtrl>(
+        start_line: usize,
+        source: &[u8],
+        table: &[char; 256],
+        options: Latin1Options,
+        mut on_control: FnCtrl,
+    ) -> Result<Self, Error>
+    where
+        FnCtrl: FnMut(u8) -> (bool, String),
+    {
+        let mut error = None;
+        let snippet = Self::build_from_code_page_ex(start_line, source, table, options, |byte| {
+            check_callback_output(&mut error, on_control(byte))
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(snippet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::range_set::RangeSet;
+    use crate::snippet::{Latin1Options, NulHandling, SourceLine, SourceSnippet, SourceUnitMeta};
+
+    fn meta(width: usize, len: usize) -> SourceUnitMeta {
+        SourceUnitMeta::new(width, len)
+    }
+
+    fn test_table() -> [char; 256] {
+        // ASCII, with some CP437 characters
+        let mut table = [char::REPLACEMENT_CHARACTER; 256];
+        for (i, chr) in table[..0x80].iter_mut().enumerate() {
+            *chr = char::from(i as u8);
+        }
+        table[0x80] = '\u{C7}';
+        table[0xC9] = '\u{2554}';
+        table[0xFF] = '\u{A0}';
+        table
+    }
+
+    #[test]
+    fn test_simple() {
+        let source = b"1\x80\xC9\n4\x017";
+        let snippet = SourceSnippet::build_from_code_page(0, source, &test_table(), 4);
+
+        assert_eq!(snippet.start_line, 0);
+        assert_eq!(
+            snippet.lines,
+            [
+                SourceLine {
+                    text: "1\u{C7}\u{2554}".into(),
+                    alts: RangeSet::new(),
+                    width: 3,
+                },
+                SourceLine {
+                    text: "4<01>7".into(),
+                    alts: RangeSet::from(1..=4),
+                    width: 6,
+                },
+            ],
+        );
+        assert_eq!(snippet.line_map, [4]);
+        assert_eq!(
+            snippet.metas,
+            [
+                meta(1, 1),
+                meta(1, 2),
+                meta(1, 3),
+                meta(1, 0),
+                meta(1, 1),
+                meta(4, 4),
+                meta(1, 1),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_crlf() {
+        let source = b"1\r\n\r2";
+        let snippet = SourceSnippet::build_from_code_page(0, source, &test_table(), 4);
+
+        assert_eq!(
+            snippet.lines,
+            [
+                SourceLine {
+                    text: "1".into(),
+                    alts: RangeSet::new(),
+                    width: 1,
+                },
+                SourceLine {
+                    text: "<0D>2".into(),
+                    alts: RangeSet::from(0..=3),
+                    width: 5,
+                },
+            ],
+        );
+        assert_eq!(snippet.line_map, [3]);
+    }
+
+    #[test]
+    fn test_nul_strip() {
+        let source = b"1\x002";
+        let snippet = SourceSnippet::build_from_code_page_ex(
+            0,
+            source,
+            &test_table(),
+            Latin1Options {
+                nul: NulHandling::Strip,
+            },
+            |_| unreachable!(),
+        );
+
+        assert_eq!(&*snippet.lines[0].text, "12");
+        assert_eq!(snippet.metas, [meta(1, 1), meta(0, 0), meta(1, 1)]);
+    }
+}
@@ -0,0 +1,398 @@
+use alloc::format;
+use alloc::string::String;
+
+use super::{check_callback_output, NulHandling, SourceSnippetBuilder, Utf8Options};
+use crate::snippet::UnitKind;
+use crate::{Error, SourceSnippet};
+
+/// A decoder of a source encoding, which allows building snippets from
+/// sources in encodings that are not supported by this crate (e.g., KOI8-R
+/// or GB2312) with [`SourceSnippet::build_with_decoder`].
+///
+/// Closures with the signature of [`decode`](Self::decode) implement this
+/// trait. The UTF-8 and Latin-1 builders are also built on a decoder, so
+/// sources decoded by a custom decoder are handled like those.
+///
+/// # Example
+///
+/// ```
+/// use sourceannot::SourceSnippet;
+///
+/// // Bytes from 0xC0 are lowercase Cyrillic letters, like in KOI8-R
+/// // (although with a different order)
+/// let decoder = |source: &[u8]| match source[0] {
+///     byte @ 0x00..=0x7F => (Some(char::from(byte)), 1),
+///     byte @ 0xC0..=0xDF => (char::from_u32(0x430 + u32::from(byte - 0xC0)), 1),
+///     _ => (None, 1),
+/// };
+///
+/// let snippet = SourceSnippet::build_with_decoder(1, b"\xC0\xC1 = 1;\n", decoder, 4);
+/// assert_eq!(snippet.get_line_col(3), (0, 3));
+/// ```
+pub trait Decoder {
+    /// Decodes the element at the start of `source`, which is not empty.
+    ///
+    /// Returns the decoded character, or `None` if `source` starts with an
+    /// invalid sequence, along with the number of bytes it spans, which
+    /// must be at least 1 and at most `source.len()`.
+    fn decode(&mut self, source: &[u8]) -> (Option<char>, usize);
+}
+
+impl<F> Decoder for F
+where
+    F: FnMut(&[u8]) -> (Option<char>, usize),
+{
+    #[inline]
+    fn decode(&mut self, source: &[u8]) -> (Option<char>, usize) {
+        self(source)
+    }
+}
+
+impl SourceSnippet {
+    /// Creates a snippet from a source in a custom encoding, decoded by
+    /// `decoder`.
+    ///
+    /// "\n" and "\r\n" (once decoded) are treated as line breaks.
+    ///
+    /// Control characters (except tabs and line breaks) are represented as
+    /// `<XXXX>` as alternative text. Each byte of invalid sequences is
+    /// represented as `<XX>` as alternative text.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `decoder` returns an invalid length.
+    pub fn build_with_decoder<D: Decoder>(
+        start_line: usize,
+        source: &[u8],
+        decoder: D,
+        tab_width: usize,
+    ) -> Self {
+        Self::build_with_decoder_ex(
+            start_line,
+            source,
+            decoder,
+            Utf8Options::default(),
+            |chr| {
+                if chr == '\t' {
+                    (false, " ".repeat(tab_width))
+                } else {
+                    (true, format!("<{:04X}>", u32::from(chr)))
+                }
+            },
+            |bytes| {
+                let text: String = bytes.iter().map(|byte| format!("<{byte:02X}>")).collect();
+                (true, text)
+            },
+        )
+    }
+
+    /// Creates a snippet from a source in a custom encoding, decoded by
+    /// `decoder`.
+    ///
+    /// "\n" and "\r\n" (once decoded) are treated as line breaks.
+    ///
+    /// `options` are applied to the decoded characters like in
+    /// [`build_from_utf8_ex_with_options`](Self::build_from_utf8_ex_with_options).
+    ///
+    /// `on_control` is used to handle control characters (that are not line
+    /// breaks) and `on_invalid` is used to handle invalid sequences. Both
+    /// also return a boolean to indicate if the text should be rendered as
+    /// alternative.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `decoder` returns an invalid length.
+    pub fn build_with_decoder_ex<D, FnCtrl, FnInv>(
+        start_line: usize,
+        source: &[u8],
+        decoder: D,
+        options: Utf8Options,
+        on_control: FnCtrl,
+        on_invalid: FnInv,
+    ) -> Self
+    where
+        D: Decoder,
+        FnCtrl: FnMut(char) -> (bool, String),
+        FnInv: FnMut(&[u8]) -> (bool, String),
+    {
+        let mut snippet = SourceSnippetBuilder::new(start_line, UnitKind::Byte);
+        snippet.push_decoded(
+            source,
+            decoder,
+            crate::width::char_width,
+            options,
+            on_control,
+            on_invalid,
+        );
+        snippet.finish()
+    }
+
+    /// Like [`build_with_decoder_ex`](Self::build_with_decoder_ex), but
+    /// returns an error if `on_control` or `on_invalid` return an invalid
+    /// text (i.e., one with control characters), which would otherwise
+    /// corrupt the layout of the snippet.
+    pub fn try_build_with_decoder_ex<D, FnCtrl, FnInv>(
+        start_line: usize,
+        source: &[u8],
+        decoder: D,
+        options: Utf8Options,
+        mut on_control: FnCtrl,
+        mut on_invalid: FnInv,
+    ) -> Result<Self, Error>
+    where
+        D: Decoder,
+        FnCtrl: FnMut(char) -> (bool, String),
+        FnInv: FnMut(&[u8]) -> (bool, String),
+    {
+        let mut control_error = None;
+        let mut invalid_error = None;
+        let snippet = Self::build_with_decoder_ex(
+            start_line,
+            source,
+            decoder,
+            options,
+            |chr| check_callback_output(&mut control_error, on_control(chr)),
+            |bytes| check_callback_output(&mut invalid_error, on_invalid(bytes)),
+        );
+        match control_error.or(invalid_error) {
+            Some(e) => Err(e),
+            None => Ok(snippet),
+        }
+    }
+}
+
+impl SourceSnippetBuilder {
+    /// Pushes `source`, decoded by `decoder`.
+    ///
+    /// This is the loop shared by the builders of encoded sources.
+    /// `char_width` returns the display width of a decoded character, or
+    /// `None` if it is a control character.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `decoder` returns an invalid length.
+    pub(super) fn push_decoded<D, FnCtrl, FnInv>(
+        &mut self,
+        source: &[u8],
+        mut decoder: D,
+        char_width: fn(char) -> Option<usize>,
+        options: Utf8Options,
+        mut on_control: FnCtrl,
+        mut on_invalid: FnInv,
+    ) where
+        D: Decoder,
+        FnCtrl: FnMut(char) -> (bool, String),
+        FnInv: FnMut(&[u8]) -> (bool, String),
+    {
+        // A decoded carriage return, waiting to know whether it is followed
+        // by a line feed
+        let mut pending_cr: Option<usize> = None;
+
+        let mut rem_source = source;
+        while !rem_source.is_empty() {
+            let (chr, orig_len) = decoder.decode(rem_source);
+            assert!(
+                orig_len != 0 && orig_len <= rem_source.len(),
+                "invalid length returned by decoder",
+            );
+            let bytes;
+            (bytes, rem_source) = rem_source.split_at(orig_len);
+
+            if let Some(cr_len) = pending_cr.take() {
+                if chr == Some('\n') {
+                    self.push_line_break(cr_len + orig_len);
+                    continue;
+                }
+                self.push_decoded_control('\r', cr_len, options, &mut on_control);
+            }
+
+            match chr {
+                Some('\r') => pending_cr = Some(orig_len),
+                Some('\n') => self.push_line_break(orig_len),
+                Some('\0') if options.nul != NulHandling::Control => {
+                    self.push_nul(options.nul, orig_len)
+                }
+                Some(chr) if options.space_like.contains(chr) => {
+                    let (alt, text) = on_control(chr);
+                    self.push_text(&text, orig_len, alt);
+                }
+                Some(chr) => match char_width(chr) {
+                    Some(chr_width) => self.push_char(chr, chr_width, orig_len, false),
+                    None => self.push_decoded_control(chr, orig_len, options, &mut on_control),
+                },
+                None => {
+                    let (alt, text) = on_invalid(bytes);
+                    self.push_text(&text, orig_len, alt);
+                }
+            }
+        }
+        if let Some(cr_len) = pending_cr {
+            self.push_decoded_control('\r', cr_len, options, &mut on_control);
+        }
+    }
+
+    /// Pushes a decoded control character (that is not a line break),
+    /// handled by `on_control`.
+    pub(super) fn push_decoded_control<FnCtrl>(
+        &mut self,
+        chr: char,
+        orig_len: usize,
+        options: Utf8Options,
+        on_control: &mut FnCtrl,
+    ) where
+        FnCtrl: FnMut(char) -> (bool, String),
+    {
+        let (alt, text) = on_control(chr);
+        if chr == '\t' && options.keep_tabs {
+            let width = crate::width::str_width(&text);
+            self.push_kept_tab(width, orig_len);
+        } else if chr == '\t' {
+            self.push_tab(&text, orig_len, alt);
+        } else {
+            self.push_text(&text, orig_len, alt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use crate::range_set::RangeSet;
+    use crate::snippet::{
+        NulHandling, SourceLine, SourceSnippet, SourceUnitMeta, SpaceLikeChars, Utf8Options,
+    };
+    use crate::Error;
+
+    fn meta(width: usize, len: usize) -> SourceUnitMeta {
+        SourceUnitMeta::new(width, len)
+    }
+
+    fn meta_tab(width: usize, len: usize) -> SourceUnitMeta {
+        SourceUnitMeta::tab(width, len)
+    }
+
+    fn meta_extra() -> SourceUnitMeta {
+        SourceUnitMeta::extra()
+    }
+
+    /// A toy double-byte encoding: ASCII bytes are themselves and a byte
+    /// from 0x80 followed by any byte is a Cyrillic letter.
+    fn double_byte(source: &[u8]) -> (Option<char>, usize) {
+        match source {
+            [byte @ 0x00..=0x7F, ..] => (Some(char::from(*byte)), 1),
+            [_, trail, ..] => (char::from_u32(0x400 + u32::from(trail & 0x3F)), 2),
+            [_] => (None, 1),
+            [] => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_simple() {
+        let source = b"a\x80\x00b\r\n\tc\x81";
+        let snippet = SourceSnippet::build_with_decoder(0, source, double_byte, 4);
+
+        assert_eq!(snippet.start_line, 0);
+        assert_eq!(
+            snippet.lines,
+            [
+                SourceLine {
+                    text: "a\u{400}b".into(),
+                    alts: RangeSet::new(),
+                    width: 3,
+                },
+                SourceLine {
+                    text: "    c<81>".into(),
+                    alts: RangeSet::from(5..=8),
+                    width: 9,
+                },
+            ],
+        );
+        assert_eq!(snippet.line_map, [6]);
+        assert_eq!(
+            snippet.metas,
+            [
+                meta(1, 1),
+                meta(1, 2),
+                meta_extra(),
+                meta(1, 1),
+                meta(1, 0),
+                meta(1, 0),
+                meta_tab(4, 4),
+                meta(1, 1),
+                meta(4, 4),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_lone_cr() {
+        let source = b"a\rb\r";
+        let snippet = SourceSnippet::build_with_decoder_ex(
+            0,
+            source,
+            double_byte,
+            Utf8Options {
+                nul: NulHandling::Glyph,
+                ..Utf8Options::default()
+            },
+            |chr| (true, String::from(if chr == '\r' { "CR" } else { "?" })),
+            |_| unreachable!(),
+        );
+
+        assert_eq!(snippet.lines.len(), 1);
+        assert_eq!(&*snippet.lines[0].text, "aCRbCR");
+        assert_eq!(snippet.lines[0].alts, RangeSet::from_iter([1..=2, 4..=5]));
+    }
+
+    #[test]
+    fn test_options() {
+        let source = b"a\tb\xC2\xA0c";
+        let decoder = |source: &[u8]| match source {
+            [b'\xC2', b'\xA0', ..] => (Some('\u{A0}'), 2),
+            [byte, ..] => (Some(char::from(*byte)), 1),
+            [] => unreachable!(),
+        };
+        let snippet = SourceSnippet::build_with_decoder_ex(
+            0,
+            source,
+            decoder,
+            Utf8Options {
+                space_like: SpaceLikeChars::ALL,
+                keep_tabs: true,
+                ..Utf8Options::default()
+            },
+            |chr| match chr {
+                '\t' => (false, String::from("    ")),
+                _ => (true, String::from("_")),
+            },
+            |_| unreachable!(),
+        );
+
+        assert_eq!(&*snippet.lines[0].text, "a\tb_c");
+        assert_eq!(snippet.lines[0].alts, RangeSet::from(3..=3));
+        assert_eq!(snippet.lines[0].width, 8);
+    }
+
+    #[test]
+    fn test_try_invalid_callback() {
+        assert_eq!(
+            SourceSnippet::try_build_with_decoder_ex(
+                0,
+                b"\x80",
+                double_byte,
+                Utf8Options::default(),
+                |_| unreachable!(),
+                |_| (true, String::from("\n")),
+            )
+            .unwrap_err(),
+            Error::ControlCharInText('\n'),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid length returned by decoder")]
+    fn test_invalid_decoder() {
+        SourceSnippet::build_with_decoder(0, b"abc", |_: &[u8]| (Some('a'), 0), 4);
+    }
+}
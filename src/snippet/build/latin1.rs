@@ -1,8 +1,9 @@
 use alloc::format;
 use alloc::string::String;
 
-use super::SourceSnippetBuilder;
-use crate::SourceSnippet;
+use super::{check_callback_output, Latin1Options, SourceSnippetBuilder, Utf8Options};
+use crate::snippet::UnitKind;
+use crate::{Error, SourceSnippet};
 
 impl SourceSnippet {
     /// Creates a snippet from a Latin-1 (ISO 8859-1) source.
@@ -31,49 +32,111 @@ impl SourceSnippet {
     pub fn build_from_latin1_ex<FnCtrl>(
         start_line: usize,
         source: &[u8],
+        on_control: FnCtrl,
+    ) -> Self
+    where
+        FnCtrl: FnMut(u8) -> (bool, String),
+    {
+        Self::build_from_latin1_ex_with_options(
+            start_line,
+            source,
+            Latin1Options::default(),
+            on_control,
+        )
+    }
+
+    /// Like [`build_from_latin1_ex`](Self::build_from_latin1_ex), with
+    /// additional `options`.
+    pub fn build_from_latin1_ex_with_options<FnCtrl>(
+        start_line: usize,
+        source: &[u8],
+        options: Latin1Options,
         mut on_control: FnCtrl,
     ) -> Self
     where
         FnCtrl: FnMut(u8) -> (bool, String),
     {
-        let mut snippet = SourceSnippetBuilder::new(start_line);
+        let mut snippet = SourceSnippetBuilder::new(start_line, UnitKind::Byte);
+        snippet.push_decoded(
+            source,
+            |source: &[u8]| (Some(char::from(source[0])), 1),
+            latin1_char_width,
+            Utf8Options {
+                nul: options.nul,
+                ..Utf8Options::default()
+            },
+            // Latin-1 characters are the first 256 code points.
+            |chr| on_control(chr as u8),
+            |_| unreachable!(),
+        );
 
-        let mut chars = source.iter();
-        while let Some(&chr) = chars.next() {
-            if chr == b'\r' && chars.as_slice().starts_with(b"\n") {
-                snippet.next_line(&[1, 1]);
-                chars.next().unwrap();
-            } else if chr == b'\n' {
-                snippet.next_line(&[1]);
-            } else {
-                let orig_len = 1;
+        snippet.finish()
+    }
 
-                if matches!(chr, b' '..=b'~' | 0xA0..=0xFF) {
-                    // The width of all printable Latin-1 characters is 1.
-                    let chr_width = 1;
-                    snippet.push_char(chr.into(), chr_width, orig_len, false);
-                } else {
-                    let (alt, text) = on_control(chr);
-                    snippet.push_text(&text, orig_len, alt);
-                }
-            }
-        }
+    /// Like [`build_from_latin1_ex`](Self::build_from_latin1_ex), but returns an
+    /// error if `on_control` returns an invalid text (i.e., one with control
+    /// characters), which would otherwise corrupt the layout of the snippet.
+    pub fn try_build_from_latin1_ex<FnCtrl>(
+        start_line: usize,
+        source: &[u8],
+        on_control: FnCtrl,
+    ) -> Result<Self, Error>
+    where
+        FnCtrl: FnMut(u8) -> (bool, String),
+    {
+        Self::try_build_from_latin1_ex_with_options(
+            start_line,
+            source,
+            Latin1Options::default(),
+            on_control,
+        )
+    }
 
-        snippet.finish()
+    /// Like [`build_from_latin1_ex_with_options`](Self::build_from_latin1_ex_with_options),
+    /// but returns an error if `on_control` returns an invalid text, like
+    /// [`try_build_from_latin1_ex`](Self::try_build_from_latin1_ex).
+    pub fn try_build_from_latin1_ex_with_options<FnCtrl>(
+        start_line: usize,
+        source: &[u8],
+        options: Latin1Options,
+        mut on_control: FnCtrl,
+    ) -> Result<Self, Error>
+    where
+        FnCtrl: FnMut(u8) -> (bool, String),
+    {
+        let mut error = None;
+        let snippet = Self::build_from_latin1_ex_with_options(start_line, source, options, |chr| {
+            check_callback_output(&mut error, on_control(chr))
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(snippet),
+        }
     }
 }
 
+/// Returns the display width of a Latin-1 character, or `None` if it is a
+/// control character.
+fn latin1_char_width(chr: char) -> Option<usize> {
+    // The width of all printable Latin-1 characters is 1.
+    matches!(chr, ' '..='~' | '\u{A0}'..='\u{FF}').then_some(1)
+}
+
 #[cfg(test)]
 mod tests {
     use alloc::format;
 
     use crate::range_set::RangeSet;
-    use crate::snippet::{SourceLine, SourceSnippet, SourceUnitMeta};
+    use crate::snippet::{Latin1Options, NulHandling, SourceLine, SourceSnippet, SourceUnitMeta};
 
     fn meta(width: usize, len: usize) -> SourceUnitMeta {
         SourceUnitMeta::new(width, len)
     }
 
+    fn meta_tab(width: usize, len: usize) -> SourceUnitMeta {
+        SourceUnitMeta::tab(width, len)
+    }
+
     #[test]
     fn test_simple_1() {
         let source = b"123\n456";
@@ -228,6 +291,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_nul_glyph() {
+        let source = b"1\x002";
+        let snippet = SourceSnippet::build_from_latin1_ex_with_options(
+            0,
+            source,
+            Latin1Options {
+                nul: NulHandling::Glyph,
+            },
+            |_| unreachable!(),
+        );
+
+        assert_eq!(
+            snippet.lines,
+            [SourceLine {
+                text: "1\u{2400}2".into(),
+                alts: RangeSet::from(1..=3),
+                width: 3,
+            }],
+        );
+        assert_eq!(snippet.metas, [meta(1, 1), meta(1, 3), meta(1, 1)]);
+    }
+
     #[test]
     fn test_crlf() {
         let source = b"123\r\n4\r6\r\n";
@@ -304,7 +390,7 @@ mod tests {
                 meta(1, 1),
                 meta(1, 1),
                 meta(1, 0),
-                meta(4, 4),
+                meta_tab(4, 4),
                 meta(1, 1),
                 meta(1, 1),
                 meta(1, 1),
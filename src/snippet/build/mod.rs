@@ -1,36 +1,204 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 
-use super::{SourceLine, SourceSnippet, SourceUnitMeta};
+use super::{SourceLine, SourceSnippet, SourceUnitMeta, UnitKind};
 use crate::range_set::RangeSet;
+use crate::Error;
 
+mod chars;
+mod code_page;
+mod decoder;
 mod latin1;
 mod utf8;
 
-struct SourceSnippetBuilder {
+pub use decoder::Decoder;
+
+/// How NUL characters (U+0000) are handled when building a snippet.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum NulHandling {
+    /// Handled like any other control character.
+    #[default]
+    Control,
+    /// Represented as `␀` (U+2400) as alternative text, with a width of 1.
+    Glyph,
+    /// Removed from the rendered text, with a width of 0.
+    Strip,
+}
+
+/// Options of [`SourceSnippet::build_from_utf8_with_options`] and
+/// [`SourceSnippet::build_from_utf8_ex_with_options`].
+///
+/// # Example
+///
+/// ```
+/// use sourceannot::{SourceSnippet, SpaceLikeChars, Utf8Options};
+///
+/// let source = "a\u{A0}b";
+/// let options = Utf8Options {
+///     space_like: SpaceLikeChars::ALL,
+///     ..Utf8Options::default()
+/// };
+/// let snippet = SourceSnippet::build_from_utf8_with_options(1, source.as_bytes(), 4, options);
+///
+/// // `b` is after the 6 columns of `<00A0>`
+/// assert_eq!(snippet.get_line_col(3), (0, 7));
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Utf8Options {
+    /// How NUL characters are handled. With [`NulHandling::Control`], they
+    /// are handled by `on_control`.
+    pub nul: NulHandling,
+    /// Space-like characters that are handled by `on_control` (which
+    /// represents them as `<XXXX>` as alternative text by default) instead
+    /// of being kept as is.
+    pub space_like: SpaceLikeChars,
+    /// Whether tabs are kept as `\t` in the rendered text instead of being
+    /// replaced with the expansion returned by `on_control`, whose width is
+    /// still used as the width of the tab.
+    ///
+    /// This is useful when the consumer expands tabs on its own (e.g., an
+    /// HTML renderer with the `tab-size` CSS property).
+    pub keep_tabs: bool,
+}
+
+/// Options of [`SourceSnippet::build_from_latin1_ex_with_options`] and of
+/// the builders of other single-byte sources, such as
+/// [`SourceSnippet::build_from_code_page_ex`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Latin1Options {
+    /// How NUL characters are handled. With [`NulHandling::Control`], they
+    /// are handled by `on_control`.
+    pub nul: NulHandling,
+}
+
+/// Space-like characters that are shown as placeholders when building a
+/// snippet, since they are invisible or indistinguishable from a regular
+/// space in the rendered text (see [`Utf8Options::space_like`]).
+///
+/// Enabled characters are handled like control characters.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpaceLikeChars {
+    /// No-break space (U+00A0).
+    pub nbsp: bool,
+    /// Zero width space (U+200B).
+    pub zero_width_space: bool,
+    /// Soft hyphen (U+00AD).
+    pub soft_hyphen: bool,
+}
+
+impl SpaceLikeChars {
+    /// Shows none of the characters.
+    pub const NONE: Self = Self {
+        nbsp: false,
+        zero_width_space: false,
+        soft_hyphen: false,
+    };
+
+    /// Shows all of the characters.
+    pub const ALL: Self = Self {
+        nbsp: true,
+        zero_width_space: true,
+        soft_hyphen: true,
+    };
+
+    /// Returns whether `chr` is shown as a placeholder.
+    pub fn contains(&self, chr: char) -> bool {
+        match chr {
+            '\u{A0}' => self.nbsp,
+            '\u{200B}' => self.zero_width_space,
+            '\u{AD}' => self.soft_hyphen,
+            _ => false,
+        }
+    }
+}
+
+/// A builder of [`SourceSnippet`] for custom sources.
+///
+/// The source is described as a sequence of *units* (e.g., bytes, UTF-16
+/// words or tokens). Annotation spans are expressed as ranges of these
+/// units. Each element pushed to the builder (a character, a text or a line
+/// break) corresponds to one or more consecutive source units:
+///
+/// * [`push_char`](Self::push_char) and [`push_text`](Self::push_text) add
+///   text to the current line, corresponding to `orig_len` units.
+/// * [`push_line_break`](Self::push_line_break) ends the current line,
+///   where the line break corresponds to `orig_len` units.
+///
+/// Spans that start or end in the middle of an element are extended to
+/// cover the whole element.
+///
+/// # Example
+///
+/// ```
+/// // A source made of two tokens per line
+/// let mut builder = sourceannot::SourceSnippet::builder(1);
+/// builder.push_text("let", 1, false);
+/// builder.push_text(" x", 1, false);
+/// builder.push_line_break(1);
+/// builder.push_text("x", 1, false);
+/// builder.push_text(" = 1", 1, false);
+/// let snippet = builder.finish();
+///
+/// assert_eq!(snippet.get_line_col(3), (1, 0));
+/// assert_eq!(snippet.get_line_col(4), (1, 1));
+/// ```
+#[derive(Clone, Debug)]
+pub struct SourceSnippetBuilder {
     start_line: usize,
+    unit_kind: UnitKind,
     lines: Vec<SourceLine>,
     line_map: Vec<usize>,
     metas: Vec<SourceUnitMeta>,
+    large_widths: BTreeMap<usize, usize>,
+    large_utf8_lens: BTreeMap<usize, usize>,
     current_line_text: String,
     current_line_alts: RangeSet<usize>,
     current_line_width: usize,
 }
 
+impl SourceSnippet {
+    /// Creates a builder to create a snippet from a custom source.
+    ///
+    /// `start_line` is the line number of the first line of the snippet.
+    /// The kind of its units is [`UnitKind::Other`] unless set with
+    /// [`set_unit_kind`](SourceSnippetBuilder::set_unit_kind).
+    #[inline]
+    pub fn builder(start_line: usize) -> SourceSnippetBuilder {
+        SourceSnippetBuilder::new(start_line, UnitKind::Other)
+    }
+}
+
 impl SourceSnippetBuilder {
-    fn new(start_line: usize) -> Self {
+    fn new(start_line: usize, unit_kind: UnitKind) -> Self {
         Self {
             start_line,
+            unit_kind,
             lines: Vec::new(),
             line_map: Vec::new(),
             metas: Vec::new(),
+            large_widths: BTreeMap::new(),
+            large_utf8_lens: BTreeMap::new(),
             current_line_text: String::new(),
             current_line_alts: RangeSet::new(),
             current_line_width: 0,
         }
     }
 
-    fn finish(mut self) -> SourceSnippet {
+    /// Finishes the current line and builds the snippet, checking that it
+    /// spans exactly `source_len` units.
+    pub fn try_finish(self, source_len: usize) -> Result<SourceSnippet, Error> {
+        if self.metas.len() != source_len {
+            return Err(Error::UnitCountMismatch {
+                expected: source_len,
+                actual: self.metas.len(),
+            });
+        }
+        Ok(self.finish())
+    }
+
+    /// Finishes the current line and builds the snippet.
+    pub fn finish(mut self) -> SourceSnippet {
         self.lines.push(SourceLine {
             text: self.current_line_text.into_boxed_str(),
             alts: self.current_line_alts,
@@ -39,24 +207,65 @@ impl SourceSnippetBuilder {
 
         SourceSnippet {
             start_line: self.start_line,
+            unit_kind: self.unit_kind,
             lines: self.lines,
             line_map: self.line_map,
             metas: self.metas,
+            large_widths: self.large_widths,
+            large_utf8_lens: self.large_utf8_lens,
         }
     }
 
-    fn next_line(&mut self, extra_widths: &[usize]) {
+    /// Sets the kind of the source units of the snippet (see
+    /// [`SourceSnippet::unit_kind`]).
+    #[inline]
+    pub fn set_unit_kind(&mut self, unit_kind: UnitKind) {
+        self.unit_kind = unit_kind;
+    }
+
+    /// Ends the current line with a line break that corresponds to
+    /// `orig_len` source units.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `orig_len` is zero.
+    pub fn push_line_break(&mut self, orig_len: usize) {
+        assert!(orig_len != 0, "a line break must span at least one unit");
         self.lines.push(SourceLine {
             text: core::mem::take(&mut self.current_line_text).into_boxed_str(),
             alts: core::mem::take(&mut self.current_line_alts),
             width: core::mem::replace(&mut self.current_line_width, 0),
         });
+        // Each unit of the line break is treated as a column after the end
+        // of the line.
         self.metas
-            .extend(extra_widths.iter().map(|&w| SourceUnitMeta::new(w, 0)));
+            .extend(core::iter::repeat(SourceUnitMeta::new(1, 0)).take(orig_len));
         self.line_map.push(self.metas.len());
     }
 
-    fn push_text(&mut self, text: &str, orig_len: usize, alt: bool) {
+    /// Adds `text` to the current line, corresponding to `orig_len` source
+    /// units.
+    ///
+    /// The width of `text` is computed from its characters. `text` must not
+    /// contain control characters (including line breaks), otherwise the
+    /// layout of the snippet will be corrupted. If `alt` is `true`, it is
+    /// rendered as alternative text.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `orig_len` is zero. In debug builds, also panics if `text`
+    /// contains control characters.
+    pub fn push_text(&mut self, text: &str, orig_len: usize, alt: bool) {
+        debug_assert!(
+            !text.chars().any(char::is_control),
+            "text contains control characters",
+        );
+        let width = crate::width::str_width(text);
+        self.push_text_with_width(text, width, orig_len, alt);
+    }
+
+    fn push_text_with_width(&mut self, text: &str, width: usize, orig_len: usize, alt: bool) {
+        assert!(orig_len != 0, "a text must span at least one unit");
         let old_line_len = self.current_line_text.len();
         self.current_line_text.push_str(text);
         let new_line_len = self.current_line_text.len();
@@ -66,10 +275,23 @@ impl SourceSnippetBuilder {
                 .insert(old_line_len..=(new_line_len - 1));
         }
 
-        let width = unicode_width::UnicodeWidthStr::width(text);
         self.current_line_width += width;
 
-        self.metas.push(SourceUnitMeta::new(width, text.len()));
+        self.push_meta(width, text.len(), orig_len);
+    }
+
+    /// Pushes the metas of an element, storing large values in the side
+    /// tables.
+    fn push_meta(&mut self, width: usize, utf8_len: usize, orig_len: usize) {
+        let meta_i = self.metas.len();
+        if width >= SourceUnitMeta::LARGE {
+            self.large_widths.insert(meta_i, width);
+        }
+        if utf8_len >= SourceUnitMeta::LARGE {
+            self.large_utf8_lens.insert(meta_i, utf8_len);
+        }
+
+        self.metas.push(SourceUnitMeta::new(width, utf8_len));
         for _ in 1..orig_len {
             // Each element of `snippet.widths` corresponds to a byte in `source`,
             // so fill with -1 for multi-unit chunks.
@@ -77,7 +299,79 @@ impl SourceSnippetBuilder {
         }
     }
 
-    fn push_char(&mut self, chr: char, width: usize, orig_len: usize, alt: bool) {
+    /// Adds a sequence of texts to the current line.
+    ///
+    /// Each item is `(text, width, orig_len, alt)`, where `width` is the
+    /// display width of `text`, `orig_len` is the number of source units it
+    /// corresponds to and `alt` indicates whether it is rendered as
+    /// alternative text.
+    ///
+    /// Unlike [`push_text`](Self::push_text), items are validated and an
+    /// error is returned for the first invalid one, in which case the
+    /// following items are not added.
+    pub fn push_units<'t, I>(&mut self, units: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (&'t str, usize, usize, bool)>,
+    {
+        for (text, width, orig_len, alt) in units {
+            check_element(text, orig_len)?;
+            self.push_text_with_width(text, width, orig_len, alt);
+        }
+        Ok(())
+    }
+
+    /// Like [`push_line_break`](Self::push_line_break), but returns an
+    /// error instead of panicking.
+    pub fn try_push_line_break(&mut self, orig_len: usize) -> Result<(), Error> {
+        if orig_len == 0 {
+            return Err(Error::ZeroLengthUnit);
+        }
+        self.push_line_break(orig_len);
+        Ok(())
+    }
+
+    /// Like [`push_text`](Self::push_text), but returns an error instead of
+    /// panicking, including when `text` contains control characters.
+    pub fn try_push_text(&mut self, text: &str, orig_len: usize, alt: bool) -> Result<(), Error> {
+        let width = crate::width::str_width(text);
+        check_element(text, orig_len)?;
+        self.push_text_with_width(text, width, orig_len, alt);
+        Ok(())
+    }
+
+    /// Like [`push_char`](Self::push_char), but returns an error instead of
+    /// panicking, including when `chr` is a control character.
+    pub fn try_push_char(
+        &mut self,
+        chr: char,
+        width: usize,
+        orig_len: usize,
+        alt: bool,
+    ) -> Result<(), Error> {
+        check_element(chr.encode_utf8(&mut [0; 4]), orig_len)?;
+        self.push_char(chr, width, orig_len, alt);
+        Ok(())
+    }
+
+    /// Like [`push_tab`](Self::push_tab), but returns an error instead of
+    /// panicking, including when `text` contains control characters.
+    pub fn try_push_tab(&mut self, text: &str, orig_len: usize, alt: bool) -> Result<(), Error> {
+        check_element(text, orig_len)?;
+        self.push_tab(text, orig_len, alt);
+        Ok(())
+    }
+
+    /// Adds `chr` to the current line with an explicit display `width`,
+    /// corresponding to `orig_len` source units.
+    ///
+    /// `chr` must not be a control character (including line breaks). If
+    /// `alt` is `true`, it is rendered as alternative text.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `orig_len` is zero.
+    pub fn push_char(&mut self, chr: char, width: usize, orig_len: usize, alt: bool) {
+        assert!(orig_len != 0, "a character must span at least one unit");
         let old_line_len = self.current_line_text.len();
         self.current_line_text.push(chr);
         let new_line_len = self.current_line_text.len();
@@ -88,11 +382,176 @@ impl SourceSnippetBuilder {
                 .insert(old_line_len..=(new_line_len - 1));
         }
 
-        self.metas.push(SourceUnitMeta::new(width, chr.len_utf8()));
-        for _ in 1..orig_len {
-            // Each element of `snippet.widths` corresponds to a byte in `source`,
-            // so fill with -1 for multi-unit characters.
-            self.metas.push(SourceUnitMeta::extra());
+        self.push_meta(width, chr.len_utf8(), orig_len);
+    }
+
+    /// Adds an expanded tab to the current line, where `text` is the
+    /// expansion, corresponding to `orig_len` source units.
+    ///
+    /// This behaves like [`push_text`](Self::push_text), but allows
+    /// annotations to be anchored inside the expansion (see
+    /// [`TabAnchor`](crate::TabAnchor)).
+    ///
+    /// # Panics
+    ///
+    /// Same as [`push_text`](Self::push_text).
+    pub fn push_tab(&mut self, text: &str, orig_len: usize, alt: bool) {
+        let meta_i = self.metas.len();
+        self.push_text(text, orig_len, alt);
+        self.metas[meta_i].set_tab();
+    }
+
+    /// Adds a tab to the current line that is kept as `\t` in the rendered
+    /// text, with a display width of `width`, corresponding to `orig_len`
+    /// source units.
+    ///
+    /// Unlike [`push_tab`](Self::push_tab), the tab is not expanded, which
+    /// is useful when the rendered text is expanded by its consumer (e.g.,
+    /// an HTML renderer). It is otherwise handled like an expanded tab.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `orig_len` is zero.
+    pub fn push_kept_tab(&mut self, width: usize, orig_len: usize) {
+        let meta_i = self.metas.len();
+        self.push_text_with_width("\t", width, orig_len, false);
+        self.metas[meta_i].set_tab();
+    }
+
+    /// Pushes an element with already known properties, e.g., one copied
+    /// from another snippet.
+    pub(in crate::snippet) fn push_element(
+        &mut self,
+        text: &str,
+        width: usize,
+        orig_len: usize,
+        alt: bool,
+        tab: bool,
+    ) {
+        let meta_i = self.metas.len();
+        self.push_text_with_width(text, width, orig_len, alt);
+        if tab {
+            self.metas[meta_i].set_tab();
+        }
+    }
+
+    /// Pushes a NUL character that is not handled as a control character.
+    fn push_nul(&mut self, nul: NulHandling, orig_len: usize) {
+        match nul {
+            NulHandling::Control => unreachable!(),
+            NulHandling::Glyph => self.push_char('\u{2400}', 1, orig_len, true),
+            NulHandling::Strip => self.push_text("", orig_len, false),
+        }
+    }
+}
+
+/// Checks that an element can be pushed to a builder.
+fn check_element(text: &str, orig_len: usize) -> Result<(), Error> {
+    if orig_len == 0 {
+        return Err(Error::ZeroLengthUnit);
+    }
+    if let Some(chr) = text.chars().find(|chr| chr.is_control()) {
+        return Err(Error::ControlCharInText(chr));
+    }
+    Ok(())
+}
+
+/// Checks the output of a callback of a builder (such as `on_control`),
+/// storing the first error in `error`.
+///
+/// Invalid texts are replaced with an empty text, so the snippet can be
+/// built anyway and then discarded.
+fn check_callback_output(error: &mut Option<Error>, output: (bool, String)) -> (bool, String) {
+    let (alt, text) = output;
+    match check_element(&text, 1) {
+        Ok(()) => (alt, text),
+        Err(e) => {
+            error.get_or_insert(e);
+            (false, String::new())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, SourceSnippet};
+
+    #[test]
+    fn test_push_units() {
+        let mut builder = SourceSnippet::builder(0);
+        builder
+            .push_units([("ab", 2, 1, false), ("<X>", 3, 2, true)])
+            .unwrap();
+        builder.push_line_break(1);
+        builder.push_units([("c", 1, 1, false)]).unwrap();
+        let snippet = builder.try_finish(5).unwrap();
+
+        assert_eq!(snippet.get_line_col(1), (0, 2));
+        assert_eq!(snippet.get_line_col(3), (0, 5));
+        assert_eq!(snippet.get_line_col(4), (1, 0));
+    }
+
+    #[test]
+    fn test_push_units_invalid() {
+        let mut builder = SourceSnippet::builder(0);
+        assert_eq!(
+            builder.push_units([("a", 1, 0, false)]),
+            Err(Error::ZeroLengthUnit),
+        );
+        assert_eq!(
+            builder.push_units([("a\nb", 3, 1, false)]),
+            Err(Error::ControlCharInText('\n')),
+        );
+    }
+
+    #[test]
+    fn test_try_finish_mismatch() {
+        let mut builder = SourceSnippet::builder(0);
+        builder.push_units([("ab", 2, 2, false)]).unwrap();
+        assert_eq!(
+            builder.try_finish(3).unwrap_err(),
+            Error::UnitCountMismatch {
+                expected: 3,
+                actual: 2,
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "text contains control characters")]
+    fn test_push_text_control_char() {
+        let mut builder = SourceSnippet::builder(0);
+        builder.push_text("a\nb", 1, false);
+    }
+
+    #[test]
+    fn test_try_push() {
+        let mut builder = SourceSnippet::builder(0);
+        assert_eq!(
+            builder.try_push_text("a", 0, false),
+            Err(Error::ZeroLengthUnit)
+        );
+        assert_eq!(
+            builder.try_push_text("a\tb", 1, false),
+            Err(Error::ControlCharInText('\t')),
+        );
+        assert_eq!(
+            builder.try_push_tab(" \n", 1, false),
+            Err(Error::ControlCharInText('\n')),
+        );
+        assert_eq!(
+            builder.try_push_char('\u{7}', 1, 1, false),
+            Err(Error::ControlCharInText('\u{7}')),
+        );
+        assert_eq!(builder.try_push_line_break(0), Err(Error::ZeroLengthUnit));
+
+        builder.try_push_text("ab", 2, false).unwrap();
+        builder.try_push_line_break(1).unwrap();
+        builder.try_push_tab("  ", 1, false).unwrap();
+        builder.try_push_char('c', 1, 1, false).unwrap();
+        let snippet = builder.try_finish(5).unwrap();
+
+        assert_eq!(snippet.get_line_col(4), (1, 2));
+    }
+}
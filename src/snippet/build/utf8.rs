@@ -1,7 +1,9 @@
 use alloc::format;
 use alloc::string::String;
 
-use super::SourceSnippetBuilder;
+use super::{check_callback_output, Decoder, SourceSnippetBuilder, Utf8Options};
+use crate::snippet::UnitKind;
+use crate::Error;
 use crate::SourceSnippet;
 
 impl SourceSnippet {
@@ -13,9 +15,26 @@ impl SourceSnippet {
     /// `<XXXX>` as alternative text. Each byte of invalid UTF-8 sequences is
     /// represented as `<XX>` as alternative text.
     pub fn build_from_utf8(start_line: usize, source: &[u8], tab_width: usize) -> Self {
-        Self::build_from_utf8_ex(
+        Self::build_from_utf8_with_options(start_line, source, tab_width, Utf8Options::default())
+    }
+
+    /// Creates a snippet from a UTF-8 (possibly broken) source, like
+    /// [`build_from_utf8`](Self::build_from_utf8), with additional
+    /// `options`.
+    ///
+    /// Space-like characters selected by [`Utf8Options::space_like`] are
+    /// represented as `<XXXX>` as alternative text and tabs kept with
+    /// [`Utf8Options::keep_tabs`] are accounted as `tab_width` columns.
+    pub fn build_from_utf8_with_options(
+        start_line: usize,
+        source: &[u8],
+        tab_width: usize,
+        options: Utf8Options,
+    ) -> Self {
+        Self::build_from_utf8_impl(
             start_line,
             source,
+            options,
             |chr| {
                 if chr == '\t' {
                     (false, " ".repeat(tab_width))
@@ -37,9 +56,9 @@ impl SourceSnippet {
     ///
     /// "\n" and "\r\n" are treated as line breaks.
     ///
-    /// `on_control` is used to handle ASCII control characters (that are not
-    /// line breaks). `on_invalid` and `invalid_multi` are used to handle
-    /// invalid UTF-8 sequences.
+    /// `on_control` is used to handle control characters (that are not line
+    /// breaks). `on_invalid` and `invalid_multi` are used to handle invalid
+    /// UTF-8 sequences.
     ///
     /// When `invalid_multi` is `true`, `on_invalid` is called for each byte
     /// of an invalid UTF-8 sequence. Otherwise, `on_invalid` is called once
@@ -50,83 +69,177 @@ impl SourceSnippet {
     pub fn build_from_utf8_ex<FnCtrl, FnInv>(
         start_line: usize,
         source: &[u8],
+        on_control: FnCtrl,
+        on_invalid: FnInv,
+        invalid_multi: bool,
+    ) -> Self
+    where
+        FnCtrl: FnMut(char) -> (bool, String),
+        FnInv: FnMut(&[u8]) -> (bool, String),
+    {
+        Self::build_from_utf8_ex_with_options(
+            start_line,
+            source,
+            Utf8Options::default(),
+            on_control,
+            on_invalid,
+            invalid_multi,
+        )
+    }
+
+    /// Like [`build_from_utf8_ex`](Self::build_from_utf8_ex), with
+    /// additional `options`.
+    pub fn build_from_utf8_ex_with_options<FnCtrl, FnInv>(
+        start_line: usize,
+        source: &[u8],
+        options: Utf8Options,
+        on_control: FnCtrl,
+        on_invalid: FnInv,
+        invalid_multi: bool,
+    ) -> Self
+    where
+        FnCtrl: FnMut(char) -> (bool, String),
+        FnInv: FnMut(&[u8]) -> (bool, String),
+    {
+        Self::build_from_utf8_impl(
+            start_line,
+            source,
+            options,
+            on_control,
+            on_invalid,
+            invalid_multi,
+        )
+    }
+
+    /// Like [`build_from_utf8_ex`](Self::build_from_utf8_ex), but returns an
+    /// error if `on_control` or `on_invalid` return an invalid text (i.e.,
+    /// one with control characters), which would otherwise corrupt the layout
+    /// of the snippet.
+    pub fn try_build_from_utf8_ex<FnCtrl, FnInv>(
+        start_line: usize,
+        source: &[u8],
+        on_control: FnCtrl,
+        on_invalid: FnInv,
+        invalid_multi: bool,
+    ) -> Result<Self, Error>
+    where
+        FnCtrl: FnMut(char) -> (bool, String),
+        FnInv: FnMut(&[u8]) -> (bool, String),
+    {
+        Self::try_build_from_utf8_ex_with_options(
+            start_line,
+            source,
+            Utf8Options::default(),
+            on_control,
+            on_invalid,
+            invalid_multi,
+        )
+    }
+
+    /// Like [`build_from_utf8_ex_with_options`](Self::build_from_utf8_ex_with_options),
+    /// but returns an error if `on_control` or `on_invalid` return an invalid
+    /// text, like [`try_build_from_utf8_ex`](Self::try_build_from_utf8_ex).
+    pub fn try_build_from_utf8_ex_with_options<FnCtrl, FnInv>(
+        start_line: usize,
+        source: &[u8],
+        options: Utf8Options,
         mut on_control: FnCtrl,
         mut on_invalid: FnInv,
         invalid_multi: bool,
-    ) -> Self
+    ) -> Result<Self, Error>
     where
         FnCtrl: FnMut(char) -> (bool, String),
         FnInv: FnMut(&[u8]) -> (bool, String),
     {
-        let mut snippet = SourceSnippetBuilder::new(start_line);
-
-        let mut rem_source = source;
-        while !rem_source.is_empty() {
-            let valid_utf8;
-            let invalid_utf8: &[u8];
-            match core::str::from_utf8(rem_source) {
-                Ok(s) => {
-                    valid_utf8 = s;
-                    invalid_utf8 = b"";
-                    rem_source = b"";
-                }
-                Err(e) => {
-                    let (valid, after_valid) = rem_source.split_at(e.valid_up_to());
-                    let error_len = e.error_len().unwrap_or(after_valid.len());
-                    valid_utf8 = core::str::from_utf8(valid).unwrap();
-                    (invalid_utf8, rem_source) = after_valid.split_at(error_len);
-                }
-            }
-
-            let mut chars = valid_utf8.chars();
-            while let Some(chr) = chars.next() {
-                if chr == '\r' && chars.as_str().starts_with('\n') {
-                    snippet.next_line(&[1, 1]);
-                    chars.next().unwrap();
-                } else if chr == '\n' {
-                    snippet.next_line(&[1]);
-                } else {
-                    let chr_width =
-                        unicode_width::UnicodeWidthChar::width(chr).filter(|_| chr != '\0');
-
-                    if let Some(chr_width) = chr_width {
-                        snippet.push_char(chr, chr_width, chr.len_utf8(), false);
-                    } else {
-                        let (alt, text) = on_control(chr);
-                        snippet.push_text(&text, chr.len_utf8(), alt);
-                    }
-                }
-            }
-
-            if !invalid_utf8.is_empty() {
-                if invalid_multi {
-                    for &byte in invalid_utf8.iter() {
-                        let (alt, text) = on_invalid(&[byte]);
-                        snippet.push_text(&text, 1, alt);
-                    }
-                } else {
-                    let (alt, text) = on_invalid(invalid_utf8);
-                    snippet.push_text(&text, invalid_utf8.len(), alt);
-                }
-            }
+        let mut control_error = None;
+        let mut invalid_error = None;
+        let snippet = Self::build_from_utf8_ex_with_options(
+            start_line,
+            source,
+            options,
+            |chr| check_callback_output(&mut control_error, on_control(chr)),
+            |bytes| check_callback_output(&mut invalid_error, on_invalid(bytes)),
+            invalid_multi,
+        );
+        match control_error.or(invalid_error) {
+            Some(e) => Err(e),
+            None => Ok(snippet),
         }
+    }
 
+    fn build_from_utf8_impl<FnCtrl, FnInv>(
+        start_line: usize,
+        source: &[u8],
+        options: Utf8Options,
+        on_control: FnCtrl,
+        on_invalid: FnInv,
+        invalid_multi: bool,
+    ) -> Self
+    where
+        FnCtrl: FnMut(char) -> (bool, String),
+        FnInv: FnMut(&[u8]) -> (bool, String),
+    {
+        let mut snippet = SourceSnippetBuilder::new(start_line, UnitKind::Byte);
+        snippet.push_decoded(
+            source,
+            Utf8Decoder { invalid_multi },
+            crate::width::char_width,
+            options,
+            on_control,
+            on_invalid,
+        );
         snippet.finish()
     }
 }
 
+/// The [`Decoder`] of UTF-8 sources.
+struct Utf8Decoder {
+    // Whether each byte of an invalid sequence is decoded separately
+    invalid_multi: bool,
+}
+
+impl Decoder for Utf8Decoder {
+    fn decode(&mut self, source: &[u8]) -> (Option<char>, usize) {
+        // Length of the sequence announced by the first byte, so only that
+        // sequence is validated
+        let seq_len = match source[0] {
+            byte @ 0x00..=0x7F => return (Some(char::from(byte)), 1),
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => 1,
+        };
+        let seq = &source[..seq_len.min(source.len())];
+        match core::str::from_utf8(seq) {
+            Ok(s) => (s.chars().next(), seq.len()),
+            Err(_) if self.invalid_multi => (None, 1),
+            // A truncated sequence at the end of the source is invalid as a
+            // whole.
+            Err(e) => (None, e.error_len().unwrap_or(seq.len())),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use alloc::format;
     use alloc::string::String;
+    use alloc::vec::Vec;
 
     use crate::range_set::RangeSet;
-    use crate::snippet::{SourceLine, SourceSnippet, SourceUnitMeta};
+    use crate::snippet::{
+        NulHandling, SourceLine, SourceSnippet, SourceUnitMeta, SpaceLikeChars, Utf8Options,
+    };
+    use crate::Error;
 
     fn meta(width: usize, len: usize) -> SourceUnitMeta {
         SourceUnitMeta::new(width, len)
     }
 
+    fn meta_tab(width: usize, len: usize) -> SourceUnitMeta {
+        SourceUnitMeta::tab(width, len)
+    }
+
     fn meta_extra() -> SourceUnitMeta {
         SourceUnitMeta::extra()
     }
@@ -304,6 +417,177 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_nul_glyph() {
+        let source = b"1\x002";
+        let snippet = SourceSnippet::build_from_utf8_ex_with_options(
+            0,
+            source,
+            Utf8Options {
+                nul: NulHandling::Glyph,
+                ..Utf8Options::default()
+            },
+            |_| unreachable!(),
+            |_| unreachable!(),
+            false,
+        );
+
+        assert_eq!(
+            snippet.lines,
+            [SourceLine {
+                text: "1\u{2400}2".into(),
+                alts: RangeSet::from(1..=3),
+                width: 3,
+            }],
+        );
+        assert_eq!(snippet.metas, [meta(1, 1), meta(1, 3), meta(1, 1)]);
+    }
+
+    #[test]
+    fn test_nul_strip() {
+        let source = b"1\x002";
+        let snippet = SourceSnippet::build_from_utf8_ex_with_options(
+            0,
+            source,
+            Utf8Options {
+                nul: NulHandling::Strip,
+                ..Utf8Options::default()
+            },
+            |_| unreachable!(),
+            |_| unreachable!(),
+            false,
+        );
+
+        assert_eq!(
+            snippet.lines,
+            [SourceLine {
+                text: "12".into(),
+                alts: RangeSet::new(),
+                width: 2,
+            }],
+        );
+        assert_eq!(snippet.metas, [meta(1, 1), meta(0, 0), meta(1, 1)]);
+    }
+
+    #[test]
+    fn test_space_like() {
+        let source = "1\u{A0}2\u{200B}3\u{AD}4";
+        let snippet = SourceSnippet::build_from_utf8_with_options(
+            0,
+            source.as_bytes(),
+            4,
+            Utf8Options {
+                space_like: SpaceLikeChars {
+                    nbsp: false,
+                    zero_width_space: true,
+                    soft_hyphen: true,
+                },
+                ..Utf8Options::default()
+            },
+        );
+
+        assert_eq!(
+            snippet.lines,
+            [SourceLine {
+                text: "1\u{A0}2<200B>3<00AD>4".into(),
+                alts: [4..=9, 11..=16].into_iter().collect(),
+                width: 17,
+            }],
+        );
+    }
+
+    #[test]
+    fn test_space_like_ex() {
+        let source = "1\u{A0}2\x003";
+        let snippet = SourceSnippet::build_from_utf8_ex_with_options(
+            0,
+            source.as_bytes(),
+            Utf8Options {
+                nul: NulHandling::Strip,
+                space_like: SpaceLikeChars::ALL,
+                ..Utf8Options::default()
+            },
+            |chr| {
+                assert_eq!(chr, '\u{A0}');
+                (true, String::from("~"))
+            },
+            |_| unreachable!(),
+            false,
+        );
+
+        assert_eq!(
+            snippet.lines,
+            [SourceLine {
+                text: "1~23".into(),
+                alts: RangeSet::from(1..=1),
+                width: 4,
+            }],
+        );
+    }
+
+    #[test]
+    fn test_keep_tabs() {
+        let source = b"1\t2\n\t";
+        let snippet = SourceSnippet::build_from_utf8_with_options(
+            0,
+            source,
+            4,
+            Utf8Options {
+                keep_tabs: true,
+                ..Utf8Options::default()
+            },
+        );
+
+        assert_eq!(
+            snippet.lines,
+            [
+                SourceLine {
+                    text: "1\t2".into(),
+                    alts: RangeSet::new(),
+                    width: 6,
+                },
+                SourceLine {
+                    text: "\t".into(),
+                    alts: RangeSet::new(),
+                    width: 4,
+                },
+            ],
+        );
+        assert_eq!(
+            snippet.metas,
+            [
+                meta(1, 1),
+                meta_tab(4, 1),
+                meta(1, 1),
+                meta(1, 0),
+                meta_tab(4, 1),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_try_build() {
+        let snippet = SourceSnippet::try_build_from_utf8_ex(
+            0,
+            b"1\t2\xFF",
+            |_| (false, " ".into()),
+            |_| (true, "?".into()),
+            false,
+        )
+        .unwrap();
+        assert_eq!(&*snippet.lines[0].text, "1 2?");
+
+        let error = SourceSnippet::try_build_from_utf8_ex(
+            0,
+            b"1\t2\xFF",
+            |_| (false, " ".repeat(200)),
+            |_| (true, "\n".into()),
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(error, Error::ControlCharInText('\n'));
+    }
+
     #[test]
     fn test_crlf() {
         let source = b"123\r\n4\r6\r\n";
@@ -355,6 +639,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "unicode-width")]
     #[test]
     fn test_fullwidth() {
         let source = b"1\xEF\xBC\x923\n456";
@@ -399,6 +684,51 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "unicode-width"))]
+    #[test]
+    fn test_fullwidth_fallback() {
+        let source = b"1\xEF\xBC\x923\n456";
+        let snippet = SourceSnippet::build_from_utf8_ex(
+            0,
+            source,
+            |_| unreachable!(),
+            |_| unreachable!(),
+            false,
+        );
+
+        assert_eq!(snippet.start_line, 0);
+        assert_eq!(
+            snippet.lines,
+            [
+                SourceLine {
+                    text: "1\u{FF12}3".into(),
+                    alts: RangeSet::new(),
+                    width: 3,
+                },
+                SourceLine {
+                    text: "456".into(),
+                    alts: RangeSet::new(),
+                    width: 3,
+                },
+            ],
+        );
+        assert_eq!(snippet.line_map, [6]);
+        assert_eq!(
+            snippet.metas,
+            [
+                meta(1, 1),
+                meta(1, 3),
+                meta_extra(),
+                meta_extra(),
+                meta(1, 1),
+                meta(1, 0),
+                meta(1, 1),
+                meta(1, 1),
+                meta(1, 1),
+            ],
+        );
+    }
+
     #[test]
     fn test_tabs() {
         let source = b"123\n\t456";
@@ -429,7 +759,7 @@ mod tests {
                 meta(1, 1),
                 meta(1, 1),
                 meta(1, 0),
-                meta(4, 4),
+                meta_tab(4, 4),
                 meta(1, 1),
                 meta(1, 1),
                 meta(1, 1),
@@ -534,4 +864,30 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn test_invalid_sequences() {
+        // An invalid continuation byte, a lone continuation byte and a
+        // sequence truncated by the end of the source
+        let source = b"\xE0\x80a\xBFb\xE2\x82";
+        let mut sequences = Vec::new();
+        let snippet = SourceSnippet::build_from_utf8_ex(
+            0,
+            source,
+            |_| unreachable!(),
+            |bytes| {
+                sequences.push(bytes.to_vec());
+                (true, String::from("?"))
+            },
+            false,
+        );
+
+        assert_eq!(
+            sequences,
+            [&b"\xE0"[..], &b"\x80"[..], &b"\xBF"[..], &b"\xE2\x82"[..]],
+        );
+        assert_eq!(&*snippet.lines[0].text, "??a?b?");
+        assert_eq!(snippet.get_line_col(5), (0, 5));
+        assert_eq!(snippet.num_units(), source.len());
+    }
 }
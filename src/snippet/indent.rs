@@ -0,0 +1,171 @@
+/// Indentation settings of a source, such as the ones configured in an
+/// `.editorconfig` file (`indent_style` and `tab_width`).
+///
+/// Use [`tab_width`](Self::tab_width) as the tab width of the snippet
+/// builders, so rendered columns match the editor of the author.
+///
+/// # Example
+///
+/// ```
+/// use sourceannot::{IndentSettings, SourceSnippet};
+///
+/// let source = b"fn main() {\n  if x {\n    y();\n  }\n}\n";
+///
+/// // Settings from an `.editorconfig` file, if any, take precedence over
+/// // the ones detected from the source.
+/// let editorconfig = None;
+/// let indent = IndentSettings::resolve(editorconfig, source);
+/// assert_eq!(indent.tab_width, 2);
+///
+/// let snippet = SourceSnippet::build_from_utf8(1, source, indent.tab_width);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IndentSettings {
+    /// Display width of a tab.
+    pub tab_width: usize,
+
+    /// Whether lines are indented with tabs instead of spaces.
+    pub use_tabs: bool,
+}
+
+impl Default for IndentSettings {
+    /// Returns the settings used when none are given or detected: 4 columns
+    /// per tab and indentation with spaces.
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            use_tabs: false,
+        }
+    }
+}
+
+impl IndentSettings {
+    /// Returns `settings` if it is `Some`, or the settings detected from
+    /// `source` (see [`detect`](Self::detect)), falling back to the
+    /// [default](Self::default) ones.
+    pub fn resolve(settings: Option<Self>, source: &[u8]) -> Self {
+        settings
+            .or_else(|| Self::detect(source))
+            .unwrap_or_default()
+    }
+
+    /// Detects the indentation settings from the leading whitespace of the
+    /// lines of `source`, which can be in any ASCII-compatible encoding.
+    ///
+    /// Lines are considered indented with tabs if most indented lines start
+    /// with a tab. The tab width is the most common indentation step between
+    /// consecutive lines indented with spaces (up to 8 columns), or the
+    /// default one if there is none. Returns `None` if no line is indented.
+    pub fn detect(source: &[u8]) -> Option<Self> {
+        let mut tab_lines = 0usize;
+        let mut space_lines = 0usize;
+        // Number of occurrences of each indentation step, from 1 to 8
+        let mut steps = [0usize; 8];
+        let mut prev_indent = 0;
+
+        for line in source.split(|&byte| byte == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.iter().all(|&byte| byte == b' ' || byte == b'\t') {
+                // Blank lines do not tell anything
+                continue;
+            }
+            let indent = line.iter().take_while(|&&byte| byte == b' ').count();
+            if line[indent] == b'\t' {
+                if indent == 0 {
+                    tab_lines += 1;
+                }
+                // Mixed indentation does not have a reliable width
+                prev_indent = 0;
+                continue;
+            }
+            if indent != 0 {
+                space_lines += 1;
+            }
+            let step = indent.abs_diff(prev_indent);
+            if let Some(count) = step.checked_sub(1).and_then(|i| steps.get_mut(i)) {
+                *count += 1;
+            }
+            prev_indent = indent;
+        }
+
+        if tab_lines == 0 && space_lines == 0 {
+            return None;
+        }
+
+        // Ties are resolved in favor of the smallest step
+        let mut tab_width = Self::default().tab_width;
+        let mut max_count = 0;
+        for (i, &count) in steps.iter().enumerate() {
+            if count > max_count {
+                max_count = count;
+                tab_width = i + 1;
+            }
+        }
+
+        Some(Self {
+            tab_width,
+            use_tabs: tab_lines > space_lines,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndentSettings;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(IndentSettings::detect(b""), None);
+        assert_eq!(IndentSettings::detect(b"a\n\n  \nb\n"), None);
+        assert_eq!(
+            IndentSettings::detect(b"a {\n  b {\n    c\n  }\n}\n"),
+            Some(IndentSettings {
+                tab_width: 2,
+                use_tabs: false,
+            }),
+        );
+        assert_eq!(
+            IndentSettings::detect(b"a:\r\n    b:\r\n        c\r\n\r\n    d\r\n"),
+            Some(IndentSettings {
+                tab_width: 4,
+                use_tabs: false,
+            }),
+        );
+        assert_eq!(
+            IndentSettings::detect(b"a {\n\tb {\n\t\tc\n\t}\n}\n"),
+            Some(IndentSettings {
+                tab_width: 4,
+                use_tabs: true,
+            }),
+        );
+        // Mixed indentation does not count
+        assert_eq!(
+            IndentSettings::detect(b"a {\n   b(c,\n  \td)\n}\n"),
+            Some(IndentSettings {
+                tab_width: 3,
+                use_tabs: false,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let source = b"a {\n  b\n}\n";
+        let explicit = IndentSettings {
+            tab_width: 8,
+            use_tabs: true,
+        };
+        assert_eq!(IndentSettings::resolve(Some(explicit), source), explicit);
+        assert_eq!(
+            IndentSettings::resolve(None, source),
+            IndentSettings {
+                tab_width: 2,
+                use_tabs: false,
+            },
+        );
+        assert_eq!(
+            IndentSettings::resolve(None, b"a\n"),
+            IndentSettings::default(),
+        );
+    }
+}
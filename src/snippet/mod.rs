@@ -1,7 +1,17 @@
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 
 mod build;
+mod indent;
+mod replace;
+mod snapshot;
+
+pub use build::{
+    Decoder, Latin1Options, NulHandling, SourceSnippetBuilder, SpaceLikeChars, Utf8Options,
+};
+pub use indent::IndentSettings;
+pub use replace::ReplacementMap;
 
 use crate::range_set::RangeSet;
 
@@ -9,9 +19,63 @@ use crate::range_set::RangeSet;
 #[derive(Clone, Debug)]
 pub struct SourceSnippet {
     start_line: usize,
+    unit_kind: UnitKind,
     lines: Vec<SourceLine>,
     line_map: Vec<usize>,
     metas: Vec<SourceUnitMeta>,
+    // Widths and UTF-8 lengths that do not fit in `SourceUnitMeta`, by
+    // unit index
+    large_widths: BTreeMap<usize, usize>,
+    large_utf8_lens: BTreeMap<usize, usize>,
+}
+
+/// The kind of the source units of a snippet, i.e., what annotation spans
+/// count.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum UnitKind {
+    /// Bytes (e.g., of UTF-8 or Latin-1 sources).
+    Byte,
+    /// UTF-16 code units.
+    Utf16Word,
+    /// Characters (i.e., Unicode scalar values).
+    Char,
+    /// Tokens of a lexer.
+    Token,
+    /// Any other kind of unit.
+    Other,
+}
+
+/// How a span was adjusted to be used in an annotation, as returned by
+/// [`SourceSnippet::normalize_span`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpanNormalization {
+    /// The span of source units that is actually annotated.
+    pub span: core::ops::Range<usize>,
+
+    /// Whether the end of the span was before its start, so the span was
+    /// collapsed to zero length at its start.
+    pub collapsed: bool,
+
+    /// Whether the start was moved back to the start of the element (e.g.,
+    /// a multi-byte character) it was in the middle of.
+    pub start_snapped: bool,
+
+    /// Whether the end was moved forward to the end of the element (e.g.,
+    /// a multi-byte character) it was in the middle of.
+    pub end_snapped: bool,
+
+    /// Whether the span went past the end of the source and was clamped to
+    /// it.
+    pub clamped: bool,
+}
+
+impl SpanNormalization {
+    /// Returns whether the span was used as is.
+    #[inline]
+    pub fn is_exact(&self) -> bool {
+        !(self.collapsed || self.start_snapped || self.end_snapped || self.clamped)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -34,31 +98,56 @@ impl core::fmt::Debug for SourceUnitMeta {
             f.debug_struct("SourceUnitMeta")
                 .field("width", &self.width())
                 .field("utf8_len", &self.utf8_len())
+                .field("is_tab", &self.is_tab())
                 .finish()
         }
     }
 }
 
 impl SourceUnitMeta {
+    /// Width or UTF-8 length that indicates that the actual value is
+    /// stored in a side table of the snippet.
+    const LARGE: usize = 0x7F;
+
     #[inline]
     fn extra() -> Self {
         Self { inner: 0x8000 }
     }
 
+    /// Creates a meta, where values greater than or equal to
+    /// [`LARGE`](Self::LARGE) are stored as `LARGE`.
     #[inline]
     fn new(width: usize, utf8_len: usize) -> Self {
-        assert!(width <= 0x7F);
-        assert!(utf8_len <= 0x7F);
+        let width = width.min(Self::LARGE);
+        let utf8_len = utf8_len.min(Self::LARGE);
         Self {
             inner: (width as u16) | ((utf8_len as u16) << 7),
         }
     }
 
+    #[cfg(test)]
+    #[inline]
+    fn tab(width: usize, utf8_len: usize) -> Self {
+        let mut meta = Self::new(width, utf8_len);
+        meta.set_tab();
+        meta
+    }
+
+    #[inline]
+    fn set_tab(&mut self) {
+        self.inner |= 0x4000;
+    }
+
     #[inline]
     fn is_extra(&self) -> bool {
         self.inner & 0x8000 != 0
     }
 
+    #[inline]
+    fn is_tab(&self) -> bool {
+        self.inner & 0xC000 == 0x4000
+    }
+
     #[inline]
     fn width(&self) -> usize {
         usize::from(self.inner & 0x7F)
@@ -91,36 +180,170 @@ impl SourceSnippet {
         } else {
             self.line_map[line - 1]
         };
-        let col = self.metas[line_start..pos]
-            .iter()
-            .map(SourceUnitMeta::width)
-            .sum();
+        let col = (line_start..pos).map(|unit| self.unit_width(unit)).sum();
 
         (line, col)
     }
 
+    /// Returns the display width of the line `line_i` (within the snippet,
+    /// i.e., not offset by the start line), excluding the line break.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `line_i` is out of bounds.
+    #[inline]
+    pub fn line_width(&self, line_i: usize) -> usize {
+        self.lines[line_i].width
+    }
+
+    /// Returns the display width of the widest line of the snippet.
+    pub fn max_line_width(&self) -> usize {
+        self.lines.iter().map(|line| line.width).max().unwrap_or(0)
+    }
+
+    /// Returns the kind of the source units of the snippet.
+    ///
+    /// Snippets created from bytes (e.g., with
+    /// [`build_from_utf8`](Self::build_from_utf8)) count bytes, snippets
+    /// created with [`build_from_chars`](Self::build_from_chars) count
+    /// characters and snippets created with a builder count the kind set
+    /// with [`SourceSnippetBuilder::set_unit_kind`].
+    #[inline]
+    pub fn unit_kind(&self) -> UnitKind {
+        self.unit_kind
+    }
+
+    /// Marks the text of the source units in `units` as alternative text,
+    /// in addition to the alternative text marked by the builder.
+    ///
+    /// This allows flagging regions (e.g., found to be suspicious by a
+    /// lexer) without a custom builder. Elements that span multiple units
+    /// are marked as a whole if any of their units is in `units`, and units
+    /// out of bounds are ignored.
+    pub fn add_alt_units(&mut self, units: &RangeSet<usize>) {
+        let num_units = self.num_units();
+        for range in units.ranges() {
+            let start = *range.start();
+            let end = range.end().saturating_add(1).min(num_units);
+            if start >= end {
+                continue;
+            }
+            let first_line_i = self.get_line_col(start).0;
+            let last_line_i = self.get_line_col(end - 1).0;
+            for line_i in first_line_i..=last_line_i {
+                let mut utf8_pos = 0;
+                let mut element = 0..0;
+                for unit in self.line_start(line_i)..self.line_start(line_i + 1) {
+                    if !self.metas[unit].is_extra() {
+                        let utf8_len = self.unit_utf8_len(unit);
+                        element = utf8_pos..(utf8_pos + utf8_len);
+                        utf8_pos += utf8_len;
+                    }
+                    if (start..end).contains(&unit) && !element.is_empty() {
+                        self.lines[line_i]
+                            .alts
+                            .insert(element.start..=(element.end - 1));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the display width of the element that starts at `unit`.
+    #[inline]
+    fn unit_width(&self, unit: usize) -> usize {
+        let width = self.metas[unit].width();
+        if width == SourceUnitMeta::LARGE {
+            self.large_widths[&unit]
+        } else {
+            width
+        }
+    }
+
+    /// Returns the UTF-8 length of the text of the element that starts at
+    /// `unit`.
+    #[inline]
+    fn unit_utf8_len(&self, unit: usize) -> usize {
+        let utf8_len = self.metas[unit].utf8_len();
+        if utf8_len == SourceUnitMeta::LARGE {
+            self.large_utf8_lens[&unit]
+        } else {
+            utf8_len
+        }
+    }
+
     #[inline]
     pub(crate) fn start_line(&self) -> usize {
         self.start_line
     }
 
+    #[inline]
+    pub(crate) fn num_lines(&self) -> usize {
+        self.lines.len()
+    }
+
+    #[inline]
+    pub(crate) fn num_units(&self) -> usize {
+        self.metas.len()
+    }
+
+    /// Returns the index of the first unit of a line, or the total number
+    /// of units if `line_i` is the number of lines.
+    #[inline]
+    pub(crate) fn line_start(&self, line_i: usize) -> usize {
+        if line_i == 0 {
+            0
+        } else if line_i == self.lines.len() {
+            self.metas.len()
+        } else {
+            self.line_map[line_i - 1]
+        }
+    }
+
     #[inline]
     pub(crate) fn line(&self, i: usize) -> &SourceLine {
         &self.lines[i]
     }
 
-    pub(crate) fn convert_span(&self, mut start: usize, mut end: usize) -> SourceSpan {
+    /// Returns the span of source units that is actually annotated when
+    /// `span` is used in an annotation, and how it was adjusted.
+    ///
+    /// Spans are adjusted so they contain whole elements (e.g., a span that
+    /// starts or ends in the middle of a multi-byte character) and do not
+    /// go past the end of the source. This does not take into account the
+    /// window of lines of [`Annotations::for_lines`](crate::Annotations::for_lines).
+    pub fn normalize_span(&self, span: core::ops::Range<usize>) -> SpanNormalization {
+        let mut start = span.start;
+        let mut end = span.end;
+        let collapsed = end < start;
         end = end.max(start);
 
+        let mut start_snapped = false;
         while self.metas.get(start).is_some_and(SourceUnitMeta::is_extra) {
             start -= 1;
+            start_snapped = true;
         }
+        let mut end_snapped = false;
         while self.metas.get(end).is_some_and(SourceUnitMeta::is_extra) {
             end += 1;
+            end_snapped = true;
         }
+        let clamped = end > self.metas.len();
         start = start.min(self.metas.len());
         end = end.min(self.metas.len());
 
+        SpanNormalization {
+            span: start..end,
+            collapsed,
+            start_snapped,
+            end_snapped,
+            clamped,
+        }
+    }
+
+    pub(crate) fn convert_span(&self, start: usize, end: usize) -> SourceSpan {
+        let core::ops::Range { start, end } = self.normalize_span(start..end).span;
+
         let start_line = match self.line_map.binary_search(&start) {
             Ok(i) => i + 1,
             Err(i) => i,
@@ -132,9 +355,9 @@ impl SourceSnippet {
         };
         let mut start_col = 0;
         let mut start_utf8 = 0;
-        for meta in self.metas[start_line_start..start].iter() {
-            start_col += meta.width();
-            start_utf8 += meta.utf8_len();
+        for unit in start_line_start..start {
+            start_col += self.unit_width(unit);
+            start_utf8 += self.unit_utf8_len(unit);
         }
 
         let end_line;
@@ -156,9 +379,9 @@ impl SourceSnippet {
             };
             end_col = 0;
             end_utf8 = 0;
-            for meta in self.metas[end_line_start..end].iter() {
-                end_col += meta.width();
-                end_utf8 += meta.utf8_len();
+            for unit in end_line_start..end {
+                end_col += self.unit_width(unit);
+                end_utf8 += self.unit_utf8_len(unit);
             }
         }
 
@@ -171,11 +394,99 @@ impl SourceSnippet {
             end_utf8,
         }
     }
+
+    /// Returns, for each display column of a line, whether it is a cell
+    /// of a wide character that is not its central cell.
+    pub(crate) fn wide_char_gaps(&self, line_i: usize) -> Vec<bool> {
+        let line = &self.lines[line_i];
+        let line_start = if line_i == 0 {
+            0
+        } else {
+            self.line_map[line_i - 1]
+        };
+
+        let mut gaps = Vec::with_capacity(line.width);
+        let mut utf8 = 0;
+        for (unit, meta) in self.metas.iter().enumerate().skip(line_start) {
+            if meta.is_extra() {
+                continue;
+            }
+            if utf8 >= line.text.len() {
+                break;
+            }
+
+            let width = self.unit_width(unit);
+            let utf8_len = self.unit_utf8_len(unit);
+            let text = &line.text[utf8..(utf8 + utf8_len)];
+            let is_wide_char = width > 1 && !meta.is_tab() && text.chars().nth(1).is_none();
+            if is_wide_char {
+                let center = (width - 1) / 2;
+                gaps.extend((0..width).map(|i| i != center));
+            } else {
+                gaps.extend(core::iter::repeat(false).take(width));
+            }
+            utf8 += utf8_len;
+        }
+        gaps
+    }
+
+    /// Moves the start of `span` to the last column of a tab if it starts
+    /// at a tab.
+    pub(crate) fn anchor_span_at_tab_end(&self, start: usize, span: &mut SourceSpan) {
+        let Some(meta) = self.metas.get(start) else {
+            return;
+        };
+        let width = self.unit_width(start);
+        if meta.is_tab() && width > 1 {
+            let is_empty = span.start_line == span.end_line && span.start_col == span.end_col;
+            span.start_col += width - 1;
+            if is_empty {
+                span.end_col = span.start_col;
+            }
+        }
+    }
+
+    /// Moves the end of `span` to the last character of its line if it
+    /// ends on the line break.
+    pub(crate) fn clamp_span_end(&self, span: &mut SourceSpan) {
+        let line = &self.lines[span.end_line];
+        if span.end_col <= line.width {
+            return;
+        }
+        span.end_col = line.width;
+        span.end_utf8 = line.text.len();
+
+        if span.start_line == span.end_line && span.start_col >= line.width && line.width != 0 {
+            // The span only covers the line break, so point to the
+            // last character of the line.
+            let line_start = if span.end_line == 0 {
+                0
+            } else {
+                self.line_map[span.end_line - 1]
+            };
+            let mut col = 0;
+            let mut utf8 = 0;
+            for (unit, meta) in self.metas.iter().enumerate().skip(line_start) {
+                if meta.is_extra() {
+                    continue;
+                }
+                let width = self.unit_width(unit);
+                if col + width >= line.width {
+                    break;
+                }
+                col += width;
+                utf8 += self.unit_utf8_len(unit);
+            }
+            span.start_col = col;
+            span.start_utf8 = utf8;
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{SourceSnippet, SourceSpan};
+    use super::{SourceSnippet, SourceSpan, SpanNormalization};
+    use crate::range_set::RangeSet;
 
     #[test]
     fn test_get_line_col() {
@@ -317,6 +628,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "unicode-width")]
     #[test]
     fn test_convert_span_multi_byte() {
         let snippet = SourceSnippet::build_from_utf8(0, b"1\xEF\xBC\x923\n456", 4);
@@ -422,6 +734,112 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_clamp_span_end() {
+        let snippet = SourceSnippet::build_from_utf8(0, b"12\x00\n456\n", 4);
+
+        let mut span = snippet.convert_span(0, 4);
+        snippet.clamp_span_end(&mut span);
+        assert_eq!(
+            span,
+            SourceSpan {
+                start_line: 0,
+                start_col: 0,
+                start_utf8: 0,
+                end_line: 0,
+                end_col: 8,
+                end_utf8: 8,
+            },
+        );
+
+        let mut span = snippet.convert_span(3, 4);
+        snippet.clamp_span_end(&mut span);
+        assert_eq!(
+            span,
+            SourceSpan {
+                start_line: 0,
+                start_col: 2,
+                start_utf8: 2,
+                end_line: 0,
+                end_col: 8,
+                end_utf8: 8,
+            },
+        );
+
+        let mut span = snippet.convert_span(1, 8);
+        snippet.clamp_span_end(&mut span);
+        assert_eq!(
+            span,
+            SourceSpan {
+                start_line: 0,
+                start_col: 1,
+                start_utf8: 1,
+                end_line: 1,
+                end_col: 3,
+                end_utf8: 3,
+            },
+        );
+    }
+
+    #[cfg(feature = "unicode-width")]
+    #[test]
+    fn test_wide_char_gaps() {
+        let snippet = SourceSnippet::build_from_utf8(0, b"1\xEF\xBC\x92\t\x003\n\xEF\xBC\x92", 2);
+
+        assert_eq!(
+            snippet.wide_char_gaps(0),
+            [false, false, true, false, false, false, false, false, false, false, false, false],
+        );
+        assert_eq!(snippet.wide_char_gaps(1), [false, true]);
+    }
+
+    #[test]
+    fn test_anchor_span_at_tab_end() {
+        let snippet = SourceSnippet::build_from_utf8(0, b"1\t23", 4);
+
+        let mut span = snippet.convert_span(1, 3);
+        snippet.anchor_span_at_tab_end(1, &mut span);
+        assert_eq!(
+            span,
+            SourceSpan {
+                start_line: 0,
+                start_col: 4,
+                start_utf8: 1,
+                end_line: 0,
+                end_col: 6,
+                end_utf8: 6,
+            },
+        );
+
+        let mut span = snippet.convert_span(1, 1);
+        snippet.anchor_span_at_tab_end(1, &mut span);
+        assert_eq!(
+            span,
+            SourceSpan {
+                start_line: 0,
+                start_col: 4,
+                start_utf8: 1,
+                end_line: 0,
+                end_col: 4,
+                end_utf8: 1,
+            },
+        );
+
+        let mut span = snippet.convert_span(2, 3);
+        snippet.anchor_span_at_tab_end(2, &mut span);
+        assert_eq!(
+            span,
+            SourceSpan {
+                start_line: 0,
+                start_col: 5,
+                start_utf8: 5,
+                end_line: 0,
+                end_col: 6,
+                end_utf8: 6,
+            },
+        );
+    }
+
     #[test]
     fn test_convert_span_invalid_utf8() {
         let snippet = SourceSnippet::build_from_utf8(0, b"1\xFF2\n3", 4);
@@ -471,4 +889,101 @@ mod tests {
             },
         );
     }
+
+    #[test]
+    fn test_large_units() {
+        let long_text = "x".repeat(300);
+        let mut builder = SourceSnippet::builder(0);
+        builder.push_text("a", 1, false);
+        builder.push_text(&long_text, 2, true);
+        builder.push_char('b', 200, 1, false);
+        builder.push_tab(&" ".repeat(130), 1, false);
+        builder.push_text("c", 1, false);
+        let snippet = builder.finish();
+
+        assert_eq!(snippet.get_line_col(1), (0, 1));
+        assert_eq!(snippet.get_line_col(3), (0, 301));
+        assert_eq!(snippet.get_line_col(4), (0, 501));
+        assert_eq!(snippet.get_line_col(5), (0, 631));
+        assert_eq!(
+            snippet.convert_span(3, 5),
+            SourceSpan {
+                start_line: 0,
+                start_col: 301,
+                start_utf8: 301,
+                end_line: 0,
+                end_col: 631,
+                end_utf8: 432,
+            },
+        );
+    }
+
+    #[test]
+    fn test_add_alt_units() {
+        // "é" is two bytes and the tab is expanded to four spaces
+        let mut snippet = SourceSnippet::build_from_utf8(0, "aé\tb\ncd".as_bytes(), 4);
+
+        // Second byte of "é", "b" and the line break, "d" and out of bounds
+        let units: RangeSet<usize> = [2..=2, 4..=5, 7..=100].into_iter().collect();
+        snippet.add_alt_units(&units);
+        assert_eq!(&*snippet.lines[0].text, "aé    b");
+        assert_eq!(
+            snippet.lines[0].alts,
+            [1..=2, 7..=7].into_iter().collect::<RangeSet<_>>(),
+        );
+        assert_eq!(&*snippet.lines[1].text, "cd");
+        assert_eq!(snippet.lines[1].alts, RangeSet::from(1..=1));
+    }
+
+    #[test]
+    fn test_normalize_span() {
+        // "é" is two bytes
+        let snippet = SourceSnippet::build_from_utf8(0, "aéb\n".as_bytes(), 4);
+
+        let exact = snippet.normalize_span(1..3);
+        assert_eq!(
+            exact,
+            SpanNormalization {
+                span: 1..3,
+                collapsed: false,
+                start_snapped: false,
+                end_snapped: false,
+                clamped: false,
+            },
+        );
+        assert!(exact.is_exact());
+
+        assert_eq!(
+            snippet.normalize_span(2..2),
+            SpanNormalization {
+                span: 1..3,
+                collapsed: false,
+                start_snapped: true,
+                end_snapped: true,
+                clamped: false,
+            },
+        );
+        assert_eq!(
+            snippet.normalize_span(core::ops::Range { start: 4, end: 1 }),
+            SpanNormalization {
+                span: 4..4,
+                collapsed: true,
+                start_snapped: false,
+                end_snapped: false,
+                clamped: false,
+            },
+        );
+        let clamped = snippet.normalize_span(3..10);
+        assert_eq!(
+            clamped,
+            SpanNormalization {
+                span: 3..5,
+                collapsed: false,
+                start_snapped: false,
+                end_snapped: false,
+                clamped: true,
+            },
+        );
+        assert!(!clamped.is_exact());
+    }
 }
@@ -0,0 +1,304 @@
+use alloc::vec::Vec;
+
+use super::{SourceSnippet, SourceUnitMeta};
+
+/// Maps source unit positions of a snippet to positions of the snippet
+/// obtained with [`SourceSnippet::with_replacement`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplacementMap {
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+}
+
+impl ReplacementMap {
+    /// Returns the span of the original snippet that was replaced, extended
+    /// to cover whole elements.
+    #[inline]
+    pub fn old_span(&self) -> core::ops::Range<usize> {
+        self.start..self.old_end
+    }
+
+    /// Returns the span of the new snippet that holds the replacement.
+    #[inline]
+    pub fn new_span(&self) -> core::ops::Range<usize> {
+        self.start..self.new_end
+    }
+
+    /// Maps a position of the original snippet to the new snippet.
+    ///
+    /// Positions inside the replaced span are mapped to the start of the
+    /// replacement.
+    pub fn map_pos(&self, pos: usize) -> usize {
+        if pos < self.start {
+            pos
+        } else if pos < self.old_end {
+            self.start
+        } else {
+            pos - self.old_end + self.new_end
+        }
+    }
+
+    /// Maps a span of the original snippet to the new snippet.
+    ///
+    /// Spans that end inside the replaced span are extended to the end of
+    /// the replacement, and spans that end at its start do not include it.
+    pub fn map_span(&self, span: core::ops::Range<usize>) -> core::ops::Range<usize> {
+        let start = self.map_pos(span.start);
+        let end = if span.end <= self.start {
+            span.end
+        } else if span.end < self.old_end {
+            self.new_end
+        } else {
+            self.map_pos(span.end)
+        };
+        start..end.max(start)
+    }
+}
+
+/// An element of a snippet, as pushed to its builder.
+enum Element<'s> {
+    Text {
+        text: &'s str,
+        width: usize,
+        orig_len: usize,
+        alt: bool,
+        tab: bool,
+    },
+    LineBreak {
+        orig_len: usize,
+    },
+}
+
+impl SourceSnippet {
+    /// Creates a new snippet where the units of `span` are replaced with
+    /// the units of `replacement`, which is useful to preview the result of
+    /// applying a fix.
+    ///
+    /// `span` is extended to cover whole elements (e.g., characters) and
+    /// clamped to the end of the snippet. An empty `span` is an insertion
+    /// point, which is moved to the start of the element it points into. The start line and the kind of
+    /// units of `replacement` are ignored.
+    ///
+    /// Returns the new snippet and a map from the positions of `self` to
+    /// the positions of the new snippet, so existing annotations can be
+    /// moved to it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use sourceannot::SourceSnippet;
+    ///
+    /// let snippet = SourceSnippet::build_from_utf8(1, b"let x = 1;\n", 4);
+    /// let replacement = SourceSnippet::build_from_utf8(1, b"value", 4);
+    /// let (fixed, map) = snippet.with_replacement(4..5, &replacement);
+    ///
+    /// assert_eq!(map.new_span(), 4..9);
+    /// assert_eq!(map.map_span(8..9), 12..13);
+    /// assert_eq!(fixed.get_line_col(12), (0, 12));
+    /// ```
+    pub fn with_replacement(
+        &self,
+        span: core::ops::Range<usize>,
+        replacement: &SourceSnippet,
+    ) -> (SourceSnippet, ReplacementMap) {
+        let mut end = span.end.max(span.start).min(self.metas.len());
+        let mut start = span.start.min(end);
+        while self.metas.get(start).is_some_and(SourceUnitMeta::is_extra) {
+            start -= 1;
+        }
+        if span.end <= span.start {
+            // An empty span is an insertion point, which must not extend to
+            // the element it points into.
+            end = start;
+        }
+
+        let mut builder = SourceSnippet::builder(self.start_line);
+        builder.set_unit_kind(self.unit_kind);
+        let mut old_end = end;
+        let mut replaced = false;
+        for (unit, element) in self.elements() {
+            if unit < start {
+                element.push_to(&mut builder);
+            } else if unit < end {
+                old_end = unit + element.orig_len();
+            } else {
+                if !replaced {
+                    replacement.push_elements_to(&mut builder);
+                    replaced = true;
+                }
+                element.push_to(&mut builder);
+            }
+        }
+        if !replaced {
+            replacement.push_elements_to(&mut builder);
+        }
+
+        let map = ReplacementMap {
+            start,
+            old_end,
+            new_end: start + replacement.metas.len(),
+        };
+        (builder.finish(), map)
+    }
+
+    fn push_elements_to(&self, builder: &mut super::SourceSnippetBuilder) {
+        for (_, element) in self.elements() {
+            element.push_to(builder);
+        }
+    }
+
+    /// Returns the elements of the snippet, along with the index of their
+    /// first unit.
+    fn elements(&self) -> Vec<(usize, Element<'_>)> {
+        let line_break_meta = SourceUnitMeta::new(1, 0);
+        let mut elements = Vec::new();
+        for (line_i, line) in self.lines.iter().enumerate() {
+            let units_start = self.line_start(line_i);
+            let units_end = self.line_start(line_i + 1);
+
+            // All lines but the last one end with a line break, whose units
+            // do not have text.
+            let mut text_end = units_end;
+            if line_i + 1 != self.lines.len() {
+                while text_end > units_start && self.metas[text_end - 1] == line_break_meta {
+                    text_end -= 1;
+                }
+            }
+
+            let mut unit = units_start;
+            let mut utf8 = 0;
+            while unit < text_end {
+                let utf8_len = self.unit_utf8_len(unit);
+                let orig_len = 1 + self.metas[(unit + 1)..text_end]
+                    .iter()
+                    .take_while(|meta| meta.is_extra())
+                    .count();
+                let text = &line.text[utf8..(utf8 + utf8_len)];
+                elements.push((
+                    unit,
+                    Element::Text {
+                        text,
+                        width: self.unit_width(unit),
+                        orig_len,
+                        alt: !text.is_empty() && line.alts.contains(&utf8),
+                        tab: self.metas[unit].is_tab(),
+                    },
+                ));
+                unit += orig_len;
+                utf8 += utf8_len;
+            }
+            if text_end != units_end {
+                elements.push((
+                    text_end,
+                    Element::LineBreak {
+                        orig_len: units_end - text_end,
+                    },
+                ));
+            }
+        }
+        elements
+    }
+}
+
+impl Element<'_> {
+    fn orig_len(&self) -> usize {
+        match *self {
+            Self::Text { orig_len, .. } | Self::LineBreak { orig_len } => orig_len,
+        }
+    }
+
+    fn push_to(&self, builder: &mut super::SourceSnippetBuilder) {
+        match *self {
+            Self::Text {
+                text,
+                width,
+                orig_len,
+                alt,
+                tab,
+            } => builder.push_element(text, width, orig_len, alt, tab),
+            Self::LineBreak { orig_len } => builder.push_line_break(orig_len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SourceSnippet;
+
+    fn text(snippet: &SourceSnippet) -> alloc::vec::Vec<&str> {
+        (0..snippet.num_lines())
+            .map(|line_i| &*snippet.line(line_i).text)
+            .collect()
+    }
+
+    #[test]
+    fn test_replacement_same_line() {
+        let snippet = SourceSnippet::build_from_utf8(1, b"let x = 1;\nx += 1;\n", 4);
+        let replacement = SourceSnippet::build_from_utf8(1, b"value", 4);
+        let (fixed, map) = snippet.with_replacement(4..5, &replacement);
+
+        assert_eq!(text(&fixed), ["let value = 1;", "x += 1;", ""]);
+        assert_eq!(fixed.num_units(), snippet.num_units() + 4);
+        assert_eq!(map.old_span(), 4..5);
+        assert_eq!(map.new_span(), 4..9);
+        assert_eq!(map.map_pos(3), 3);
+        assert_eq!(map.map_pos(4), 4);
+        assert_eq!(map.map_pos(5), 9);
+        assert_eq!(map.map_span(0..10), 0..14);
+        assert_eq!(map.map_span(11..12), 15..16);
+    }
+
+    #[test]
+    fn test_replacement_lines() {
+        let snippet = SourceSnippet::build_from_utf8(1, b"a\r\nb\tc\nd", 4);
+        let replacement = SourceSnippet::build_from_utf8(1, b"1\n2", 4);
+
+        // Replaces `\r\nb`
+        let (fixed, map) = snippet.with_replacement(1..4, &replacement);
+        assert_eq!(text(&fixed), ["a1", "2    c", "d"]);
+        assert_eq!(map.new_span(), 1..4);
+        assert_eq!(fixed.get_line_col(map.map_pos(5)), (1, 5));
+
+        // Insertion at the end
+        let (fixed, map) = snippet.with_replacement(8..8, &replacement);
+        assert_eq!(text(&fixed), ["a", "b    c", "d1", "2"]);
+        assert_eq!(map.new_span(), 8..11);
+        assert_eq!(map.map_span(0..8), 0..8);
+
+        // Deletion
+        let empty = SourceSnippet::build_from_utf8(1, b"", 4);
+        let (fixed, map) = snippet.with_replacement(4..7, &empty);
+        assert_eq!(text(&fixed), ["a", "bd"]);
+        assert_eq!(map.new_span(), 4..4);
+        assert_eq!(map.map_span(5..8), 4..5);
+    }
+
+    #[test]
+    fn test_replacement_multi_unit() {
+        let snippet = SourceSnippet::build_from_utf8(1, "aé\u{7}b".as_bytes(), 4);
+        let replacement = SourceSnippet::build_from_utf8(1, b"e", 4);
+
+        // The span is extended to the whole `é`
+        let (fixed, map) = snippet.with_replacement(2..3, &replacement);
+        assert_eq!(text(&fixed), ["ae<0007>b"]);
+        assert_eq!(map.old_span(), 1..3);
+        assert_eq!(map.new_span(), 1..2);
+        assert_eq!(map.map_pos(4), 3);
+        assert!(fixed.line(0).alts.contains(&2));
+        assert!(!fixed.line(0).alts.contains(&1));
+    }
+
+    #[test]
+    fn test_insertion_multi_unit() {
+        let snippet = SourceSnippet::build_from_utf8(1, "aé".as_bytes(), 4);
+        let replacement = SourceSnippet::build_from_utf8(1, b"x", 4);
+
+        // The insertion point is moved to the start of `é`, which is kept
+        let (fixed, map) = snippet.with_replacement(2..2, &replacement);
+        assert_eq!(text(&fixed), ["axé"]);
+        assert_eq!(map.old_span(), 1..1);
+        assert_eq!(map.new_span(), 1..2);
+        assert_eq!(map.map_pos(1), 2);
+    }
+}
@@ -0,0 +1,408 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{SourceLine, SourceSnippet, SourceUnitMeta, UnitKind};
+use crate::range_set::RangeSet;
+use crate::Error;
+
+/// Bytes that start every snapshot.
+const MAGIC: &[u8; 4] = b"SASN";
+
+/// Version of the snapshot format, increased on every incompatible change.
+const VERSION: u8 = 1;
+
+/// Maximum width of a unit that is wider than its text (e.g., a kept tab
+/// or a char with a custom width) in a loaded snapshot, which avoids huge
+/// rendered lines.
+const MAX_UNIT_WIDTH: usize = 0xFFFF;
+
+impl SourceSnippet {
+    /// Serializes the snippet to a compact binary snapshot, which can be
+    /// loaded with [`from_bytes`](Self::from_bytes).
+    ///
+    /// This allows caching snippets that are expensive to build (e.g., of
+    /// huge generated files) between runs. Snapshots do not depend on the
+    /// byte order or the pointer width of the platform, and they start with
+    /// a header with the version of the format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer { bytes: Vec::new() };
+        w.bytes.extend_from_slice(MAGIC);
+        w.bytes.push(VERSION);
+
+        w.usize(self.start_line);
+        w.bytes.push(unit_kind_to_u8(self.unit_kind));
+
+        w.usize(self.lines.len());
+        for line in self.lines.iter() {
+            w.usize(line.text.len());
+            w.bytes.extend_from_slice(line.text.as_bytes());
+            w.usize(line.width);
+            let alts: Vec<_> = line.alts.ranges().collect();
+            w.usize(alts.len());
+            for range in alts {
+                w.usize(*range.start());
+                w.usize(*range.end());
+            }
+        }
+
+        // Line starts are stored as deltas, which are usually small
+        let mut prev_line_start = 0;
+        for &line_start in self.line_map.iter() {
+            w.usize(line_start - prev_line_start);
+            prev_line_start = line_start;
+        }
+
+        w.usize(self.metas.len());
+        for meta in self.metas.iter() {
+            w.bytes.extend_from_slice(&meta.inner.to_le_bytes());
+        }
+
+        for table in [&self.large_widths, &self.large_utf8_lens] {
+            w.usize(table.len());
+            for (&unit, &value) in table.iter() {
+                w.usize(unit);
+                w.usize(value);
+            }
+        }
+
+        w.bytes
+    }
+
+    /// Loads a snippet from a snapshot created with
+    /// [`to_bytes`](Self::to_bytes).
+    ///
+    /// Returns [`Error::UnsupportedSnapshotVersion`] if the snapshot was
+    /// created with another version of the format and
+    /// [`Error::InvalidSnapshot`] if it is malformed. Snapshots are also
+    /// rejected if they contain an element wider than both its text and
+    /// 65535 columns.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader { bytes };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(Error::InvalidSnapshot);
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedSnapshotVersion(version));
+        }
+
+        let start_line = r.usize()?;
+        let unit_kind = unit_kind_from_u8(r.u8()?)?;
+
+        let num_lines = r.usize()?;
+        if num_lines == 0 {
+            return Err(Error::InvalidSnapshot);
+        }
+        // Each line takes at least three bytes, which bounds the
+        // preallocation
+        let mut lines = Vec::with_capacity(num_lines.min(r.bytes.len() / 3));
+        for _ in 0..num_lines {
+            let text_len = r.usize()?;
+            let text =
+                String::from_utf8(r.take(text_len)?.into()).map_err(|_| Error::InvalidSnapshot)?;
+            let width = r.usize()?;
+            let mut alts = RangeSet::new();
+            for _ in 0..r.usize()? {
+                let start = r.usize()?;
+                let end = r.usize()?;
+                if start > end || end >= text.len() {
+                    return Err(Error::InvalidSnapshot);
+                }
+                alts.insert(start..=end);
+            }
+            lines.push(SourceLine {
+                text: text.into_boxed_str(),
+                alts,
+                width,
+            });
+        }
+
+        let mut line_map = Vec::with_capacity(num_lines - 1);
+        let mut line_start = 0usize;
+        for _ in 1..num_lines {
+            line_start = line_start
+                .checked_add(r.usize()?)
+                .ok_or(Error::InvalidSnapshot)?;
+            line_map.push(line_start);
+        }
+
+        let num_units = r.usize()?;
+        if num_units > r.bytes.len() / 2 || line_start > num_units {
+            return Err(Error::InvalidSnapshot);
+        }
+        let mut metas = Vec::with_capacity(num_units);
+        for _ in 0..num_units {
+            let inner = r.take(2)?;
+            metas.push(SourceUnitMeta {
+                inner: u16::from_le_bytes([inner[0], inner[1]]),
+            });
+        }
+
+        let mut tables = [BTreeMap::new(), BTreeMap::new()];
+        for table in tables.iter_mut() {
+            for _ in 0..r.usize()? {
+                let unit = r.usize()?;
+                let value = r.usize()?;
+                if unit >= num_units || value < SourceUnitMeta::LARGE {
+                    return Err(Error::InvalidSnapshot);
+                }
+                table.insert(unit, value);
+            }
+        }
+        let [large_widths, large_utf8_lens] = tables;
+
+        if !r.bytes.is_empty() {
+            return Err(Error::InvalidSnapshot);
+        }
+
+        let snippet = Self {
+            start_line,
+            unit_kind,
+            lines,
+            line_map,
+            metas,
+            large_widths,
+            large_utf8_lens,
+        };
+        if !snippet.is_consistent() {
+            return Err(Error::InvalidSnapshot);
+        }
+        Ok(snippet)
+    }
+
+    /// Checks that the metas of the units agree with the text of the
+    /// lines, so a loaded snapshot cannot cause panics later.
+    fn is_consistent(&self) -> bool {
+        for (unit, meta) in self.metas.iter().enumerate() {
+            if meta.is_extra() {
+                continue;
+            }
+            if (meta.width() == SourceUnitMeta::LARGE && !self.large_widths.contains_key(&unit))
+                || (meta.utf8_len() == SourceUnitMeta::LARGE
+                    && !self.large_utf8_lens.contains_key(&unit))
+            {
+                return false;
+            }
+        }
+
+        for (line_i, line) in self.lines.iter().enumerate() {
+            let units = self.line_start(line_i)..self.line_start(line_i + 1);
+            if self
+                .metas
+                .get(units.start)
+                .is_some_and(SourceUnitMeta::is_extra)
+            {
+                return false;
+            }
+            let mut utf8_pos = 0usize;
+            let mut width = 0usize;
+            // Number of trailing units that may belong to the line break
+            let mut num_break_units = 0;
+            for unit in units {
+                let meta = &self.metas[unit];
+                if meta.is_extra() {
+                    num_break_units = 0;
+                    continue;
+                }
+                let unit_utf8_len = self.unit_utf8_len(unit);
+                let unit_width = self.unit_width(unit);
+                if unit_width > unit_utf8_len.max(MAX_UNIT_WIDTH) {
+                    return false;
+                }
+                width += unit_width;
+                if unit_width == 1 && unit_utf8_len == 0 && !meta.is_tab() {
+                    num_break_units += 1;
+                } else {
+                    num_break_units = 0;
+                }
+                match utf8_pos.checked_add(unit_utf8_len) {
+                    Some(end) if line.text.is_char_boundary(end) => utf8_pos = end,
+                    _ => return false,
+                }
+            }
+            if utf8_pos != line.text.len() {
+                return false;
+            }
+
+            // The width of the line does not include its line break, which
+            // spans at least one unit (except for the last line)
+            let break_width = width.checked_sub(line.width);
+            let is_last = line_i == self.lines.len() - 1;
+            match break_width {
+                Some(0) if is_last => {}
+                Some(n) if !is_last && (1..=num_break_units).contains(&n) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+fn unit_kind_to_u8(unit_kind: UnitKind) -> u8 {
+    match unit_kind {
+        UnitKind::Byte => 0,
+        UnitKind::Utf16Word => 1,
+        UnitKind::Char => 2,
+        UnitKind::Token => 3,
+        UnitKind::Other => 4,
+    }
+}
+
+fn unit_kind_from_u8(value: u8) -> Result<UnitKind, Error> {
+    match value {
+        0 => Ok(UnitKind::Byte),
+        1 => Ok(UnitKind::Utf16Word),
+        2 => Ok(UnitKind::Char),
+        3 => Ok(UnitKind::Token),
+        4 => Ok(UnitKind::Other),
+        _ => Err(Error::InvalidSnapshot),
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    /// Writes an unsigned LEB128 integer.
+    fn usize(&mut self, mut value: usize) {
+        while value >= 0x80 {
+            self.bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bytes.len() {
+            return Err(Error::InvalidSnapshot);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads an unsigned LEB128 integer.
+    fn usize(&mut self) -> Result<usize, Error> {
+        let mut value: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            let bits = usize::from(byte & 0x7F);
+            if shift >= usize::BITS || (bits << shift) >> shift != bits {
+                return Err(Error::InvalidSnapshot);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use crate::{Error, SourceSnippet};
+
+    #[track_caller]
+    fn check_roundtrip(snippet: &SourceSnippet) {
+        let bytes = snippet.to_bytes();
+        let loaded = SourceSnippet::from_bytes(&bytes).unwrap();
+        assert_eq!(format!("{loaded:?}"), format!("{snippet:?}"));
+        assert_eq!(loaded.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        check_roundtrip(&SourceSnippet::build_from_utf8(1, b"", 4));
+        check_roundtrip(&SourceSnippet::build_from_utf8(
+            10,
+            b"let x = \"\xE2\x82\xAC\";\r\n\tif \xFF {\n}\n",
+            4,
+        ));
+        check_roundtrip(&SourceSnippet::build_from_utf8(1, b"\tx", 200));
+        check_roundtrip(&SourceSnippet::build_from_chars(1, "a\u{1}b\nc", 4));
+
+        let mut builder = SourceSnippet::builder(1);
+        builder.push_char('a', 200, 1, false);
+        builder.push_line_break(2);
+        builder.push_text("", 1, false);
+        check_roundtrip(&builder.finish());
+    }
+
+    #[test]
+    fn test_header() {
+        let mut bytes = SourceSnippet::build_from_utf8(1, b"abc\n", 4).to_bytes();
+        assert_eq!(&bytes[..5], b"SASN\x01");
+
+        bytes[4] = 2;
+        assert_eq!(
+            SourceSnippet::from_bytes(&bytes).unwrap_err(),
+            Error::UnsupportedSnapshotVersion(2),
+        );
+        assert_eq!(
+            SourceSnippet::from_bytes(b"SAS").unwrap_err(),
+            Error::InvalidSnapshot,
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let bytes = SourceSnippet::build_from_utf8(1, b"a\xC3\xA9\nb\n", 4).to_bytes();
+        // Every truncation is rejected without panicking
+        for len in 0..bytes.len() {
+            assert_eq!(
+                SourceSnippet::from_bytes(&bytes[..len]).unwrap_err(),
+                Error::InvalidSnapshot,
+            );
+        }
+        // Corruptions either load or are rejected without panicking
+        for i in 5..bytes.len() {
+            for value in [0x00, 0x01, 0x7F, 0x80, 0xC3, 0xFF] {
+                let mut corrupted = bytes.clone();
+                corrupted[i] = value;
+                let _ = SourceSnippet::from_bytes(&corrupted);
+            }
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            SourceSnippet::from_bytes(&trailing).unwrap_err(),
+            Error::InvalidSnapshot,
+        );
+
+        // Line widths that do not match the widths of their units
+        for width in [1, 3, usize::MAX] {
+            let mut snippet = SourceSnippet::build_from_utf8(1, b"a\xC3\xA9\nb\n", 4);
+            snippet.lines[0].width = width;
+            assert_eq!(
+                SourceSnippet::from_bytes(&snippet.to_bytes()).unwrap_err(),
+                Error::InvalidSnapshot,
+            );
+        }
+
+        // Units that are too wide, even if the line width matches
+        let mut snippet = SourceSnippet::build_from_utf8(1, b"\tx", 200);
+        snippet.large_widths.insert(0, usize::MAX - 1);
+        snippet.lines[0].width = usize::MAX;
+        assert_eq!(
+            SourceSnippet::from_bytes(&snippet.to_bytes()).unwrap_err(),
+            Error::InvalidSnapshot,
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Helpers to test rendered snippets.
+//!
+//! This module is only available with the `testing` feature.
+//!
+//! # Example
+//!
+//! ```
+//! use sourceannot::testing::{ANNOT_STYLE, MAIN_STYLE};
+//!
+//! let snippet = sourceannot::SourceSnippet::build_from_utf8(1, b"let x = 1;\n", 4);
+//! let mut annots = sourceannot::Annotations::new(&snippet, MAIN_STYLE);
+//! annots.add_annotation(4..5, ANNOT_STYLE, vec![("a variable".into(), 'l')]);
+//!
+//! sourceannot::expect_render!(
+//!     annots,
+//!     "
+//!     1 │ let x = 1;
+//!       │     ^ a variable
+//!     ",
+//! );
+//! ```
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{AnnotStyle, Annotations, MainStyle, MarginStyle};
+
+/// A canonical main style for tests, with Unicode box drawing characters
+/// and the default options.
+///
+/// Each kind of chunk has a different metadata character: `m` for the
+/// margin, `s` for spaces, `t` for normal text and `T` for alternative
+/// text.
+pub const MAIN_STYLE: MainStyle<char> = MainStyle {
+    margin: Some(MarginStyle {
+        line_char: '│',
+        dot_char: '·',
+        meta: 'm',
+    }),
+    spaces_meta: 's',
+    text_normal_meta: 't',
+    text_alt_meta: 'T',
+    ..MainStyle::new('s')
+};
+
+/// A canonical annotation style for tests, with `^` carets.
+///
+/// Annotated text has `a` (normal) or `A` (alternative) metadata and
+/// drawings have `l` metadata.
+pub const ANNOT_STYLE: AnnotStyle<char> = AnnotStyle {
+    caret: '^',
+    text_normal_meta: 'a',
+    text_alt_meta: 'A',
+    line_meta: 'l',
+    hide_carets: false,
+};
+
+/// Renders `annots` without filling lines between annotated lines and
+/// returns the text, without metadata.
+pub fn render_text<M: Clone>(annots: &Annotations<'_, M>) -> String {
+    annots
+        .render(annots.max_line_no_width(), 0, 0)
+        .iter()
+        .map(|(text, _)| text.as_str())
+        .collect()
+}
+
+/// Returns a string with the metadata character of each character of
+/// `rendered`, keeping line breaks, so it can be compared like the text.
+pub fn gather_styles(rendered: &[(String, char)]) -> String {
+    let mut r = String::new();
+    for (text, style) in rendered.iter() {
+        for chr in text.chars() {
+            r.push(*style);
+            if chr == '\n' {
+                r.push('\n');
+            }
+        }
+    }
+    r
+}
+
+/// Removes the leading line break and the common indentation of the lines
+/// of `text`, like the `indoc` crate.
+///
+/// Lines with only spaces do not count for the common indentation.
+pub fn unindent(text: &str) -> String {
+    let text = text.strip_prefix('\n').unwrap_or(text);
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches(' ').len())
+        .min()
+        .unwrap_or(0);
+    let mut result = String::new();
+    for line in text.split_inclusive('\n') {
+        match line.get(indent..) {
+            Some(unindented) => result.push_str(unindented),
+            None => result.push_str(line.trim_start_matches(' ')),
+        }
+    }
+    result
+}
+
+/// Asserts that the rendered text `actual` is equal to `expected` after
+/// [unindenting](unindent) it.
+///
+/// # Panics
+///
+/// Panics if they are different, with a side-by-side comparison of their
+/// lines in which different lines are marked with `!`.
+#[track_caller]
+pub fn assert_render_eq(actual: &str, expected: &str) {
+    let expected = unindent(expected);
+    if actual != expected {
+        panic!(
+            "rendered snippet does not match\n{}",
+            side_by_side(&expected, actual)
+        );
+    }
+}
+
+/// Formats the lines of `expected` and `actual` side by side.
+fn side_by_side(expected: &str, actual: &str) -> String {
+    let expected_lines: Vec<&str> = expected.split('\n').collect();
+    let actual_lines: Vec<&str> = actual.split('\n').collect();
+    let width = expected_lines
+        .iter()
+        .map(|line| crate::width::str_width(line))
+        .max()
+        .unwrap_or(0)
+        .max("expected".len());
+
+    let mut result = format!("  {:width$} ┃ actual\n", "expected");
+    for i in 0..expected_lines.len().max(actual_lines.len()) {
+        let expected_line = expected_lines.get(i).copied();
+        let actual_line = actual_lines.get(i).copied();
+        let marker = if expected_line == actual_line {
+            ' '
+        } else {
+            '!'
+        };
+        let expected_line = expected_line.unwrap_or("");
+        let padding = width - crate::width::str_width(expected_line);
+        result.push_str(&format!(
+            "{marker} {expected_line}{:padding$} ┃ {}\n",
+            "",
+            actual_line.unwrap_or(""),
+        ));
+    }
+    result
+}
+
+/// Asserts that rendering some annotations (with
+/// [`render_text`](crate::testing::render_text)) produces the expected text,
+/// which is [unindented](crate::testing::unindent).
+///
+/// See [`assert_render_eq`](crate::testing::assert_render_eq).
+#[macro_export]
+macro_rules! expect_render {
+    ($annots:expr, $expected:expr $(,)?) => {
+        $crate::testing::assert_render_eq(&$crate::testing::render_text(&$annots), $expected)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{side_by_side, unindent};
+
+    #[test]
+    fn test_unindent() {
+        assert_eq!(unindent("\n    a\n      b\n\n    c\n    "), "a\n  b\n\nc\n");
+        assert_eq!(unindent("a\n b\n"), "a\n b\n");
+    }
+
+    #[test]
+    fn test_side_by_side() {
+        assert_eq!(
+            side_by_side("1 │ a\n  │ ^\n", "1 │ a\n  │ -\n"),
+            concat!(
+                "  expected ┃ actual\n",
+                "  1 │ a    ┃ 1 │ a\n",
+                "!   │ ^    ┃   │ -\n",
+                "           ┃ \n",
+            ),
+        );
+    }
+}
@@ -0,0 +1,34 @@
+/// Returns the display width of `chr`, or `None` if it is a control
+/// character.
+#[cfg(feature = "unicode-width")]
+#[inline]
+pub(crate) fn char_width(chr: char) -> Option<usize> {
+    unicode_width::UnicodeWidthChar::width(chr)
+}
+
+/// Returns the display width of `chr`, or `None` if it is a control
+/// character.
+#[cfg(not(feature = "unicode-width"))]
+#[inline]
+pub(crate) fn char_width(chr: char) -> Option<usize> {
+    if chr.is_control() {
+        None
+    } else {
+        Some(1)
+    }
+}
+
+/// Returns the display width of `s`.
+#[cfg(feature = "unicode-width")]
+#[inline]
+pub(crate) fn str_width(s: &str) -> usize {
+    unicode_width::UnicodeWidthStr::width(s)
+}
+
+/// Returns the display width of `s`, where control characters have no
+/// width.
+#[cfg(not(feature = "unicode-width"))]
+#[inline]
+pub(crate) fn str_width(s: &str) -> usize {
+    s.chars().map(|chr| char_width(chr).unwrap_or(0)).sum()
+}
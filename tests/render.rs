@@ -7,7 +7,12 @@
 )]
 #![forbid(unsafe_code)]
 
-use sourceannot::{AnnotStyle, Annotations, MainStyle, MarginStyle, SourceSnippet};
+use sourceannot::{
+    AnnotStyle, Annotations, Capabilities, ChunkKind, EndLabelOverflow, Error, LabelAnchor,
+    LabelSanitization, LayoutElement, LayoutElementKind, LineBreakEnd, MainStyle, MarginStyle,
+    MultiLineStarts, Output, RenderOptions, RowKind, Severity, SeverityStyles, SourceSnippet,
+    StylePrecedence, TabAnchor, UnitKind, Utf8Options,
+};
 
 const MAIN_STYLE: MainStyle<char> = MainStyle {
     margin: Some(MarginStyle {
@@ -15,14 +20,10 @@ const MAIN_STYLE: MainStyle<char> = MainStyle {
         dot_char: '·',
         meta: 'm',
     }),
-    horizontal_char: '─',
-    vertical_char: '│',
-    top_vertical_char: '╭',
-    top_corner_char: '╭',
-    bottom_corner_char: '╰',
     spaces_meta: 's',
     text_normal_meta: 't',
     text_alt_meta: 'T',
+    ..MainStyle::new('s')
 };
 
 const ANNOT_STYLE_1: AnnotStyle<char> = AnnotStyle {
@@ -30,6 +31,7 @@ const ANNOT_STYLE_1: AnnotStyle<char> = AnnotStyle {
     text_normal_meta: 'a',
     text_alt_meta: 'A',
     line_meta: 'l',
+    hide_carets: false,
 };
 
 const ANNOT_STYLE_2: AnnotStyle<char> = AnnotStyle {
@@ -37,6 +39,7 @@ const ANNOT_STYLE_2: AnnotStyle<char> = AnnotStyle {
     text_normal_meta: 'b',
     text_alt_meta: 'B',
     line_meta: 'L',
+    hide_carets: false,
 };
 
 fn gather_styles(rendered: &[(String, char)]) -> String {
@@ -52,6 +55,21 @@ fn gather_styles(rendered: &[(String, char)]) -> String {
     r
 }
 
+#[test]
+fn test_main_style_new() {
+    assert_eq!(MainStyle::new('\0'), MainStyle::<char>::default());
+
+    let source = "1234\n5678\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MainStyle::new('s'));
+    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("label".into(), 'x')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(text, "1 │ 1234\n  │  ^^ label\n");
+}
+
 #[test]
 fn test_render_single_line_1() {
     let source = "1234\n5678\n90ab\ncdef\n";
@@ -296,12 +314,202 @@ fn test_render_multi_line_3() {
 }
 
 #[test]
-fn test_render_multi_line_crlf() {
-    let source = "1234\r\n5678\r\n90ab\r\ncdef\r\n";
+fn test_render_multi_line_always_below() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        multi_line_starts: MultiLineStarts::AlwaysBelow,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(0..7, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(6..18, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │   1234
+              │ ╭─^
+            2 │ │ 5678
+              │ ╰──^ test 1
+              │ ╭──-
+              · │ 
+            4 │ │ cdef
+              │ ╰───- test 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsssaaaas
+            ssmsllls
+            msmslsaabbs
+            ssmslllls111111s
+            ssmsLLLLs
+            ssmsLss
+            msmsLsbbbts
+            ssmsLLLLLs222222s
+        "},
+    );
+}
+
+#[test]
+fn test_render_multi_line_end_label_overflow() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        end_label_overflow: EndLabelOverflow::NextRow,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(0..11, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(6..18, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        render_width: Some(14),
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │  ╭ 1234
+            2 │  │ 5678
+              │ ╭│──-
+            3 │ ││ 90ab
+              │ │╰─^
+              │ │test 1
+            4 │ │  cdef
+              │ ╰────-
+              │ test 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsslsaaaas
+            msmsslstbbbs
+            ssmsLlLLLs
+            msmsLlsattts
+            ssmsLllls
+            ssmsL111111s
+            msmsLssbbbts
+            ssmsLLLLLLs
+            ssms222222s
+        "},
+    );
+}
+
+#[test]
+fn test_render_label_wrap_marker() {
+    let source = "let value = compute(first);\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        label_wrap_marker: Some("↵"),
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(
+        4..9,
+        ANNOT_STYLE_1,
+        vec![("the value that is being computed".into(), '1')],
+    );
+    annots.add_annotation(12..19, ANNOT_STYLE_2, vec![("a function call".into(), '2')]);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        render_width: Some(34),
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^   ------- a↵
+              │     │               function↵
+              │     │               call
+              │     │
+              │     the value that is being↵
+              │     computed
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsttttaaaaatttbbbbbbbtttttttts
+            ssmssssslllllsssLLLLLLLs2Ls
+            ssmssssslsssssssssssssss22222222Ls
+            ssmssssslsssssssssssssss2222s
+            ssmsssssls
+            ssmsssss11111111111111111111111ls
+            ssmsssss11111111s
+        "},
+    );
+}
+
+#[test]
+fn test_render_embedded() {
+    let source = "1234\n5678\n90ab\ncdef\n";
     let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
 
     let mut annots = Annotations::new(&snippet, MAIN_STYLE);
-    annots.add_annotation(1..14, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+    annots.add_annotation(0..6, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(16..17, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        indent: Some(("> ", 'i')),
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        concat!(
+            "> ╭ 1234\n",
+            "> │ 5678\n",
+            "> ╰─^ test 1\n",
+            ">   \n",
+            ">   cdef\n",
+            ">    - test 2\n",
+        ),
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            iilsaaaas
+            iilsattts
+            iillls111111s
+            iisss
+            iisstbtts
+            iisssLs222222s
+        "},
+    );
+}
+
+#[test]
+fn test_render_style_precedence() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        style_precedence: StylePrecedence::Innermost,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(1..7, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(2..3, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
 
     let rendered = annots.render(1, 0, 0);
     let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
@@ -311,31 +519,35 @@ fn test_render_multi_line_crlf() {
         text,
         indoc::indoc! {"
             1 │   1234
+              │     - test 2
               │ ╭──^
-              · │ 
-            3 │ │ 90ab
-              │ ╰──^ test
+            2 │ │ 5678
+              │ ╰──^ test 1
         "},
     );
     assert_eq!(
         styles,
         indoc::indoc! {"
-            msmssstaaas
+            msmssstabas
+            ssmsssssLs222222s
             ssmslllls
-            ssmslss
             msmslsaatts
-            ssmslllls1111s
+            ssmslllls111111s
         "},
     );
 }
 
 #[test]
-fn test_render_zero_len_span() {
-    let source = "1234\n5678\n90ab\ncdef\n";
+fn test_render_text_meta_combine() {
+    let source = "1234\n";
     let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
 
     let mut annots = Annotations::new(&snippet, MAIN_STYLE);
-    annots.add_annotation(1..1, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+    annots.set_text_meta_combine(|base, annot| match (*base, *annot) {
+        ('t', 'a') => 'x',
+        _ => '?',
+    });
+    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("test".into(), '1')]);
 
     let rendered = annots.render(1, 0, 0);
     let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
@@ -345,47 +557,2133 @@ fn test_render_zero_len_span() {
         text,
         indoc::indoc! {"
             1 │ 1234
-              │  ^ test
+              │  ^^ test
         "},
     );
     assert_eq!(
         styles,
         indoc::indoc! {"
-            msmstttts
-            ssmssls1111s
+            msmstxxts
+            ssmsslls1111s
         "},
     );
 }
 
 #[test]
-fn test_render_tab() {
-    let source = "1234\n\t5678\n";
+fn test_render_hide_carets() {
+    let source = "let value = compute(first);\n";
     let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
 
+    let hidden_style = AnnotStyle {
+        hide_carets: true,
+        ..ANNOT_STYLE_2
+    };
     let mut annots = Annotations::new(&snippet, MAIN_STYLE);
-    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
-    annots.add_annotation(5..6, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(12..19, hidden_style, vec![("test 2".into(), '2')]);
+    annots.add_annotation(20..25, hidden_style, vec![]);
 
     let rendered = annots.render(1, 0, 0);
     let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
     let styles = gather_styles(&rendered);
 
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^ test 1
+              │             test 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsttttaaaaatttbbbbbbbtbbbbbtts
+            ssmsssssllllls111111s
+            ssmsssssssssssss222222s
+        "},
+    );
+}
+
+#[test]
+fn test_render_max_labels_per_line() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(0..1, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(2..3, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    annots.add_annotation(3..4, ANNOT_STYLE_2, vec![("test 3".into(), '3')]);
+    annots.add_annotation(6..7, ANNOT_STYLE_1, vec![("test 4".into(), '4')]);
+    annots.add_annotation(7..8, ANNOT_STYLE_2, vec![("test 5".into(), '5')]);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        max_labels_per_line: Some(1),
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
     assert_eq!(
         text,
         indoc::indoc! {"
             1 │ 1234
-              │  ^^ test 1
-            2 │     5678
-              │ ---- test 2
+              │ ^ --
+              │ │
+              │ test 1
+              │ … and 2 more annotations
+            2 │ 5678
+              │  ^-
+              │  │
+              │  test 4
+              │ … and 1 more annotation
         "},
     );
     assert_eq!(
         styles,
         indoc::indoc! {"
-            msmstaats
-            ssmsslls111111s
-            msmsbbbbtttts
-            ssmsLLLLs222222s
+            msmsatbbs
+            ssmslsLLs
+            ssmsls
+            ssms111111s
+            ssmstttttttttttttttttttttttts
+            msmstabts
+            ssmsslLs
+            ssmssls
+            ssmss444444s
+            ssmsttttttttttttttttttttttts
+        "},
+    );
+}
+
+#[test]
+fn test_render_secondary_label_threshold() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(0..1, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    let id2 = annots
+        .add_annotation(2..3, ANNOT_STYLE_2, vec![("test 2".into(), '2')])
+        .unwrap();
+    annots.set_secondary(id2);
+    annots.add_annotation(3..4, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+    annots.add_annotation(6..7, ANNOT_STYLE_1, vec![("test 4".into(), '4')]);
+    let id5 = annots
+        .add_annotation(7..8, ANNOT_STYLE_2, vec![("test 5".into(), '5')])
+        .unwrap();
+    annots.set_secondary(id5);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        secondary_label_threshold: Some(2),
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │ ^ -^ test 3
+              │ │
+              │ test 1
+              │ … and 1 more annotation
+            2 │ 5678
+              │  ^- test 5
+              │  │
+              │  test 4
+        "},
+    );
+}
+
+#[test]
+fn test_render_full_snippet() {
+    let source = "1234\n5678\n90ab\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_full_snippet_annotation(ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ ╭ 1234
+              · │ 
+            3 │ │ 90ab
+              │ ╰─────^ test 1
+        "},
+    );
+}
+
+#[test]
+fn test_render_preferred_ml_slot() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let render = |with_first: bool| {
+        let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+        if with_first {
+            let id1 = annots
+                .add_annotation(1..12, ANNOT_STYLE_1, vec![("test 1".into(), '1')])
+                .unwrap();
+            annots.set_preferred_ml_slot(id1, 0);
+        }
+        let id2 = annots
+            .add_annotation(6..17, ANNOT_STYLE_2, vec![("test 2".into(), '2')])
+            .unwrap();
+        annots.set_preferred_ml_slot(id2, 1);
+
+        let rendered = annots.render(1, 0, 0);
+        rendered.iter().map(|(s, _)| s.as_str()).collect::<String>()
+    };
+
+    assert_eq!(
+        render(true),
+        indoc::indoc! {"
+            1 │    1234
+              │  ╭──^
+            2 │  │ 5678
+              │ ╭│──-
+            3 │ ││ 90ab
+              │ │╰──^ test 1
+            4 │ │  cdef
+              │ ╰───- test 2
+        "},
+    );
+    // The second annotation keeps its slot without the first one
+    assert_eq!(
+        render(false),
+        indoc::indoc! {"
+            2 │    5678
+              │ ╭───-
+              · │  
+            4 │ │  cdef
+              │ ╰───- test 2
+        "},
+    );
+}
+
+#[test]
+fn test_render_console_chars() {
+    let source = "1234\n5678\n90ab\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    for (legacy_console, expected) in [
+        (
+            false,
+            indoc::indoc! {"
+                1 │ ╭ 1234
+                2 │ │ 5678
+                  │ │    - test 2
+                  │ ╰────^ test 1
+            "},
+        ),
+        (
+            true,
+            indoc::indoc! {"
+                1 | , 1234
+                2 | | 5678
+                  | |    - test 2
+                  | `----^ test 1
+            "},
+        ),
+    ] {
+        let main_style = MAIN_STYLE.with_console_chars(legacy_console);
+        let mut annots = Annotations::new(&snippet, main_style);
+        annots.add_annotation(0..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+        annots.add_annotation(8..9, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+        let rendered = annots.render(1, 0, 0);
+        let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(text, expected);
+    }
+}
+
+#[test]
+fn test_render_downgraded_style() {
+    let source = "1234\n5678\n90ab\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    assert_eq!(MAIN_STYLE.validate(Capabilities::FULL), Ok(()));
+    assert_eq!(
+        MAIN_STYLE.validate(Capabilities::ASCII),
+        Err(Error::UnsupportedStyleChar('─')),
+    );
+    let annot_style = AnnotStyle {
+        caret: '△',
+        ..ANNOT_STYLE_1
+    };
+    assert_eq!(
+        annot_style.validate(Capabilities::ASCII),
+        Err(Error::UnsupportedStyleChar('△')),
+    );
+
+    let main_style = MainStyle {
+        eof_placeholder: Some("␄"),
+        ..MAIN_STYLE
+    }
+    .downgrade(Capabilities::ASCII);
+    assert_eq!(main_style.validate(Capabilities::ASCII), Ok(()));
+    assert_eq!(main_style.eof_placeholder, Some("<EOF>"));
+    assert_eq!(main_style.more_annotations_marker, "...");
+    let annot_style = annot_style.downgrade(Capabilities::ASCII);
+    assert_eq!(annot_style.validate(Capabilities::ASCII), Ok(()));
+
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(0..9, annot_style, vec![("test 1".into(), '1')]);
+    annots.add_annotation(8..9, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    annots.add_annotation(9..10, ANNOT_STYLE_2, vec![("test 3".into(), '3')]);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        max_labels_per_line: Some(1),
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 | , 1234
+            2 | | 5678
+              | |    --
+              | |    |
+              | |    test 2
+              | | ... and 1 more annotation
+              | `----^ test 1
+        "},
+    );
+
+    // Supported characters are kept
+    let main_style = MainStyle {
+        vertical_char: '!',
+        ..MAIN_STYLE
+    }
+    .downgrade(Capabilities::ASCII);
+    assert_eq!(main_style.vertical_char, '!');
+    assert_eq!(MAIN_STYLE.downgrade(Capabilities::FULL), MAIN_STYLE);
+}
+
+#[test]
+fn test_max_render_width() {
+    let source = "1234\n5678\n90abcdef\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+    assert_eq!(snippet.line_width(2), 8);
+    assert_eq!(snippet.max_line_width(), 8);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..7, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(20..21, ANNOT_STYLE_2, vec![("a long label".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let max_width = text.lines().map(|line| line.chars().count()).max().unwrap();
+
+    assert_eq!(
+        annots.max_render_width(&RenderOptions::default()),
+        max_width
+    );
+}
+
+#[test]
+fn test_render_label_anchor_last_caret() {
+    let source = "let value = compute(1, 2);\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        label_anchor: LabelAnchor::LastCaret,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(0..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(12..19, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    annots.add_annotation(20..21, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(1, 2);
+              │ ^^^^^^^^^   ------- ^ test 3
+              │         │         │
+              │         │         test 2
+              │         test 1
+        "},
+    );
+}
+
+#[test]
+fn test_render_left_labels() {
+    let source = "let value = compute(first);\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        left_labels: true,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(20..25, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^    test 2 -----
+              │     │
+              │     test 1
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsttttaaaaatttttttttttbbbbbtts
+            ssmssssslllllssss222222sLLLLLs
+            ssmsssssls
+            ssmsssss111111s
+        "},
+    );
+
+    // No room before the carets
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(12..19, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^   ------- test 2
+              │     │
+              │     test 1
+        "},
+    );
+}
+
+#[test]
+fn test_render_spacing() {
+    let source = "1234\n5678\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        label_row_spacing: 1,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(0..1, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(1..2, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    annots.add_annotation(2..3, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+    annots.add_annotation(3..4, ANNOT_STYLE_2, vec![("test 4".into(), '4')]);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        padding_before: 1,
+        padding_after: 2,
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        concat!(
+            "  │ \n",
+            "1 │ 1234\n",
+            "  │ ^-^- test 4\n",
+            "  │ │││\n",
+            "  │ ││test 3\n",
+            "  │ ││\n",
+            "  │ │test 2\n",
+            "  │ │\n",
+            "  │ test 1\n",
+            "  │ \n",
+            "  │ \n",
+        ),
+    );
+}
+
+#[test]
+fn test_render_context_lines() {
+    let source = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    // `c`
+    annots.add_annotation(4..5, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    // `e`
+    annots.add_annotation(8..9, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    // `j`
+    annots.add_annotation(18..19, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+
+    // The fill options are ignored
+    let options = RenderOptions {
+        max_fill_after_first: 5,
+        max_fill_before_last: 5,
+        context_lines: Some(1),
+        ..RenderOptions::default()
+    };
+    assert_eq!(annots.max_line_no_width_with_options(&options), 2);
+    let rendered = annots.render_with_options(&options);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        concat!(
+            "2  │ b\n",
+            "3  │ c\n",
+            "   │ ^ test 1\n",
+            "4  │ d\n",
+            "5  │ e\n",
+            "   │ - test 2\n",
+            "6  │ f\n",
+            "   · \n",
+            "9  │ i\n",
+            "10 │ j\n",
+            "   │ ^ test 3\n",
+            "11 │ k\n",
+        ),
+    );
+}
+
+#[test]
+fn test_render_unfold_single_line_gaps() {
+    let source = "a\nb\nc\nd\ne\nf\ng\nh\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    // `a`
+    annots.add_annotation(0..1, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    // `c`
+    annots.add_annotation(4..5, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    // `f`
+    annots.add_annotation(10..11, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+
+    // The single line gap is rendered, the two line gap is folded
+    let rendered = annots.render_with_options(&RenderOptions {
+        unfold_single_line_gaps: true,
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        concat!(
+            "1 │ a\n",
+            "  │ ^ test 1\n",
+            "2 │ b\n",
+            "3 │ c\n",
+            "  │ - test 2\n",
+            "  · \n",
+            "6 │ f\n",
+            "  │ ^ test 3\n",
+        ),
+    );
+
+    // Also after the filled lines
+    let rendered = annots.render_with_options(&RenderOptions {
+        max_fill_after_first: 1,
+        unfold_single_line_gaps: true,
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        concat!(
+            "1 │ a\n",
+            "  │ ^ test 1\n",
+            "2 │ b\n",
+            "3 │ c\n",
+            "  │ - test 2\n",
+            "4 │ d\n",
+            "5 │ e\n",
+            "6 │ f\n",
+            "  │ ^ test 3\n",
+        ),
+    );
+}
+
+#[test]
+fn test_render_compact_labels() {
+    let source = "let value = compute(first, second);\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        compact_labels: true,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(12..19, ANNOT_STYLE_2, vec![("long test 2".into(), '2')]);
+    annots.add_annotation(20..25, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+    annots.add_annotation(27..33, ANNOT_STYLE_2, vec![("test 4".into(), '4')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first, second);
+              │     ^^^^^   ------- ^^^^^  ------ test 4
+              │     │       │       │
+              │     test 1  │       test 3
+              │             long test 2
+        "},
+    );
+}
+
+#[test]
+fn test_render_label_delimiter() {
+    let source = "let value = compute(first);\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        label_delimiter: Some("; "),
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(20..25, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+    annots.add_annotation(12..19, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^   ------- ^^^^^ test 1; test 2; test 3
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsttttaaaaatttbbbbbbbtaaaaatts
+            ssmssssslllllsssLLLLLLLsllllls111111ss222222ss333333s
+        "},
+    );
+
+    // Overlapping annotations are not merged
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(6..11, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^--
+              │     │ │
+              │     │ test 2
+              │     test 1
+        "},
+    );
+}
+
+#[test]
+fn test_render_identical_spans() {
+    let source = "let value = compute(first);\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    // Labels of annotations with the same span share the carets
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(4..9, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    annots.add_annotation(4..9, ANNOT_STYLE_2, vec![("test 3".into(), '3')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^ test 1
+              │           test 2
+              │           test 3
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsttttaaaaatttttttttttttttttts
+            ssmsssssllllls111111s
+            ssmsssssssssss222222s
+            ssmsssssssssss333333s
+        "},
+    );
+
+    // Stacked labels under a vertical line
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(12..19, ANNOT_STYLE_2, vec![("test 3".into(), '3')]);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^   ------- test 3
+              │     │
+              │     test 1
+              │     test 2
+        "},
+    );
+
+    // Compact labels are kept out of the way of stacked ones
+    let main_style = MainStyle {
+        compact_labels: true,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![("test 2".into(), '2')]);
+    annots.add_annotation(12..19, ANNOT_STYLE_2, vec![("test 3".into(), '3')]);
+    annots.add_annotation(20..25, ANNOT_STYLE_2, vec![("test 4".into(), '4')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^   ------- ----- test 4
+              │     │       │
+              │     │       test 3
+              │     test 1
+              │     test 2
+        "},
+    );
+
+    // Annotations without label do not add rows
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(4..9, ANNOT_STYLE_1, vec![]);
+    annots.add_annotation(4..9, ANNOT_STYLE_2, vec![("test 1".into(), '1')]);
+    annots.add_annotation(4..9, ANNOT_STYLE_2, vec![]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let value = compute(first);
+              │     ^^^^^ test 1
+        "},
+    );
+}
+
+#[test]
+fn test_render_column_guides() {
+    let source = "ab\ncdefgh\ni\nj\nk\nlm\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(0..1, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_column_guide(6, ANNOT_STYLE_2, vec![("guide 1".into(), 'g')]);
+    annots.add_column_guide(4, ANNOT_STYLE_1, vec![("guide 2".into(), 'G')]);
+    annots.add_annotation(17..18, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 1, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    // Rows whose content crosses a column interrupt its rule
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ ab  │ │
+              │ ^ test 1
+            2 │ cdefgh│
+              ·     │ │
+            6 │ lm  │ │
+              │  - test 2
+              │     │ - guide 1
+              │     ^ guide 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsatsslsLs
+            ssmsls111111s
+            msmsttttttLs
+            ssmssssslsLs
+            msmstbsslsLs
+            ssmssLs222222s
+            ssmssssslsLsgggggggs
+            ssmssssslsGGGGGGGs
+        "},
+    );
+
+    // Column guides are not annotations
+    assert_eq!(annots.len(), 2);
+    let (_, layout) = annots.render_with_layout(&RenderOptions {
+        max_fill_after_first: 1,
+        ..RenderOptions::default()
+    });
+    assert_eq!(layout.hit_test(0, 8), None);
+    assert_eq!(layout.hit_test(2, 10), None);
+
+    let max_width = text.lines().map(|line| line.chars().count()).max().unwrap();
+    assert!(annots.max_render_width(&RenderOptions::default()) >= max_width);
+}
+
+#[test]
+fn test_render_bidi_reordering() {
+    let source = "x = \"אבג דה\";\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    // `אבג`
+    annots.add_annotation(5..11, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    // `דה`
+    annots.add_annotation(12..16, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render_with_options(&RenderOptions {
+        bidi_reordering: true,
+        ..RenderOptions::default()
+    });
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        concat!(
+            "1 │ \u{202D}x = \"הד גבא\";\u{202C}\n",
+            "  │      -- ^^^\n",
+            "  │      │  │\n",
+            "  │      │  test 1\n",
+            "  │      test 2\n",
+        ),
+    );
+}
+
+#[test]
+fn test_render_severity() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let styles = SeverityStyles::new(|severity| match severity {
+        Severity::Error => ('a', 'A', 'l'),
+        Severity::Warning => ('b', 'B', 'L'),
+        Severity::Note => ('c', 'C', 'n'),
+    });
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(
+        0..1,
+        styles.get(Severity::Error),
+        vec![("error".into(), '1')],
+    );
+    annots.add_annotation(
+        5..7,
+        styles.get(Severity::Warning),
+        vec![("warning".into(), '2')],
+    );
+    annots.add_annotation(
+        10..11,
+        styles.get(Severity::Note),
+        vec![("note".into(), '3')],
+    );
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │ ^ error
+            2 │ 5678
+              │ ~~ warning
+            3 │ 90ab
+              │ - note
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsattts
+            ssmsls11111s
+            msmsbbtts
+            ssmsLLs2222222s
+            msmscttts
+            ssmsns3333s
+        "},
+    );
+}
+
+#[test]
+fn test_hit_test() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    let id1 = annots
+        .add_annotation(0..2, ANNOT_STYLE_1, vec![("test 1".into(), '1')])
+        .unwrap();
+    let id2 = annots
+        .add_annotation(3..4, ANNOT_STYLE_2, vec![("test 2".into(), '2')])
+        .unwrap();
+    let id3 = annots
+        .add_annotation(6..12, ANNOT_STYLE_1, vec![("test 3".into(), '3')])
+        .unwrap();
+    assert_ne!(id1, id2);
+
+    let (rendered, layout) = annots.render_with_layout(&RenderOptions::default());
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │   1234
+              │   ^^ - test 2
+              │   │
+              │   test 1
+            2 │   5678
+              │ ╭──^
+            3 │ │ 90ab
+              │ ╰──^ test 3
+        "},
+    );
+
+    // Source text and margin
+    assert_eq!(layout.hit_test(0, 4), None);
+    assert_eq!(layout.hit_test(1, 0), None);
+    // Carets
+    assert_eq!(layout.hit_test(1, 6), Some(id1));
+    assert_eq!(layout.hit_test(1, 7), Some(id1));
+    assert_eq!(layout.hit_test(1, 8), None);
+    assert_eq!(layout.hit_test(1, 9), Some(id2));
+    // Labels and lines
+    assert_eq!(layout.hit_test(1, 12), Some(id2));
+    assert_eq!(layout.hit_test(2, 6), Some(id1));
+    assert_eq!(layout.hit_test(3, 9), Some(id1));
+    assert_eq!(layout.hit_test(5, 4), Some(id3));
+    assert_eq!(layout.hit_test(6, 4), Some(id3));
+    assert_eq!(layout.hit_test(7, 12), Some(id3));
+    // Past the end of a row or of the output
+    assert_eq!(layout.hit_test(3, 12), None);
+    assert_eq!(layout.hit_test(8, 0), None);
+
+    // Without keeping the layout
+    let options = RenderOptions::default();
+    assert_eq!(annots.hit_test(&options, 1, 6), Some(id1));
+    assert_eq!(annots.hit_test(&options, 7, 12), Some(id3));
+    assert_eq!(annots.hit_test(&options, 1, 8), None);
+}
+
+#[test]
+fn test_render_kinds() {
+    let source = "1234\n5678\n90ab\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(10..12, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render_with_kinds(&RenderOptions {
+        max_fill_after_first: 1,
+        max_fill_before_last: 1,
+        ..RenderOptions::default()
+    });
+    let kinds: Vec<(&str, ChunkKind)> = rendered
+        .iter()
+        .map(|(text, _, kind)| (text.as_str(), *kind))
+        .collect();
+
+    assert_eq!(
+        kinds,
+        [
+            ("1", ChunkKind::LineNumber),
+            (" ", ChunkKind::Margin),
+            ("│", ChunkKind::Margin),
+            (" ", ChunkKind::Margin),
+            ("1", ChunkKind::SourceText),
+            ("23", ChunkKind::SourceText),
+            ("4", ChunkKind::SourceText),
+            ("\n", ChunkKind::Whitespace),
+            ("  ", ChunkKind::Margin),
+            ("│", ChunkKind::Margin),
+            (" ", ChunkKind::Margin),
+            (" ", ChunkKind::Whitespace),
+            ("^^", ChunkKind::Caret),
+            (" ", ChunkKind::Whitespace),
+            ("test 1", ChunkKind::Label),
+            ("\n", ChunkKind::Whitespace),
+            ("2", ChunkKind::LineNumber),
+            (" ", ChunkKind::Margin),
+            ("│", ChunkKind::Margin),
+            (" ", ChunkKind::Margin),
+            ("5678", ChunkKind::Fill),
+            ("\n", ChunkKind::Whitespace),
+            ("3", ChunkKind::LineNumber),
+            (" ", ChunkKind::Margin),
+            ("│", ChunkKind::Margin),
+            (" ", ChunkKind::Margin),
+            ("90", ChunkKind::SourceText),
+            ("ab", ChunkKind::SourceText),
+            ("\n", ChunkKind::Whitespace),
+            ("  ", ChunkKind::Margin),
+            ("│", ChunkKind::Margin),
+            (" ", ChunkKind::Margin),
+            ("--", ChunkKind::Caret),
+            (" ", ChunkKind::Whitespace),
+            ("test 2", ChunkKind::Label),
+            ("\n", ChunkKind::Whitespace),
+        ],
+    );
+    // Same chunks as `render`
+    assert_eq!(
+        rendered
+            .into_iter()
+            .map(|(text, meta, _)| (text, meta))
+            .collect::<Vec<_>>(),
+        annots.render(1, 1, 1),
+    );
+}
+
+#[test]
+fn test_render_cancellable() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..2, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(16..17, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let options = RenderOptions {
+        max_fill_after_first: 2,
+        max_fill_before_last: 2,
+        ..RenderOptions::default()
+    };
+    let num_checks = core::cell::Cell::new(0);
+    let rendered = annots.render_cancellable(
+        || {
+            num_checks.set(num_checks.get() + 1);
+            false
+        },
+        &options,
+    );
+    assert_eq!(rendered, Some(annots.render(1, 2, 2)));
+    // Once before each source line
+    assert_eq!(num_checks.get(), 4);
+
+    num_checks.set(0);
+    let rendered = annots.render_cancellable(
+        || {
+            num_checks.set(num_checks.get() + 1);
+            num_checks.get() == 2
+        },
+        &options,
+    );
+    assert_eq!(rendered, None);
+    assert_eq!(num_checks.get(), 2);
+}
+
+#[test]
+fn test_render_to() {
+    struct Rows {
+        rows: Vec<(RowKind, String)>,
+        in_row: bool,
+    }
+
+    impl Output<char> for Rows {
+        fn put(&mut self, text: &str, _meta: &char, _kind: ChunkKind) {
+            assert!(self.in_row);
+            self.rows.last_mut().unwrap().1.push_str(text);
+        }
+
+        fn begin_row(&mut self, kind: RowKind) {
+            assert!(!self.in_row);
+            self.rows.push((kind, String::new()));
+            self.in_row = true;
+        }
+
+        fn end_row(&mut self) {
+            assert!(self.in_row);
+            self.in_row = false;
+        }
+    }
+
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(15..17, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let options = RenderOptions {
+        padding_before: 1,
+        ..RenderOptions::default()
+    };
+    let mut rows = Rows {
+        rows: Vec::new(),
+        in_row: false,
+    };
+    annots.render_to(&mut rows, &options);
+    assert!(!rows.in_row);
+
+    assert_eq!(
+        rows.rows,
+        [
+            (RowKind::Padding, "  │ \n".into()),
+            (RowKind::Source, "1 │ 1234\n".into()),
+            (RowKind::Annotation, "  │  ^^ test 1\n".into()),
+            (RowKind::Fold, "  · \n".into()),
+            (RowKind::Source, "4 │ cdef\n".into()),
+            (RowKind::Annotation, "  │ -- test 2\n".into()),
+        ],
+    );
+
+    // The output into a `Vec` is the same as `render_with_options`
+    let mut rendered = Vec::new();
+    annots.render_to(&mut rendered, &options);
+    assert_eq!(rendered, annots.render_with_options(&options));
+}
+
+#[test]
+fn test_render_layout() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    let id1 = annots
+        .add_annotation(1..3, ANNOT_STYLE_1, vec![("test 1".into(), '1')])
+        .unwrap();
+    let id2 = annots
+        .add_annotation(6..12, ANNOT_STYLE_2, vec![("test 2".into(), '2')])
+        .unwrap();
+
+    let (rendered, layout) = annots.render_with_layout(&RenderOptions::default());
+    assert_eq!(rendered, annots.render(1, 0, 0));
+
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │   1234
+              │    ^^ test 1
+            2 │   5678
+              │ ╭──-
+            3 │ │ 90ab
+              │ ╰──- test 2
+        "},
+    );
+
+    let rows = &layout.rows;
+    assert_eq!(rows.len(), 6);
+    assert_eq!(
+        rows.iter().map(|row| row.source_line).collect::<Vec<_>>(),
+        [Some(0), None, Some(1), None, Some(2), None],
+    );
+    assert!(rows[0].elements.is_empty());
+    assert_eq!(
+        rows[1].elements,
+        [
+            LayoutElement {
+                annotation: id1,
+                kind: LayoutElementKind::Caret,
+                cols: 7..9,
+            },
+            LayoutElement {
+                annotation: id1,
+                kind: LayoutElementKind::Label,
+                cols: 10..16,
+            },
+        ],
+    );
+    assert_eq!(
+        rows[3].elements,
+        [
+            LayoutElement {
+                annotation: id2,
+                kind: LayoutElementKind::Line,
+                cols: 4..7,
+            },
+            LayoutElement {
+                annotation: id2,
+                kind: LayoutElementKind::Caret,
+                cols: 7..8,
+            },
+        ],
+    );
+    assert_eq!(
+        rows[4].elements,
+        [LayoutElement {
+            annotation: id2,
+            kind: LayoutElementKind::Line,
+            cols: 4..5,
+        }],
+    );
+
+    assert_eq!(layout.hit_test(5, 10), Some(id2));
+    assert_eq!(layout.hit_test(6, 0), None);
+}
+
+#[test]
+fn test_render_multi_span() {
+    let source = "f(1, 2, 3)\ng(4, 5)\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    let id = annots
+        .add_multi_span_annotation(
+            [8..9, 2..3, 5..6, 13..14],
+            ANNOT_STYLE_1,
+            vec![("these conflict".into(), '1')],
+        )
+        .unwrap();
+    annots.add_annotation(0..1, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let (rendered, layout) = annots.render_with_layout(&RenderOptions::default());
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ f(1, 2, 3)
+              │ - ^  ^  ^
+              │ │
+              │ test 2
+            2 │ g(4, 5)
+              │   ^ these conflict
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsbtattattats
+            ssmsLslsslssls
+            ssmsLs
+            ssms222222s
+            msmsttatttts
+            ssmsssls11111111111111s
+        "},
+    );
+    assert_eq!(layout.hit_test(1, 6), Some(id));
+    assert_eq!(layout.hit_test(1, 9), Some(id));
+    assert_eq!(layout.hit_test(5, 6), Some(id));
+}
+
+#[test]
+fn test_render_linked() {
+    let source = "f(a, b]\n{\nx\n)\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_linked_annotation(6..7, 1..2, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_linked_annotation(8..9, 12..13, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │   f(a, b]
+              │    ^────^ test 1
+            2 │ ╭ {
+              · │ 
+            4 │ │ )
+              │ ╰─- test 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmssstattttas
+            ssmsssslllllls111111s
+            msmsLsbs
+            ssmsLss
+            msmsLsbs
+            ssmsLLLs222222s
+        "},
+    );
+}
+
+#[test]
+fn test_gutter_width() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    assert_eq!(annots.num_multi_line_slots(), 0);
+    assert_eq!(annots.gutter_width(), 0);
+
+    annots.add_annotation(0..11, ANNOT_STYLE_1, vec![("test 2".into(), '1')]);
+    annots.add_annotation(6..18, ANNOT_STYLE_2, vec![("test 3".into(), '2')]);
+    assert_eq!(annots.num_multi_line_slots(), 2);
+    assert_eq!(annots.gutter_width(), 3);
+}
+
+#[test]
+fn test_incremental_ml_slots() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    let id1 = annots
+        .add_annotation(6..13, ANNOT_STYLE_1, vec![("test 1".into(), '1')])
+        .unwrap();
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            2 │   5678
+              │ ╭──^
+            3 │ │ 90ab
+              │ ╰───^ test 1
+        "},
+    );
+
+    // An annotation that encloses the first one does not take its slot
+    let id2 = annots
+        .add_annotation(1..18, ANNOT_STYLE_2, vec![("test 2".into(), '2')])
+        .unwrap();
+    assert_eq!(annots.ml_slot(id1), Some(0));
+    assert_eq!(annots.ml_slot(id2), Some(1));
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │    1234
+              │ ╭───-
+            2 │ │  5678
+              │ │╭──^
+            3 │ ││ 90ab
+              │ │╰───^ test 1
+            4 │ │  cdef
+              │ ╰────- test 2
+        "},
+    );
+
+    // Slots can be kept in a new collection
+    let mut new_annots = Annotations::new(&snippet, MAIN_STYLE);
+    let new_id2 = new_annots
+        .add_annotation(1..18, ANNOT_STYLE_2, vec![("test 2".into(), '2')])
+        .unwrap();
+    new_annots.set_preferred_ml_slot(new_id2, annots.ml_slot(id2).unwrap());
+    let new_id1 = new_annots
+        .add_annotation(6..13, ANNOT_STYLE_1, vec![("test 1".into(), '1')])
+        .unwrap();
+    assert_eq!(new_annots.ml_slot(new_id1), Some(0));
+    assert_eq!(new_annots.ml_slot(new_id2), Some(1));
+    assert_eq!(new_annots.render(1, 0, 0), rendered);
+
+    let id3 = annots
+        .add_annotation(1..2, ANNOT_STYLE_1, vec![("test 3".into(), '1')])
+        .unwrap();
+    assert_eq!(annots.ml_slot(id3), None);
+}
+
+#[test]
+fn test_counts() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    assert!(annots.is_empty());
+    assert_eq!(annots.len(), 0);
+    assert_eq!(annots.multi_line_count(), 0);
+    assert_eq!(annots.annotated_line_count(), 0);
+
+    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(0..11, ANNOT_STYLE_1, vec![("test 2".into(), '1')]);
+    annots.add_multi_span_annotation([5..6, 15..16], ANNOT_STYLE_2, vec![("test 3".into(), '2')]);
+    annots.add_hidden_annotation(2..4, ANNOT_STYLE_2);
+    assert!(!annots.is_empty());
+    assert_eq!(annots.len(), 4);
+    assert_eq!(annots.multi_line_count(), 1);
+    assert_eq!(annots.annotated_line_count(), 4);
+
+    // Hidden multi-line annotations count every line
+    let source = "1234\n5678\n90ab\ncdef\nghij\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(0..16, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+    assert_eq!(annots.multi_line_count(), 1);
+    assert_eq!(annots.annotated_line_count(), 2);
+
+    annots.add_hidden_annotation(6..21, ANNOT_STYLE_2);
+    assert_eq!(annots.multi_line_count(), 1);
+    assert_eq!(annots.annotated_line_count(), 5);
+}
+
+#[test]
+fn test_render_multi_line_crlf() {
+    let source = "1234\r\n5678\r\n90ab\r\ncdef\r\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..14, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │   1234
+              │ ╭──^
+              · │ 
+            3 │ │ 90ab
+              │ ╰──^ test
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmssstaaas
+            ssmslllls
+            ssmslss
+            msmslsaatts
+            ssmslllls1111s
+        "},
+    );
+}
+
+#[test]
+fn test_render_zero_len_span() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..1, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │  ^ test
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmstttts
+            ssmssls1111s
+        "},
+    );
+}
+
+#[test]
+fn test_render_past_eof() {
+    let source = "1234\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(7..9, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            2 │ 
+              │ ^ test
+        "},
+    );
+
+    let main_style = MainStyle {
+        eof_placeholder: Some("<EOF>"),
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(7..9, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            2 │ <EOF>
+              │ ^ test
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsTTTTTs
+            ssmsls1111s
+        "},
+    );
+}
+
+#[test]
+fn test_render_eof_annotation() {
+    let source = "let x = (1\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(8..9, ANNOT_STYLE_2, vec![("opened here".into(), '2')]);
+    annots.add_eof_annotation(ANNOT_STYLE_1, vec![("unexpected end of file".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        concat!(
+            "1 │ let x = (1\n",
+            "  │         - opened here\n",
+            "2 │ \n",
+            "  │ ^ unexpected end of file\n",
+        ),
+    );
+
+    let source = "let x = (1";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        eof_placeholder: Some("<EOF>"),
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(8..9, ANNOT_STYLE_2, vec![("opened here".into(), '2')]);
+    annots.add_eof_annotation(ANNOT_STYLE_1, vec![("unexpected end of file".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let x = (1<EOF>
+              │         - ^ unexpected end of file
+              │         │
+              │         opened here
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsttttttttbtTTTTTs
+            ssmsssssssssLsls1111111111111111111111s
+            ssmsssssssssLs
+            ssmsssssssss22222222222s
+        "},
+    );
+    assert_eq!(annots.max_render_width(&RenderOptions::default()), 38);
+}
+
+#[test]
+fn test_render_empty_snippet() {
+    let snippet = SourceSnippet::build_from_utf8(1, b"", 4);
+
+    let main_style = MainStyle {
+        eof_placeholder: Some("<empty file>"),
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(0..0, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ <empty file>
+              │ ^ test
+        "},
+    );
+}
+
+#[test]
+fn test_render_huge_start_line() {
+    let source = "1234\n5678\n90ab\n";
+    let snippet = SourceSnippet::build_from_utf8(usize::MAX - 1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..2, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(11..12, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    assert_eq!(annots.max_line_no_width(), 20);
+
+    // A too small `max_line_no_width` does not panic
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            18446744073709551614 │ 1234
+              │  ^ test 1
+              · 
+            18446744073709551615 │ 90ab
+              │  - test 2
+        "},
+    );
+}
+
+#[test]
+fn test_render_label_sanitization() {
+    let source = "1234\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        label_sanitization: LabelSanitization::Strip,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(1..2, ANNOT_STYLE_1, vec![("a\nb\x1B[0m".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │  ^ ab[0m
+        "},
+    );
+
+    let main_style = MainStyle {
+        label_sanitization: LabelSanitization::Escape,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(1..2, ANNOT_STYLE_1, vec![("a\nb\x1B[0m".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {r"
+            1 │ 1234
+              │  ^ a\nb\u{1b}[0m
+        "},
+    );
+}
+
+#[test]
+fn test_render_zero_len_span_carets() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        empty_span_carets: 2,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(1..1, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(3..4, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │  ^^- test 2
+              │  │
+              │  test 1
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmstttbs
+            ssmssllLs222222s
+            ssmssls
+            ssmss111111s
+        "},
+    );
+}
+
+#[test]
+fn test_render_custom_snippet() {
+    // A source where each unit is a token
+    let mut builder = SourceSnippet::builder(1);
+    builder.push_text("let", 1, false);
+    builder.push_text(" x", 1, false);
+    builder.push_text(" =", 1, false);
+    builder.push_text(" <invalid>", 1, true);
+    builder.push_line_break(1);
+    builder.push_text("x", 1, false);
+    let snippet = builder.finish();
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(3..4, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(5..6, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ let x = <invalid>
+              │        ^^^^^^^^^^ test 1
+            2 │ x
+              │ - test 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmstttttttAAAAAAAAAAs
+            ssmsssssssslllllllllls111111s
+            msmsbs
+            ssmsLs222222s
+        "},
+    );
+}
+
+#[test]
+fn test_unit_kind() {
+    let snippet = SourceSnippet::build_from_utf8(1, b"1234", 4);
+    let annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.debug_assert_unit_kind(UnitKind::Byte);
+
+    let mut builder = SourceSnippet::builder(1);
+    builder.set_unit_kind(UnitKind::Token);
+    builder.push_text("1234", 1, false);
+    let snippet = builder.finish();
+    assert_eq!(snippet.unit_kind(), UnitKind::Token);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "the snippet has units of a different kind")]
+fn test_unit_kind_mismatch() {
+    let snippet = SourceSnippet::build_from_utf8(1, b"1234", 4);
+    let annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.debug_assert_unit_kind(UnitKind::Utf16Word);
+}
+
+#[test]
+fn test_render_for_lines() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::for_lines(&snippet, 1..3, MAIN_STYLE);
+    // Outside the window
+    annots.add_annotation(0..2, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(15..17, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    // Clamped to the window
+    annots.add_annotation(3..6, ANNOT_STYLE_1, vec![("test 2".into(), '1')]);
+    annots.add_annotation(11..17, ANNOT_STYLE_2, vec![("test 3".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            2 │ 5678
+              │ ^ test 2
+            3 │ 90ab
+              │  ---- test 3
+        "},
+    );
+}
+
+#[test]
+fn test_try_for_lines() {
+    let source = "1234\n5678\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    assert!(Annotations::try_for_lines(&snippet, 1..2, MAIN_STYLE).is_ok());
+    assert_eq!(
+        Annotations::try_for_lines(&snippet, 3..5, MAIN_STYLE).unwrap_err(),
+        Error::EmptyLineWindow,
+    );
+}
+
+#[test]
+fn test_render_with_start_line() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(11..12, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let options = RenderOptions {
+        start_line: Some(98),
+        ..RenderOptions::default()
+    };
+    assert_eq!(annots.max_line_no_width(), 1);
+    assert_eq!(annots.max_line_no_width_with_options(&options), 3);
+
+    let rendered = annots.render_with_options(&options);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            100 │ 90ab
+                │  ^ test
+        "},
+    );
+}
+
+#[test]
+fn test_render_hidden() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_hidden_annotation(2..7, ANNOT_STYLE_2);
+    annots.add_annotation(1..2, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │  ^ test
+            2 │ 5678
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmstabbs
+            ssmssls1111s
+            msmsbbtts
+        "},
+    );
+}
+
+#[test]
+fn test_render_groups() {
+    let source = "1234\n5678\n90ab\ncdef\nghij\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..2, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.set_group(1);
+    annots.add_annotation(22..23, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+    annots.set_group(0);
+    annots.add_annotation(6..8, ANNOT_STYLE_1, vec![("test 3".into(), '3')]);
+
+    assert_eq!(annots.group_ids(), [0, 1]);
+
+    let blocks = annots.render_groups(&RenderOptions {
+        max_fill_after_first: 1,
+        max_fill_before_last: 1,
+        ..RenderOptions::default()
+    });
+    assert_eq!(blocks.len(), 2);
+
+    let text: String = blocks[0].iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │  ^ test 1
+            2 │ 5678
+              │  ^^ test 3
+        "},
+    );
+
+    let text: String = blocks[1].iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            5 │ ghij
+              │   - test 2
+        "},
+    );
+
+    let text: String = annots
+        .group(2)
+        .render(1, 0, 0)
+        .iter()
+        .map(|(s, _)| s.as_str())
+        .collect();
+    assert_eq!(text, "");
+}
+
+#[test]
+fn test_render_tab() {
+    let source = "1234\n\t5678\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..3, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(5..6, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │  ^^ test 1
+            2 │     5678
+              │ ---- test 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmstaats
+            ssmsslls111111s
+            msmsbbbbtttts
+            ssmsLLLLs222222s
+        "},
+    );
+}
+
+#[test]
+fn test_render_kept_tab() {
+    let source = "1234\n\t5678\n";
+    let options = Utf8Options {
+        keep_tabs: true,
+        ..Utf8Options::default()
+    };
+    let snippet = SourceSnippet::build_from_utf8_with_options(1, source.as_bytes(), 4, options);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(5..7, ANNOT_STYLE_2, vec![("test".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            2 │ \t5678
+              │ ----- test
+        "},
+    );
+}
+
+#[test]
+fn test_render_huge_tab() {
+    let source = "\tx\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 200);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..2, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    let spaces = " ".repeat(200);
+    assert_eq!(text, format!("1 │ {spaces}x\n  │ {spaces}^ test\n"),);
+}
+
+#[test]
+fn test_render_tab_last_column() {
+    let source = "1234\n\t5678\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let main_style = MainStyle {
+        tab_anchor: TabAnchor::LastColumn,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(5..7, ANNOT_STYLE_2, vec![("test".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            2 │     5678
+              │    -- test
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsbbbbbttts
+            ssmssssLLs2222s
+        "},
+    );
+}
+
+#[cfg(feature = "unicode-width")]
+#[test]
+fn test_render_wide_char() {
+    let source = "1\u{FF12}\u{FF13}4\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(1..7, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1２３4
+              │  ^^^^ test
+        "},
+    );
+
+    let main_style = MainStyle {
+        wide_char_carets: sourceannot::WideCharCarets::PerChar,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(1..8, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1２３4
+              │  ^ ^ ^ test
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmstaaas
+            ssmsslslsls1111s
+        "},
+    );
+}
+
+#[test]
+fn test_render_line_break_end() {
+    let source = "1234\n5678\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+    annots.add_annotation(2..5, ANNOT_STYLE_1, vec![("test".into(), '1')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │   ^^^ test
+        "},
+    );
+
+    let main_style = MainStyle {
+        line_break_end: LineBreakEnd::LastChar,
+        ..MAIN_STYLE
+    };
+    let mut annots = Annotations::new(&snippet, main_style);
+    annots.add_annotation(2..5, ANNOT_STYLE_1, vec![("test 1".into(), '1')]);
+    annots.add_annotation(9..10, ANNOT_STYLE_2, vec![("test 2".into(), '2')]);
+
+    let rendered = annots.render(1, 0, 0);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    let styles = gather_styles(&rendered);
+
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ 1234
+              │   ^^ test 1
+            2 │ 5678
+              │    - test 2
+        "},
+    );
+    assert_eq!(
+        styles,
+        indoc::indoc! {"
+            msmsttaas
+            ssmssslls111111s
+            msmstttbs
+            ssmssssLs222222s
+        "},
+    );
+}
+
+#[test]
+fn test_render_deterministic() {
+    let source = "1234\n5678\n90ab\ncdef\n";
+    let snippet = SourceSnippet::build_from_utf8(1, source.as_bytes(), 4);
+
+    let build = |order: &[usize]| {
+        let spans = [(1..3, "test 1"), (1..2, "test 2"), (0..7, "test 3")];
+        let mut annots = Annotations::new(&snippet, MAIN_STYLE);
+        for &i in order {
+            let (span, label) = spans[i].clone();
+            annots.add_annotation(span, ANNOT_STYLE_1, vec![(label.into(), '1')]);
+        }
+        annots.render(1, 0, 0)
+    };
+
+    let rendered = build(&[0, 1, 2]);
+    assert_eq!(rendered, build(&[0, 1, 2]));
+
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ ╭ 1234
+              │ │  ^^
+              │ │  │
+              │ │  │test 2
+              │ │  test 1
+            2 │ │ 5678
+              │ ╰──^ test 3
+        "},
+    );
+
+    let rendered = build(&[1, 0, 2]);
+    let text: String = rendered.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(
+        text,
+        indoc::indoc! {"
+            1 │ ╭ 1234
+              │ │  ^^
+              │ │  │
+              │ │  │test 1
+              │ │  test 2
+            2 │ │ 5678
+              │ ╰──^ test 3
         "},
     );
 }